@@ -1,46 +1,292 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Arch {
+    #[serde(alias = "x86_64")]
     X86_64,
     Aarch64,
     Riscv64,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Platform {
     Qemu,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageLayout {
+    /// whole disk is one FAT32 volume, no partition table
+    Superfloppy,
+    /// protective MBR + GPT with an EFI System Partition
+    Gpt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsoleTransport {
+    /// QEMU listens on a Unix socket in the output directory
+    Socket,
+    /// QEMU allocates a pseudo-terminal
+    Pty,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Accelerator {
+    /// KVM when /dev/kvm is usable for the guest arch, TCG otherwise
+    Auto,
+    Kvm,
+    Tcg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VarsMode {
+    /// every run starts from a private copy of the firmware's template
+    Fresh,
+    /// the store in the out directory is kept between runs
+    Persistent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NetworkMode {
+    /// no NIC at all
+    None,
+    /// QEMU's built-in NAT (slirp), reachable from the host through `hostfwd`
+    User,
+    /// an Ethernet segment shared with other QEMU instances over a socket
+    Socket,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NicModel {
+    E1000,
+    VirtioNet,
+    Rtl8139,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymbolizeMode {
+    Off,
+    /// annotate crash lines on the terminal as they arrive
+    Inline,
+    /// print a crash report once QEMU has exited
+    Report,
+}
+
 #[derive(Clone, Debug, Parser)]
 #[command(author, version)]
 pub struct Cli {
+    /// project config; defaults to ./spencer.toml when present
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
     #[command(subcommand)]
     pub command: Command,
 }
 
 #[derive(Clone, Debug, Subcommand)]
 pub enum Command {
+    /// build the kernel, loader and Nun, and pack them into a disk image
     Build(BuildArgs),
+    /// build and boot in QEMU with the serial console on the terminal
     Run(RunArgs),
+    /// build, boot paused with the gdbstub enabled and attach a debugger
+    Gdb(GdbArgs),
+    /// boot headless and exit with the guest's test result
+    Test(TestArgs),
+    /// cargo runner: boot a Nun ELF as init with the cached kernel and loader
+    ///
+    /// --arch defaults to the ELF's machine type.
+    Runner(RunnerArgs),
+    /// build (or test) every supported arch x platform x profile combination
+    ///
+    /// --arch, --platform, --release and --debug narrow the matrix to that value.
+    Matrix(MatrixArgs),
+    /// check the host tools and submodules the build needs
+    ///
+    /// Every arch is checked unless --arch (or build.arch) picks one.
+    Doctor(DoctorArgs),
+    /// delete build output: everything, one combination, or single components
+    ///
+    /// --arch, --platform, --release and --debug narrow the combinations cleaned;
+    /// without them (and without --component) every output location is deleted.
+    Clean(CleanArgs),
+    /// control a running QEMU (from run, test, gdb or runner) over QMP
+    ///
+    /// --arch, --platform and the profile pick out/<...>/qmp.sock.
+    Qmp(QmpArgs),
+    /// list and edit the UEFI variables and boot entries of a variable store
+    ///
+    /// --arch, --platform, the profile and --vars / --vars-file pick the store.
+    Vars(VarsArgs),
+    /// print the effective configuration (spencer.toml merged with the command line)
+    Config(ConfigArgs),
 }
 
+// the options below fall back to spencer.toml when omitted
 #[derive(Clone, Debug, Parser)]
 pub struct CommonArgs {
+    /// guest architecture
     #[arg(long, value_enum)]
-    pub arch: Arch,
+    pub arch: Option<Arch>,
 
+    /// machine the image is built for
     #[arg(long, value_enum)]
-    pub platform: Platform,
+    pub platform: Option<Platform>,
 
-    #[arg(long)]
+    /// optimized build
+    #[arg(long, conflicts_with = "debug")]
     pub release: bool,
 
+    /// unoptimized build (the default), overriding build.release
+    #[arg(long)]
+    pub debug: bool,
+
+    /// more detail about what each step does
     #[arg(long, default_value_t = false)]
     pub verbose: bool,
 
+    /// print what would be done without doing it
     #[arg(long, default_value_t = false)]
     pub dry_run: bool,
+
+    /// rebuild every step even when its inputs are unchanged
+    #[arg(long, default_value_t = false)]
+    pub force: bool,
+
+    /// build steps run at the same time; defaults to the number of CPUs
+    #[arg(long, short = 'j', value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// FAT volume size in MiB; computed from the packed files when omitted
+    #[arg(long, value_name = "MIB")]
+    pub image_size: Option<u64>,
+
+    /// free space in MiB added on top of the packed files when sizing automatically
+    #[arg(long, value_name = "MIB")]
+    pub image_slack: Option<u64>,
+
+    /// TOML table of extra `image_path = host_path` entries for the ESP
+    #[arg(long, value_name = "PATH")]
+    pub esp_layout: Option<String>,
+
+    /// partitioning of the disk image
+    #[arg(long, value_enum)]
+    pub image_layout: Option<ImageLayout>,
+
+    /// NAME:SIZE_MIB[:RAW_IMAGE], gpt layout only
+    #[arg(long, value_parser = parse_data_partition)]
+    pub data_partition: Vec<DataPartitionArg>,
+
+    /// resolve crash addresses in the serial output against the kernel and init ELFs
+    #[arg(long, value_enum)]
+    pub symbolize: Option<SymbolizeMode>,
+
+    /// guest RAM, e.g. 512M or 4G
+    #[arg(long, value_name = "SIZE")]
+    pub memory: Option<String>,
+
+    /// QEMU CPU model; the arch's default is "max"
+    #[arg(long, value_name = "MODEL")]
+    pub cpu: Option<String>,
+
+    /// CPU count or topology, e.g. 4 or cpus=4,sockets=1,cores=2,threads=2
+    #[arg(long, value_name = "SPEC")]
+    pub smp: Option<String>,
+
+    /// QEMU machine type, e.g. q35 or pc on x86_64; the arch's default otherwise
+    #[arg(long, value_name = "TYPE")]
+    pub machine: Option<String>,
+
+    /// QEMU accelerator
+    #[arg(long, value_enum)]
+    pub accel: Option<Accelerator>,
+
+    /// port of the gdbstub on 127.0.0.1 for `gdb` and `run --gdb`
+    #[arg(long, value_name = "PORT")]
+    pub gdb_port: Option<u16>,
+
+    /// firmware that supports secure boot (an OVMF secure boot build needs SMM, so q35 on x86_64)
+    #[arg(long, conflicts_with = "no_secure_boot")]
+    pub secure_boot: bool,
+
+    /// firmware without secure boot, overriding qemu.secure_boot
+    #[arg(long)]
+    pub no_secure_boot: bool,
+
+    /// use this QEMU firmware descriptor (JSON) instead of searching for one
+    #[arg(long, value_name = "PATH", conflicts_with = "firmware_code")]
+    pub firmware_descriptor: Option<String>,
+
+    /// UEFI code image, instead of any descriptor; needs --firmware-vars
+    #[arg(long, value_name = "PATH", requires = "firmware_vars")]
+    pub firmware_code: Option<String>,
+
+    /// UEFI variable store template that goes with --firmware-code
+    #[arg(long, value_name = "PATH", requires = "firmware_code")]
+    pub firmware_vars: Option<String>,
+
+    /// UEFI variable store of the guest
+    #[arg(long, value_enum, conflicts_with = "vars_file")]
+    pub vars: Option<VarsMode>,
+
+    /// keep the UEFI variable store in this file; created from the template if missing
+    #[arg(long, value_name = "PATH")]
+    pub vars_file: Option<String>,
+
+    /// guest networking
+    #[arg(long, value_enum)]
+    pub net: Option<NetworkMode>,
+
+    /// emulated network card
+    #[arg(long, value_enum)]
+    pub nic: Option<NicModel>,
+
+    /// user networking host forward, e.g. tcp:127.0.0.1:8080-:80; replaces the configured ones
+    #[arg(long, value_name = "RULE")]
+    pub hostfwd: Vec<String>,
+
+    /// record the guest's network traffic to net.pcap in the out directory
+    #[arg(long, default_value_t = false)]
+    pub capture: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct DataPartitionArg {
+    pub name: String,
+    pub size_mib: u64,
+    pub raw_image: Option<String>,
+}
+
+fn parse_data_partition(value: &str) -> Result<DataPartitionArg, String> {
+    let mut parts = value.splitn(3, ':');
+
+    let name = parts.next().unwrap_or_default();
+    let size_mib = parts
+        .next()
+        .ok_or_else(|| format!("expected NAME:SIZE_MIB[:RAW_IMAGE], got '{}'", value))?;
+
+    if name.is_empty() {
+        return Err(format!("data partition name is empty: '{}'", value));
+    }
+
+    let size_mib = size_mib
+        .parse::<u64>()
+        .map_err(|error| format!("invalid size '{}': {}", size_mib, error))?;
+
+    Ok(DataPartitionArg {
+        name: name.to_string(),
+        size_mib,
+        raw_image: parts.next().map(str::to_string),
+    })
 }
 
 #[derive(Clone, Debug, Parser)]
@@ -54,9 +300,259 @@ pub struct RunArgs {
     #[command(flatten)]
     pub common: CommonArgs,
 
+    /// enable QEMU's gdbstub on 127.0.0.1 (see --gdb-port)
     #[arg(long, default_value_t = false)]
     pub gdb: bool,
 
+    /// start the guest paused
     #[arg(long, default_value_t = false)]
     pub stop: bool,
+
+    /// boot several connected instances described in this file instead of one
+    #[arg(long, value_name = "PATH")]
+    pub topology: Option<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct GdbArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    // both flags are accepted so the documented invocation keeps working
+    /// no effect: the gdbstub is always enabled
+    #[arg(long, default_value_t = false)]
+    pub gdb: bool,
+
+    /// no effect: the guest always starts paused
+    #[arg(long, default_value_t = false)]
+    pub stop: bool,
+
+    /// debugger to run instead of debugger.command, e.g. gdb-multiarch
+    #[arg(long)]
+    pub debugger: Option<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct TestArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// seconds before QEMU is killed and the run counts as timed out
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// serial expectations (ordered regexes and/or a golden file) checked after the run
+    #[arg(long, value_name = "PATH")]
+    pub expect: Option<String>,
+
+    /// rewrite the spec's golden file from this run
+    #[arg(long, default_value_t = false)]
+    pub bless: bool,
+
+    /// expect/send steps run against the serial console during the test
+    #[arg(long, value_name = "PATH")]
+    pub script: Option<String>,
+
+    /// how the script reaches the serial port
+    #[arg(long, value_enum)]
+    pub console: Option<ConsoleTransport>,
+
+    /// boot several connected instances described in this file instead of one
+    #[arg(long, value_name = "PATH")]
+    pub topology: Option<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct RunnerArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// seconds before QEMU is killed and the run counts as timed out
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// ELF built by cargo for a Nun target, packed as /kernel/init.elf
+    pub elf: String,
+
+    /// what cargo passes after the executable (e.g. test filters); the guest
+    /// has no command line, so these are ignored
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct MatrixArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// only combinations whose name (e.g. x86_64-qemu-release) matches one of these globs
+    #[arg(long, value_name = "GLOB")]
+    pub filter: Vec<String>,
+
+    /// boot each combination with `test` after building it
+    #[arg(long, default_value_t = false)]
+    pub test: bool,
+
+    /// test timeout per combination, in seconds
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    /// combinations processed at the same time
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub parallel: usize,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct DoctorArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CleanArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// only the output of these steps
+    #[arg(long, value_enum)]
+    pub component: Vec<CleanComponent>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CleanComponent {
+    Kernel,
+    Loader,
+    Nun,
+    Image,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct QmpArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// another instance's socket, e.g. out/<...>/runner/qmp.sock
+    #[arg(long, value_name = "PATH")]
+    pub socket: Option<String>,
+
+    #[command(subcommand)]
+    pub action: QmpAction,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum QmpAction {
+    /// print whether the guest is running, paused, shut down, ...
+    Status,
+    Pause,
+    Resume,
+    /// save the display as a PPM image
+    Screendump {
+        path: String,
+    },
+    /// save or restore a VM snapshot; needs qcow2 disks
+    Savevm {
+        name: String,
+    },
+    Loadvm {
+        name: String,
+    },
+    /// print the CPU registers
+    Registers {
+        /// only this CPU index
+        #[arg(long)]
+        cpu: Option<u32>,
+    },
+    Nmi,
+    /// ask the guest to power off
+    Powerdown,
+    /// stop QEMU at once
+    Quit,
+    /// any QMP command, with its arguments as a JSON object
+    Execute {
+        command: String,
+        arguments: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct VarsArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// any variable store file instead
+    #[arg(long, value_name = "PATH")]
+    pub file: Option<String>,
+
+    #[command(subcommand)]
+    pub action: VarsAction,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum VarsAction {
+    List,
+    /// add a variable, replacing one of the same name and GUID
+    Add {
+        name: String,
+        /// vendor GUID; defaults to the EFI global variable GUID
+        #[arg(long)]
+        guid: Option<String>,
+        /// comma-separated names out of NV, BS, RT, HR, AW, AT and AP
+        #[arg(long, default_value = "NV,BS,RT")]
+        attributes: String,
+        #[command(flatten)]
+        data: VarsData,
+    },
+    Delete {
+        name: String,
+        /// vendor GUID; defaults to the EFI global variable GUID
+        #[arg(long)]
+        guid: Option<String>,
+    },
+    /// start over from the firmware's template
+    Reset,
+    #[command(subcommand)]
+    Boot(BootAction),
+}
+
+#[derive(Clone, Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct VarsData {
+    /// bytes as hex, e.g. 0100 or "01 00"
+    #[arg(long)]
+    pub hex: Option<String>,
+    /// UTF-16 text with a terminating NUL, as UEFI strings are stored
+    #[arg(long)]
+    pub string: Option<String>,
+    /// raw bytes from a file
+    #[arg(long, value_name = "PATH")]
+    pub data_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum BootAction {
+    /// Boot#### entries in BootOrder order, then the ones not in it
+    List,
+    /// add a Boot#### entry for a file and append it to BootOrder
+    Add {
+        /// name the firmware's boot menu shows
+        #[arg(long)]
+        description: String,
+        /// file on any partition the firmware can read, e.g. \EFI\BOOT\BOOTX64.EFI
+        #[arg(long)]
+        path: String,
+        /// put it at the front of BootOrder
+        #[arg(long, default_value_t = false)]
+        first: bool,
+        /// add the entry without LOAD_OPTION_ACTIVE, so it is skipped at boot
+        #[arg(long, default_value_t = false)]
+        inactive: bool,
+    },
+    /// delete Boot#### (the hex number) and drop it from BootOrder
+    Delete { number: String },
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ConfigArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
 }
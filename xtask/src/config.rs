@@ -0,0 +1,879 @@
+use crate::cli::{
+    Accelerator, Arch, CommonArgs, ConsoleTransport, ImageLayout, NetworkMode, NicModel, Platform,
+    SymbolizeMode, VarsMode,
+};
+use crate::steps::qemu::{GDB_STUB_PORT, SocketBackend};
+use crate::steps::target::target_desc;
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const CONFIG_FILE_NAME: &str = "spencer.toml";
+
+// spencer.toml as written by the user; every value is optional.
+// Precedence: command line > [target.<arch>-<platform>] > top-level sections > defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub build: BuildSection,
+    #[serde(default)]
+    pub image: ImageSection,
+    #[serde(default)]
+    pub qemu: QemuSection,
+    #[serde(default)]
+    pub network: NetworkSection,
+    #[serde(default)]
+    pub debugger: DebuggerSection,
+    #[serde(default)]
+    pub test: TestSection,
+    #[serde(default)]
+    pub target: BTreeMap<String, TargetSection>,
+
+    // where the file was loaded from; relative paths inside are resolved against its dir
+    #[serde(skip)]
+    pub path: Option<Utf8PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildSection {
+    pub arch: Option<Arch>,
+    pub platform: Option<Platform>,
+    pub release: Option<bool>,
+    pub verbose: Option<bool>,
+    pub jobs: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageSection {
+    pub layout: Option<ImageLayout>,
+    pub size_mib: Option<u64>,
+    pub slack_mib: Option<u64>,
+    pub esp_layout: Option<Utf8PathBuf>,
+    pub data_partitions: Option<Vec<DataPartitionSettings>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QemuSection {
+    pub memory: Option<String>,
+    pub cpu: Option<String>,
+    pub smp: Option<SmpValue>,
+    pub machine: Option<String>,
+    pub accel: Option<Accelerator>,
+    pub firmware_code: Option<Utf8PathBuf>,
+    pub firmware_vars: Option<Utf8PathBuf>,
+    pub firmware_descriptor: Option<Utf8PathBuf>,
+    pub secure_boot: Option<bool>,
+    pub vars: Option<VarsMode>,
+    pub vars_file: Option<Utf8PathBuf>,
+    pub symbolize: Option<SymbolizeMode>,
+}
+
+impl QemuSection {
+    // vars_file wins over vars within one section
+    fn vars_setting(&self) -> Option<VarsSetting> {
+        match (&self.vars_file, self.vars) {
+            (Some(path), _) => Some(VarsSetting::File(path.clone())),
+            (None, Some(VarsMode::Fresh)) => Some(VarsSetting::Fresh),
+            (None, Some(VarsMode::Persistent)) => Some(VarsSetting::Persistent),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSection {
+    pub mode: Option<NetworkMode>,
+    pub nic: Option<NicModel>,
+    pub mac: Option<String>,
+    pub hostfwd: Option<Vec<String>>,
+    // socket mode; at most one of these
+    pub multicast: Option<String>,
+    pub listen: Option<String>,
+    pub connect: Option<String>,
+    pub capture: Option<bool>,
+}
+
+impl NetworkSection {
+    fn socket_backend(&self) -> Result<Option<SocketBackend>> {
+        let backends: Vec<SocketBackend> = [
+            self.multicast.clone().map(SocketBackend::Multicast),
+            self.listen.clone().map(SocketBackend::Listen),
+            self.connect.clone().map(SocketBackend::Connect),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if backends.len() > 1 {
+            bail!("network: set only one of multicast, listen and connect");
+        }
+        Ok(backends.into_iter().next())
+    }
+}
+
+// `smp = 4` or `smp = "cpus=4,cores=2"`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SmpValue {
+    Count(u32),
+    Spec(String),
+}
+
+impl SmpValue {
+    fn to_spec(&self) -> String {
+        match self {
+            SmpValue::Count(count) => count.to_string(),
+            SmpValue::Spec(spec) => spec.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DebuggerSection {
+    pub command: Option<String>,
+    pub gdb_port: Option<u16>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestSection {
+    pub timeout_secs: Option<u64>,
+    pub expect: Option<Utf8PathBuf>,
+    pub script: Option<Utf8PathBuf>,
+    pub console: Option<ConsoleTransport>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSection {
+    #[serde(default)]
+    pub image: ImageSection,
+    #[serde(default)]
+    pub qemu: QemuSection,
+    #[serde(default)]
+    pub network: NetworkSection,
+    #[serde(default)]
+    pub debugger: DebuggerSection,
+    #[serde(default)]
+    pub test: TestSection,
+}
+
+// Effective configuration after merging the command line with spencer.toml.
+#[derive(Clone, Debug, Serialize)]
+pub struct Settings {
+    pub arch: Arch,
+    pub platform: Platform,
+    pub release: bool,
+    pub verbose: bool,
+    pub jobs: usize,
+    #[serde(skip)]
+    pub dry_run: bool,
+    #[serde(skip)]
+    pub force: bool,
+
+    pub image: ImageSettings,
+    pub qemu: QemuSettings,
+    pub network: NetworkSettings,
+    pub debugger: DebuggerSettings,
+    pub test: TestSettings,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageSettings {
+    pub layout: ImageLayout,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_mib: Option<u64>,
+    pub slack_mib: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub esp_layout: Option<Utf8PathBuf>,
+    pub data_partitions: Vec<DataPartitionSettings>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataPartitionSettings {
+    pub name: String,
+    pub size_mib: u64,
+    pub raw_image: Option<Utf8PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QemuSettings {
+    pub memory: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine: Option<String>,
+    pub accel: Accelerator,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_code: Option<Utf8PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_vars: Option<Utf8PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_descriptor: Option<Utf8PathBuf>,
+    pub secure_boot: bool,
+    pub vars: VarsSetting,
+    pub symbolize: SymbolizeMode,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VarsSetting {
+    Fresh,
+    Persistent,
+    File(Utf8PathBuf),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NetworkSettings {
+    pub mode: NetworkMode,
+    pub nic: NicModel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hostfwd: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<SocketBackend>,
+    pub capture: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DebuggerSettings {
+    pub command: String,
+    pub gdb_port: u16,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TestSettings {
+    pub timeout_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect: Option<Utf8PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<Utf8PathBuf>,
+    pub console: ConsoleTransport,
+}
+
+// Load `path`, or <repo_root>/spencer.toml when it exists.
+pub fn load_config(repo_root: &Utf8Path, path: Option<&Utf8Path>) -> Result<ConfigFile> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let default_path = repo_root.join(CONFIG_FILE_NAME);
+            if !default_path.exists() {
+                return Ok(ConfigFile::default());
+            }
+            default_path
+        }
+    };
+
+    let text = std::fs::read_to_string(&path).with_context(|| format!("read config: {}", path))?;
+    let mut config: ConfigFile =
+        toml::from_str(&text).with_context(|| format!("parse config: {}", path))?;
+    config.path = Some(path);
+
+    Ok(config)
+}
+
+impl ConfigFile {
+    pub fn resolve(&self, common: &CommonArgs) -> Result<Settings> {
+        let Some(arch) = common.arch.clone().or(self.build.arch.clone()) else {
+            bail!(
+                "no arch given: pass --arch or set build.arch in {}",
+                CONFIG_FILE_NAME
+            );
+        };
+        let Some(platform) = common.platform.clone().or(self.build.platform.clone()) else {
+            bail!(
+                "no platform given: pass --platform or set build.platform in {}",
+                CONFIG_FILE_NAME
+            );
+        };
+
+        let release = if common.release {
+            true
+        } else if common.debug {
+            false
+        } else {
+            self.build.release.unwrap_or(false)
+        };
+
+        let jobs = match common.jobs.or(self.build.jobs) {
+            Some(0) => bail!("jobs must be at least 1"),
+            Some(jobs) => jobs,
+            None => std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+        };
+
+        let target_key = target_key(&arch, &platform);
+        let target = self.target.get(&target_key).cloned().unwrap_or_default();
+
+        for key in self.target.keys() {
+            if !known_target_keys().contains(key) {
+                bail!(
+                    "unknown section [target.{}] in {} (expected one of: {})",
+                    key,
+                    CONFIG_FILE_NAME,
+                    known_target_keys().join(", ")
+                );
+            }
+        }
+
+        let image = ImageSettings {
+            layout: common
+                .image_layout
+                .or(target.image.layout)
+                .or(self.image.layout)
+                .unwrap_or(ImageLayout::Superfloppy),
+            size_mib: common
+                .image_size
+                .or(target.image.size_mib)
+                .or(self.image.size_mib),
+            slack_mib: common
+                .image_slack
+                .or(target.image.slack_mib)
+                .or(self.image.slack_mib)
+                .unwrap_or(8),
+            esp_layout: match &common.esp_layout {
+                Some(path) => Some(Utf8PathBuf::from(path)),
+                None => target
+                    .image
+                    .esp_layout
+                    .or(self.image.esp_layout.clone())
+                    .map(|path| self.resolve_path(&path)),
+            },
+            data_partitions: if !common.data_partition.is_empty() {
+                common
+                    .data_partition
+                    .iter()
+                    .map(|partition| DataPartitionSettings {
+                        name: partition.name.clone(),
+                        size_mib: partition.size_mib,
+                        raw_image: partition.raw_image.as_ref().map(Utf8PathBuf::from),
+                    })
+                    .collect()
+            } else {
+                target
+                    .image
+                    .data_partitions
+                    .or(self.image.data_partitions.clone())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|partition| DataPartitionSettings {
+                        raw_image: partition.raw_image.map(|path| self.resolve_path(&path)),
+                        ..partition
+                    })
+                    .collect()
+            },
+        };
+
+        // command line, target, top level; a config file's paths are relative to it
+        let vars = match (&common.vars_file, common.vars) {
+            (Some(path), _) => VarsSetting::File(Utf8PathBuf::from(path)),
+            (None, Some(VarsMode::Fresh)) => VarsSetting::Fresh,
+            (None, Some(VarsMode::Persistent)) => VarsSetting::Persistent,
+            (None, None) => match target
+                .qemu
+                .vars_setting()
+                .or_else(|| self.qemu.vars_setting())
+            {
+                Some(VarsSetting::File(path)) => VarsSetting::File(self.resolve_path(&path)),
+                Some(setting) => setting,
+                None => VarsSetting::Fresh,
+            },
+        };
+
+        // firmware given on the command line replaces the configured one entirely
+        let (firmware_code, firmware_vars, firmware_descriptor) = match (
+            &common.firmware_code,
+            &common.firmware_vars,
+            &common.firmware_descriptor,
+        ) {
+            (Some(code), Some(vars), _) => (
+                Some(Utf8PathBuf::from(code)),
+                Some(Utf8PathBuf::from(vars)),
+                None,
+            ),
+            (_, _, Some(descriptor)) => (None, None, Some(Utf8PathBuf::from(descriptor))),
+            _ => (
+                target
+                    .qemu
+                    .firmware_code
+                    .or(self.qemu.firmware_code.clone())
+                    .map(|path| self.resolve_path(&path)),
+                target
+                    .qemu
+                    .firmware_vars
+                    .or(self.qemu.firmware_vars.clone())
+                    .map(|path| self.resolve_path(&path)),
+                target
+                    .qemu
+                    .firmware_descriptor
+                    .or(self.qemu.firmware_descriptor.clone())
+                    .map(|path| self.resolve_path(&path)),
+            ),
+        };
+
+        let qemu = QemuSettings {
+            memory: common
+                .memory
+                .clone()
+                .or(target.qemu.memory)
+                .or(self.qemu.memory.clone())
+                .unwrap_or_else(|| "4G".to_string()),
+            cpu: common
+                .cpu
+                .clone()
+                .or(target.qemu.cpu)
+                .or(self.qemu.cpu.clone()),
+            smp: common.smp.clone().or_else(|| {
+                target
+                    .qemu
+                    .smp
+                    .as_ref()
+                    .or(self.qemu.smp.as_ref())
+                    .map(SmpValue::to_spec)
+            }),
+            machine: common
+                .machine
+                .clone()
+                .or(target.qemu.machine)
+                .or(self.qemu.machine.clone()),
+            accel: common
+                .accel
+                .or(target.qemu.accel)
+                .or(self.qemu.accel)
+                .unwrap_or(Accelerator::Auto),
+            firmware_code,
+            firmware_vars,
+            firmware_descriptor,
+            secure_boot: secure_boot_flag(common)
+                .or(target.qemu.secure_boot)
+                .or(self.qemu.secure_boot)
+                .unwrap_or(false),
+            vars,
+            symbolize: common
+                .symbolize
+                .or(target.qemu.symbolize)
+                .or(self.qemu.symbolize)
+                .unwrap_or(SymbolizeMode::Report),
+        };
+
+        let network = self.resolve_network(common, &target.network)?;
+
+        let debugger = DebuggerSettings {
+            command: target
+                .debugger
+                .command
+                .or(self.debugger.command.clone())
+                .unwrap_or_else(|| "gdb".to_string()),
+            gdb_port: common
+                .gdb_port
+                .or(target.debugger.gdb_port)
+                .or(self.debugger.gdb_port)
+                .unwrap_or(GDB_STUB_PORT),
+        };
+
+        let test = TestSettings {
+            timeout_secs: target
+                .test
+                .timeout_secs
+                .or(self.test.timeout_secs)
+                .unwrap_or(60),
+            expect: target
+                .test
+                .expect
+                .or(self.test.expect.clone())
+                .map(|path| self.resolve_path(&path)),
+            script: target
+                .test
+                .script
+                .or(self.test.script.clone())
+                .map(|path| self.resolve_path(&path)),
+            console: target
+                .test
+                .console
+                .or(self.test.console)
+                .unwrap_or(ConsoleTransport::Socket),
+        };
+
+        Ok(Settings {
+            arch,
+            platform,
+            release,
+            verbose: common.verbose || self.build.verbose.unwrap_or(false),
+            jobs,
+            dry_run: common.dry_run,
+            force: common.force,
+            image,
+            qemu,
+            network,
+            debugger,
+            test,
+        })
+    }
+
+    fn resolve_network(
+        &self,
+        common: &CommonArgs,
+        target: &NetworkSection,
+    ) -> Result<NetworkSettings> {
+        let mode = common
+            .net
+            .or(target.mode)
+            .or(self.network.mode)
+            .unwrap_or(NetworkMode::User);
+
+        // the options of the other modes are dropped, so that a target can
+        // switch modes without unsetting the top level's
+        let hostfwd = match mode {
+            NetworkMode::User if !common.hostfwd.is_empty() => common.hostfwd.clone(),
+            NetworkMode::User => target
+                .hostfwd
+                .clone()
+                .or(self.network.hostfwd.clone())
+                .unwrap_or_default(),
+            _ if !common.hostfwd.is_empty() => {
+                bail!("--hostfwd only applies to user networking");
+            }
+            _ => Vec::new(),
+        };
+        // a target's endpoint replaces the top level's as a whole
+        let socket = match mode {
+            NetworkMode::Socket => Some(
+                target
+                    .socket_backend()?
+                    .or(self.network.socket_backend()?)
+                    .unwrap_or_else(|| SocketBackend::Multicast("230.0.0.1:5100".to_string())),
+            ),
+            _ => None,
+        };
+
+        let capture = match mode {
+            NetworkMode::None if common.capture => {
+                bail!("--capture needs a NIC, but the network mode is none");
+            }
+            NetworkMode::None => false,
+            _ => common.capture || target.capture.or(self.network.capture).unwrap_or(false),
+        };
+
+        Ok(NetworkSettings {
+            mode,
+            nic: common
+                .nic
+                .or(target.nic)
+                .or(self.network.nic)
+                .unwrap_or(NicModel::E1000),
+            mac: target.mac.clone().or(self.network.mac.clone()),
+            hostfwd,
+            socket,
+            capture,
+        })
+    }
+
+    fn resolve_path(&self, path: &Utf8Path) -> Utf8PathBuf {
+        match self
+            .path
+            .as_ref()
+            .and_then(|config_path| config_path.parent())
+        {
+            Some(config_dir) if path.is_relative() => config_dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+impl Settings {
+    // Effective configuration as TOML, for troubleshooting.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).context("serialize effective config")
+    }
+}
+
+// --secure-boot / --no-secure-boot; None leaves it to spencer.toml
+fn secure_boot_flag(common: &CommonArgs) -> Option<bool> {
+    if common.secure_boot {
+        Some(true)
+    } else if common.no_secure_boot {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+pub fn target_key(arch: &Arch, platform: &Platform) -> String {
+    let platform_name = match platform {
+        Platform::Qemu => "qemu",
+    };
+
+    format!("{}-{}", target_desc(arch).arch_name, platform_name)
+}
+
+fn known_target_keys() -> Vec<String> {
+    let mut keys = Vec::new();
+    for arch in Arch::value_variants() {
+        for platform in Platform::value_variants() {
+            keys.push(target_key(arch, platform));
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        common: CommonArgs,
+    }
+
+    fn common(args: &[&str]) -> CommonArgs {
+        let args = ["xtask", "--arch", "x86-64", "--platform", "qemu"]
+            .into_iter()
+            .chain(args.iter().copied());
+        TestCli::try_parse_from(args).unwrap().common
+    }
+
+    fn config(text: &str) -> ConfigFile {
+        let mut config: ConfigFile = toml::from_str(text).unwrap();
+        config.path = Some(Utf8PathBuf::from("/project/spencer.toml"));
+        config
+    }
+
+    fn resolve(text: &str, args: &[&str]) -> Settings {
+        config(text).resolve(&common(args)).unwrap()
+    }
+
+    fn resolve_error(text: &str, args: &[&str]) -> String {
+        format!("{:#}", config(text).resolve(&common(args)).unwrap_err())
+    }
+
+    const TOP_LEVEL: &str = r#"
+        [image]
+        slack_mib = 16
+        [qemu]
+        memory = "1G"
+        [network]
+        nic = "rtl8139"
+        [debugger]
+        gdb_port = 2000
+        [test]
+        timeout_secs = 10
+    "#;
+
+    const TARGET: &str = r#"
+        [target.x86_64-qemu.image]
+        slack_mib = 32
+        [target.x86_64-qemu.qemu]
+        memory = "2G"
+        [target.x86_64-qemu.network]
+        nic = "virtio-net"
+        [target.x86_64-qemu.debugger]
+        gdb_port = 3000
+        [target.x86_64-qemu.test]
+        timeout_secs = 20
+    "#;
+
+    #[test]
+    fn defaults_apply_without_config() {
+        let settings = resolve("", &[]);
+        assert_eq!(settings.qemu.memory, "4G");
+        assert_eq!(settings.image.slack_mib, 8);
+        assert_eq!(settings.network.nic, NicModel::E1000);
+        assert_eq!(settings.debugger.gdb_port, GDB_STUB_PORT);
+        assert_eq!(settings.test.timeout_secs, 60);
+        assert!(!settings.release);
+    }
+
+    #[test]
+    fn top_level_sections_override_defaults() {
+        let settings = resolve(TOP_LEVEL, &[]);
+        assert_eq!(settings.qemu.memory, "1G");
+        assert_eq!(settings.image.slack_mib, 16);
+        assert_eq!(settings.network.nic, NicModel::Rtl8139);
+        assert_eq!(settings.debugger.gdb_port, 2000);
+        assert_eq!(settings.test.timeout_secs, 10);
+    }
+
+    #[test]
+    fn target_table_overrides_top_level() {
+        let settings = resolve(&format!("{}{}", TOP_LEVEL, TARGET), &[]);
+        assert_eq!(settings.qemu.memory, "2G");
+        assert_eq!(settings.image.slack_mib, 32);
+        assert_eq!(settings.network.nic, NicModel::VirtioNet);
+        assert_eq!(settings.debugger.gdb_port, 3000);
+        assert_eq!(settings.test.timeout_secs, 20);
+    }
+
+    #[test]
+    fn command_line_overrides_target_table() {
+        let settings = resolve(
+            &format!("{}{}", TOP_LEVEL, TARGET),
+            &[
+                "--memory",
+                "3G",
+                "--image-slack",
+                "64",
+                "--nic",
+                "e1000",
+                "--gdb-port",
+                "4000",
+            ],
+        );
+        assert_eq!(settings.qemu.memory, "3G");
+        assert_eq!(settings.image.slack_mib, 64);
+        assert_eq!(settings.network.nic, NicModel::E1000);
+        assert_eq!(settings.debugger.gdb_port, 4000);
+    }
+
+    #[test]
+    fn secure_boot_can_be_turned_off_on_the_command_line() {
+        let config = "[qemu]\nsecure_boot = true\n";
+        assert!(resolve(config, &[]).qemu.secure_boot);
+        assert!(!resolve(config, &["--no-secure-boot"]).qemu.secure_boot);
+
+        let config = "[qemu]\nsecure_boot = false\n[target.x86_64-qemu.qemu]\nsecure_boot = true\n";
+        assert!(resolve(config, &[]).qemu.secure_boot);
+        assert!(!resolve(config, &["--no-secure-boot"]).qemu.secure_boot);
+
+        assert!(resolve("", &["--secure-boot"]).qemu.secure_boot);
+        assert!(!resolve("", &[]).qemu.secure_boot);
+    }
+
+    #[test]
+    fn other_targets_do_not_apply() {
+        let settings = resolve(
+            r#"
+            [qemu]
+            memory = "1G"
+            [target.aarch64-qemu.qemu]
+            memory = "2G"
+            "#,
+            &[],
+        );
+        assert_eq!(settings.qemu.memory, "1G");
+
+        let error = resolve_error("[target.x86_64-bochs.qemu]\nmemory = \"2G\"\n", &[]);
+        assert!(error.contains("unknown section [target.x86_64-bochs]"));
+    }
+
+    #[test]
+    fn debug_flag_overrides_configured_release() {
+        let text = "[build]\nrelease = true\n";
+        assert!(resolve(text, &[]).release);
+        assert!(!resolve(text, &["--debug"]).release);
+    }
+
+    #[test]
+    fn config_paths_are_relative_to_the_file() {
+        let text = r#"
+            [image]
+            esp_layout = "esp.toml"
+            [test]
+            expect = "/abs/expect.toml"
+            [target.x86_64-qemu.test]
+            script = "scripts/boot.script"
+        "#;
+        let settings = resolve(text, &[]);
+        assert_eq!(
+            settings.image.esp_layout.as_deref(),
+            Some(Utf8Path::new("/project/esp.toml"))
+        );
+        assert_eq!(
+            settings.test.expect.as_deref(),
+            Some(Utf8Path::new("/abs/expect.toml"))
+        );
+        assert_eq!(
+            settings.test.script.as_deref(),
+            Some(Utf8Path::new("/project/scripts/boot.script"))
+        );
+
+        // command line paths are taken as given
+        let settings = resolve(text, &["--esp-layout", "other.toml"]);
+        assert_eq!(
+            settings.image.esp_layout.as_deref(),
+            Some(Utf8Path::new("other.toml"))
+        );
+    }
+
+    #[test]
+    fn command_line_firmware_replaces_configured_firmware() {
+        let text = r#"
+            [qemu]
+            firmware_descriptor = "fw.json"
+            [target.x86_64-qemu.qemu]
+            firmware_code = "code.fd"
+            firmware_vars = "vars.fd"
+        "#;
+        let settings = resolve(text, &[]);
+        assert_eq!(
+            settings.qemu.firmware_code.as_deref(),
+            Some(Utf8Path::new("/project/code.fd"))
+        );
+        assert_eq!(
+            settings.qemu.firmware_descriptor.as_deref(),
+            Some(Utf8Path::new("/project/fw.json"))
+        );
+
+        let settings = resolve(text, &["--firmware-descriptor", "/other.json"]);
+        assert_eq!(settings.qemu.firmware_code, None);
+        assert_eq!(settings.qemu.firmware_vars, None);
+        assert_eq!(
+            settings.qemu.firmware_descriptor.as_deref(),
+            Some(Utf8Path::new("/other.json"))
+        );
+    }
+
+    #[test]
+    fn vars_setting_follows_the_same_order() {
+        let top_level = "[qemu]\nvars = \"persistent\"\nvars_file = \"my.fd\"\n";
+        assert!(matches!(
+            resolve(top_level, &[]).qemu.vars,
+            VarsSetting::File(path) if path == "/project/my.fd"
+        ));
+
+        let with_target = format!("{}[target.x86_64-qemu.qemu]\nvars = \"fresh\"\n", top_level);
+        assert!(matches!(
+            resolve(&with_target, &[]).qemu.vars,
+            VarsSetting::Fresh
+        ));
+        assert!(matches!(
+            resolve(&with_target, &["--vars", "persistent"]).qemu.vars,
+            VarsSetting::Persistent
+        ));
+    }
+
+    #[test]
+    fn network_options_of_other_modes_are_dropped() {
+        let text = r#"
+            [network]
+            hostfwd = ["tcp:127.0.0.1:8080-:80"]
+            [target.x86_64-qemu.network]
+            mode = "socket"
+            connect = "127.0.0.1:5000"
+        "#;
+        let settings = resolve(text, &[]);
+        assert!(settings.network.hostfwd.is_empty());
+        assert!(matches!(
+            settings.network.socket,
+            Some(SocketBackend::Connect(ref address)) if address == "127.0.0.1:5000"
+        ));
+
+        let settings = resolve(text, &["--net", "user"]);
+        assert_eq!(settings.network.hostfwd, ["tcp:127.0.0.1:8080-:80"]);
+        assert!(settings.network.socket.is_none());
+
+        let error = resolve_error(text, &["--hostfwd", "tcp::1-:1"]);
+        assert!(error.contains("--hostfwd only applies to user networking"));
+    }
+}
@@ -1,165 +1,959 @@
+// QMP and the serial console are Unix sockets, and QEMU runs in its own
+// process group
+#[cfg(not(unix))]
+compile_error!("the xtask needs a Unix host");
+
 mod cli;
+mod config;
 mod steps;
 
 use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
-use clap::Parser;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, ValueEnum};
+use std::time::Duration;
 
 fn main() -> Result<()> {
     let cli = cli::Cli::parse();
 
-    let repo_root = std::env::current_dir().context("get current_dir")?;
-    let repo_root = Utf8PathBuf::from_path_buf(repo_root)
-        .map_err(|_| anyhow::anyhow!("repo root path is not valid utf-8"))?;
+    let current_dir = std::env::current_dir().context("get current_dir")?;
+    let current_dir = Utf8PathBuf::from_path_buf(current_dir)
+        .map_err(|_| anyhow::anyhow!("current directory is not valid utf-8"))?;
+
+    // cargo starts the runner in the package being run, not at the top level
+    let repo_root = match &cli.command {
+        cli::Command::Runner(_) => Utf8Path::new(env!("CARGO_MANIFEST_DIR"))
+            .parent()
+            .context("xtask has no parent directory")?
+            .to_path_buf(),
+        _ => current_dir.clone(),
+    };
+
+    let config_path = cli.config.as_ref().map(Utf8PathBuf::from);
+    let config = config::load_config(&repo_root, config_path.as_deref())?;
 
     match cli.command {
         cli::Command::Build(args) => {
-            run_build_pipeline(&repo_root, &args.common)?;
+            let settings = config.resolve(&args.common)?;
+            run_build_pipeline(&repo_root, &settings)?;
         }
         cli::Command::Run(args) => {
-            run_build_pipeline(&repo_root, &args.common)?;
-            run_qemu(&repo_root, &args)?;
+            let settings = config.resolve(&args.common)?;
+            if args.topology.is_some() && (args.gdb || args.stop) {
+                anyhow::bail!("--gdb and --stop are not supported with --topology");
+            }
+            let artifacts = run_build_pipeline(&repo_root, &settings)?;
+            match &args.topology {
+                Some(topology) => {
+                    let topology_path = current_dir.join(topology);
+                    run_topology(
+                        &repo_root,
+                        &settings,
+                        &artifacts,
+                        &topology_path,
+                        false,
+                        false,
+                    )?;
+                }
+                None => run_qemu(&repo_root, &settings, &artifacts, &args)?,
+            }
+        }
+        cli::Command::Gdb(args) => {
+            let mut settings = config.resolve(&args.common)?;
+            if let Some(debugger) = &args.debugger {
+                settings.debugger.command = debugger.clone();
+            }
+            let artifacts = run_build_pipeline(&repo_root, &settings)?;
+            run_gdb(&repo_root, &settings, &artifacts)?;
+        }
+        cli::Command::Test(args) => {
+            let mut settings = config.resolve(&args.common)?;
+            if let Some(timeout) = args.timeout {
+                settings.test.timeout_secs = timeout;
+            }
+            if let Some(expect) = &args.expect {
+                settings.test.expect = Some(Utf8PathBuf::from(expect));
+            }
+            if let Some(script) = &args.script {
+                settings.test.script = Some(Utf8PathBuf::from(script));
+            }
+            if let Some(console) = args.console {
+                settings.test.console = console;
+            }
+            if args.topology.is_some() && settings.test.script.is_some() {
+                anyhow::bail!("console scripts are not supported with --topology");
+            }
+            let artifacts = run_build_pipeline(&repo_root, &settings)?;
+            let exit_code = match &args.topology {
+                Some(topology) => {
+                    let topology_path = current_dir.join(topology);
+                    run_topology(
+                        &repo_root,
+                        &settings,
+                        &artifacts,
+                        &topology_path,
+                        true,
+                        args.bless,
+                    )?
+                }
+                None => run_test(&repo_root, &settings, &artifacts, args.bless)?,
+            };
+            std::process::exit(exit_code);
+        }
+        cli::Command::Runner(args) => {
+            let elf_path = current_dir.join(&args.elf);
+
+            let mut common = args.common.clone();
+            if common.arch.is_none() {
+                common.arch = Some(steps::target::arch_from_elf(&elf_path)?);
+            }
+            // the runner always boots on QEMU
+            common.platform.get_or_insert(cli::Platform::Qemu);
+
+            let mut settings = config.resolve(&common)?;
+            if let Some(timeout) = args.timeout {
+                settings.test.timeout_secs = timeout;
+            }
+            // a filter would otherwise look like it worked
+            if !args.args.is_empty() {
+                eprintln!(
+                    "[runner] warning: the guest has no command line; ignoring {} \
+                     (every test in the image runs)",
+                    args.args.join(" ")
+                );
+            }
+
+            let exit_code = run_runner(&repo_root, &settings, &elf_path)?;
+            std::process::exit(exit_code);
+        }
+        cli::Command::Matrix(args) => {
+            steps::matrix::run_matrix(&args, |common| {
+                build_matrix_combination(&repo_root, &config, &args, common)
+            })?;
+        }
+        cli::Command::Doctor(args) => {
+            run_doctor(&repo_root, &config, &args)?;
+        }
+        cli::Command::Clean(args) => {
+            run_clean(&repo_root, &config, &args)?;
+        }
+        cli::Command::Qmp(args) => {
+            let socket_path = match &args.socket {
+                Some(socket) => current_dir.join(socket),
+                None => out_base(&repo_root, &config.resolve(&args.common)?).join("qmp.sock"),
+            };
+            run_qmp(&current_dir, &socket_path, &args.action)?;
+        }
+        cli::Command::Vars(args) => {
+            let settings = config.resolve(&args.common)?;
+            let vars_args = steps::vars::RunVarsArgs {
+                current_dir: &current_dir,
+                out_base: &out_base(&repo_root, &settings),
+                vars: vars_store(&settings),
+                file: args.file.as_deref(),
+                action: &args.action,
+            };
+            steps::vars::run_vars(&vars_args, || vars_template_path(&repo_root, &settings))?;
+        }
+        cli::Command::Config(args) => {
+            let settings = config.resolve(&args.common)?;
+            match &config.path {
+                Some(path) => println!("# merged from {} and the command line", path),
+                None => println!(
+                    "# no {} found; defaults and the command line",
+                    config::CONFIG_FILE_NAME
+                ),
+            }
+            print!("{}", settings.to_toml()?);
         }
     }
 
     Ok(())
 }
 
-fn run_build_pipeline(repo_root: &camino::Utf8Path, common: &cli::CommonArgs) -> Result<()> {
-    let kernel_args = steps::kernel::BuildKernelArgs {
-        arch: common.arch.clone(),
-        platform: common.platform.clone(),
-        release: common.release,
-        verbose: common.verbose,
-        dry_run: common.dry_run,
+struct BuildArtifacts {
+    kernel: steps::kernel::KernelArtifacts,
+    a9nloader: steps::a9nloader::A9nloaderArtifacts,
+    nun_os: steps::nun::NunOsArtifacts,
+    image: steps::image::ImageArtifacts,
+}
+
+fn out_base(repo_root: &Utf8Path, settings: &config::Settings) -> Utf8PathBuf {
+    steps::target::out_base(
+        repo_root,
+        &settings.arch,
+        &settings.platform,
+        settings.release,
+    )
+}
+
+fn kernel_args(settings: &config::Settings) -> steps::kernel::BuildKernelArgs {
+    steps::kernel::BuildKernelArgs {
+        arch: settings.arch.clone(),
+        platform: settings.platform.clone(),
+        release: settings.release,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
+        force: settings.force,
+    }
+}
+
+fn a9nloader_args(settings: &config::Settings) -> steps::a9nloader::BuildA9nloaderArgs {
+    steps::a9nloader::BuildA9nloaderArgs {
+        arch: settings.arch.clone(),
+        platform: settings.platform.clone(),
+        release: settings.release,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
+        force: settings.force,
+    }
+}
+
+fn nun_os_args(settings: &config::Settings) -> steps::nun::BuildNunOsArgs {
+    steps::nun::BuildNunOsArgs {
+        arch: settings.arch.clone(),
+        platform: settings.platform.clone(),
+        release: settings.release,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
+        force: settings.force,
+        use_nightly_build_std: true,
+    }
+}
+
+// crash addresses are looked up in the kernel, then in the init ELF
+fn symbolize_args(
+    settings: &config::Settings,
+    artifacts: &BuildArtifacts,
+) -> steps::symbolize::SymbolizeArgs {
+    steps::symbolize::SymbolizeArgs {
+        mode: settings.qemu.symbolize,
+        elf_paths: vec![
+            artifacts.kernel.kernel_elf.path.clone(),
+            artifacts.nun_os.init_elf.path.clone(),
+        ],
+    }
+}
+
+fn machine_options(settings: &config::Settings) -> steps::qemu::MachineOptions<'_> {
+    steps::qemu::MachineOptions {
+        memory: &settings.qemu.memory,
+        cpu: settings.qemu.cpu.as_deref(),
+        smp: settings.qemu.smp.as_deref(),
+        machine: settings.qemu.machine.as_deref(),
+        accel: settings.qemu.accel,
+    }
+}
+
+fn vars_store(settings: &config::Settings) -> steps::qemu::VarsStore<'_> {
+    match &settings.qemu.vars {
+        config::VarsSetting::Fresh => steps::qemu::VarsStore::Fresh,
+        config::VarsSetting::Persistent => steps::qemu::VarsStore::Persistent,
+        config::VarsSetting::File(path) => steps::qemu::VarsStore::File(path),
+    }
+}
+
+fn network_options<'a>(
+    settings: &'a config::Settings,
+    capture_path: &'a Utf8Path,
+) -> steps::qemu::NetworkOptions<'a> {
+    let network = &settings.network;
+    steps::qemu::NetworkOptions {
+        mode: network.mode,
+        nic: network.nic,
+        mac: network.mac.as_deref(),
+        hostfwd: &network.hostfwd,
+        socket: network.socket.as_ref(),
+        capture: network.capture.then_some(capture_path),
+    }
+}
+
+fn run_build_pipeline(repo_root: &Utf8Path, settings: &config::Settings) -> Result<BuildArtifacts> {
+    // before any step starts, so that a kernel build is not wasted on a target
+    // the loader cannot be built for
+    steps::target::validate_pipeline_supported(&settings.arch, &settings.platform)?;
+
+    let kernel_args = kernel_args(settings);
+
+    let a9nloader_args = a9nloader_args(settings);
+
+    let nun_os_args = nun_os_args(settings);
+
+    let out_base = out_base(repo_root, settings);
+
+    let img_path = out_base.join("spencer.img");
+
+    let kernel_artifacts = steps::scheduler::Output::new();
+    let a9nloader_artifacts = steps::scheduler::Output::new();
+    let nun_os_artifacts = steps::scheduler::Output::new();
+    let image_artifacts = steps::scheduler::Output::new();
+
+    let pipeline = vec![
+        steps::scheduler::Step::new("kernel", &[], || {
+            kernel_artifacts.set(steps::kernel::build_kernel(repo_root, &kernel_args)?);
+            Ok(())
+        }),
+        steps::scheduler::Step::new("a9nloader", &[], || {
+            a9nloader_artifacts.set(steps::a9nloader::build_a9nloader(
+                repo_root,
+                &a9nloader_args,
+            )?);
+            Ok(())
+        }),
+        steps::scheduler::Step::new("nun", &[], || {
+            nun_os_artifacts.set(steps::nun::build_nun_os(repo_root, &nun_os_args)?);
+            Ok(())
+        }),
+        steps::scheduler::Step::new("image", &["kernel", "a9nloader", "nun"], || {
+            let a9nloader = a9nloader_artifacts.get()?;
+            let nun_os = nun_os_artifacts.get()?;
+            let kernel = kernel_artifacts.get()?;
+
+            image_artifacts.set(build_image(
+                settings,
+                &img_path,
+                &a9nloader.loader_efi.path,
+                &nun_os.init_elf.path,
+                &kernel.kernel_elf.path,
+            )?);
+            Ok(())
+        }),
+    ];
+
+    // dry-run output stays in pipeline order
+    let jobs = if settings.dry_run { 1 } else { settings.jobs };
+
+    steps::scheduler::run_steps(pipeline, jobs, settings.verbose)?;
+
+    let artifacts = BuildArtifacts {
+        kernel: kernel_artifacts.into_inner()?,
+        a9nloader: a9nloader_artifacts.into_inner()?,
+        nun_os: nun_os_artifacts.into_inner()?,
+        image: image_artifacts.into_inner()?,
     };
 
-    steps::kernel::build_kernel(repo_root, &kernel_args)?;
+    let manifest_path = out_base.join("manifest.json");
+    if settings.dry_run {
+        eprintln!("[dry-run] write manifest: {}", manifest_path);
+    } else {
+        steps::artifact::write_manifest(
+            &manifest_path,
+            &config::target_key(&settings.arch, &settings.platform),
+            if settings.release { "release" } else { "debug" },
+            &[
+                &artifacts.kernel.kernel_elf,
+                &artifacts.a9nloader.loader_efi,
+                &artifacts.nun_os.init_elf,
+                &artifacts.image.disk_image,
+            ],
+        )?;
+        eprintln!("manifest: {}", manifest_path);
+    }
+
+    Ok(artifacts)
+}
 
-    let a9nloader_args = steps::a9nloader::BuildA9nloaderArgs {
-        arch: common.arch.clone(),
-        platform: common.platform.clone(),
-        release: common.release,
-        verbose: common.verbose,
-        dry_run: common.dry_run,
+fn build_image(
+    settings: &config::Settings,
+    img_path: &Utf8Path,
+    loader_efi_path: &Utf8Path,
+    init_elf_path: &Utf8Path,
+    kernel_elf_path: &Utf8Path,
+) -> Result<steps::image::ImageArtifacts> {
+    let target = steps::target::target_desc(&settings.arch);
+
+    let data_partitions: Vec<_> = settings
+        .image
+        .data_partitions
+        .iter()
+        .map(|partition| steps::image::DataPartition {
+            name: partition.name.clone(),
+            size_mib: partition.size_mib,
+            raw_image_path: partition.raw_image.clone(),
+        })
+        .collect();
+
+    let entries = vec![
+        steps::image::EspEntry::new(target.boot_efi_image_path(), loader_efi_path),
+        steps::image::EspEntry::new("/kernel/init.elf", init_elf_path),
+        steps::image::EspEntry::new("/kernel/kernel.elf", kernel_elf_path),
+    ];
+
+    let img_args = steps::image::BuildImgArgs {
+        img_path,
+        entries: &entries,
+        esp_layout_path: settings.image.esp_layout.as_deref(),
+        layout: settings.image.layout,
+        data_partitions: &data_partitions,
+        image_size_mib: settings.image.size_mib,
+        slack_mib: settings.image.slack_mib,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
+        force: settings.force,
     };
 
-    steps::a9nloader::build_a9nloader(repo_root, &a9nloader_args)?;
+    steps::image::build_fat_img(&img_args)
+}
 
-    let nun_os_args = steps::nun::BuildNunOsArgs {
-        arch: common.arch.clone(),
-        platform: common.platform.clone(),
-        release: common.release,
-        verbose: common.verbose,
-        dry_run: common.dry_run,
-        use_nightly_build_std: true,
+fn run_qemu(
+    repo_root: &Utf8Path,
+    settings: &config::Settings,
+    artifacts: &BuildArtifacts,
+    args: &cli::RunArgs,
+) -> Result<()> {
+    let out_base = out_base(repo_root, settings);
+
+    let serial_log_path = out_base.join("serial.log");
+    let qmp_socket_path = out_base.join("qmp.sock");
+    let capture_path = out_base.join("net.pcap");
+
+    // QEMU owns the terminal, so crashes can only be reported afterwards
+    let mut symbolize = symbolize_args(settings, artifacts);
+    if symbolize.mode == cli::SymbolizeMode::Inline {
+        symbolize.mode = cli::SymbolizeMode::Report;
+    }
+
+    let firmware = resolve_firmware(repo_root, settings)?;
+
+    let qemu_args = steps::qemu::RunQemuArgs {
+        arch: settings.arch.clone(),
+        platform: settings.platform.clone(),
+        out_base: &out_base,
+        img_path: &artifacts.image.disk_image.path,
+        firmware: &firmware,
+        vars: vars_store(settings),
+        machine: machine_options(settings),
+        network: network_options(settings, &capture_path),
+        serial: match symbolize.mode {
+            cli::SymbolizeMode::Off => steps::qemu::SerialTarget::Stdio,
+            _ => steps::qemu::SerialTarget::StdioLogged(&serial_log_path),
+        },
+        qmp_socket: Some(&qmp_socket_path),
+        gdb_port: args.gdb.then_some(settings.debugger.gdb_port),
+        stop_at_start: args.stop,
+        headless: false,
+        test_exit: false,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
     };
 
-    steps::nun::build_nun_os(repo_root, &nun_os_args)?;
+    // a guest that crashed may well have made QEMU exit with an error
+    let result = steps::qemu::run_qemu(&qemu_args);
+
+    if !settings.dry_run {
+        steps::symbolize::report_crashes(&symbolize, &serial_log_path)?;
+    }
+
+    result
+}
+
+fn run_gdb(
+    repo_root: &Utf8Path,
+    settings: &config::Settings,
+    artifacts: &BuildArtifacts,
+) -> Result<()> {
+    let out_base = out_base(repo_root, settings);
+
+    let serial_log_path = out_base.join("serial.log");
+    let qmp_socket_path = out_base.join("qmp.sock");
+    let capture_path = out_base.join("net.pcap");
 
-    let target_arch = match kernel_args.arch {
-        cli::Arch::X86_64 => "x86_64",
-        cli::Arch::Aarch64 => "aarch64",
-        cli::Arch::Riscv64 => "riscv64",
+    let firmware = resolve_firmware(repo_root, settings)?;
+
+    // the debugger owns the terminal, so the guest serial goes to a file
+    let qemu_args = steps::qemu::RunQemuArgs {
+        arch: settings.arch.clone(),
+        platform: settings.platform.clone(),
+        out_base: &out_base,
+        img_path: &artifacts.image.disk_image.path,
+        firmware: &firmware,
+        vars: vars_store(settings),
+        machine: machine_options(settings),
+        network: network_options(settings, &capture_path),
+        serial: steps::qemu::SerialTarget::File(&serial_log_path),
+        qmp_socket: Some(&qmp_socket_path),
+        gdb_port: Some(settings.debugger.gdb_port),
+        stop_at_start: true,
+        headless: false,
+        test_exit: false,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
     };
 
-    let platform_name = match kernel_args.platform {
-        cli::Platform::Qemu => "qemu",
+    let gdb_args = steps::gdb::RunGdbArgs {
+        arch: settings.arch.clone(),
+        debugger: &settings.debugger.command,
+        out_base: &out_base,
+        kernel_elf_path: &artifacts.kernel.kernel_elf.path,
+        init_elf_path: &artifacts.nun_os.init_elf.path,
+        gdb_port: settings.debugger.gdb_port,
+        verbose: settings.verbose,
+        dry_run: settings.dry_run,
     };
 
-    let out_base = repo_root.join("out").join(format!(
-        "{}-{}-{}",
-        target_arch,
-        platform_name,
-        if kernel_args.release {
-            "release"
-        } else {
-            "debug"
-        },
-    ));
+    if !settings.dry_run {
+        steps::process::catch_interrupts()?;
+    }
 
-    let img_path = out_base.join("spencer.img");
+    // QEMU is stopped once the debugger exits (or fails)
+    let qemu = steps::qemu::spawn_qemu(&qemu_args)?;
 
-    // Sources are currently x86_64-fixed based on your confirmed output paths.
-    // Generalize later by deriving from the target json or a mapping table.
-    let bootx64_efi_source = out_base.join("a9nloader").join("a9nloader-rs.efi");
+    if !settings.dry_run {
+        eprintln!("[gdb] guest serial: {}", serial_log_path);
+    }
 
-    let init_elf_source = out_base
-        .join("nun_os_target_dir")
-        .join("x86_64-unknown-a9n")
-        .join(if kernel_args.release {
-            "release"
-        } else {
-            "debug"
-        })
-        .join("core");
+    let result = steps::gdb::run_gdb(&gdb_args);
+    if let Some(mut qemu) = qemu {
+        steps::qmp::shut_down(&mut qemu, &qmp_socket_path);
+    }
 
-    let kernel_elf_source = out_base.join("a9n").join("kernel.elf");
+    // the debugger owns the terminal, so crashes are reported afterwards
+    if !settings.dry_run && settings.qemu.symbolize != cli::SymbolizeMode::Off {
+        let symbolize = steps::symbolize::SymbolizeArgs {
+            mode: cli::SymbolizeMode::Report,
+            ..symbolize_args(settings, artifacts)
+        };
+        steps::symbolize::report_crashes(&symbolize, &serial_log_path)?;
+    }
 
-    let img_args = steps::image::BuildImgArgs {
-        img_path: &img_path,
-        bootx64_efi_source_path: &bootx64_efi_source,
-        init_elf_source_path: &init_elf_source,
-        kernel_elf_source_path: &kernel_elf_source,
-        image_size_mib: 64,
-        verbose: kernel_args.verbose,
-        dry_run: kernel_args.dry_run,
+    result
+}
+
+// Returns the process exit code for the test result.
+fn run_test(
+    repo_root: &Utf8Path,
+    settings: &config::Settings,
+    artifacts: &BuildArtifacts,
+    bless: bool,
+) -> Result<i32> {
+    let out_base = out_base(repo_root, settings);
+
+    let serial_log_path = out_base.join("serial.log");
+    let serial_socket_path = out_base.join("serial.sock");
+    let qmp_socket_path = out_base.join("qmp.sock");
+    let capture_path = out_base.join("net.pcap");
+
+    // a console script needs a serial port it can write to
+    let serial = match (&settings.test.script, settings.test.console) {
+        (None, _) => steps::qemu::SerialTarget::Stdio,
+        (Some(_), cli::ConsoleTransport::Socket) => {
+            steps::qemu::SerialTarget::UnixSocket(&serial_socket_path)
+        }
+        (Some(_), cli::ConsoleTransport::Pty) => steps::qemu::SerialTarget::Pty,
     };
 
-    steps::image::build_fat_img(&img_args)?;
+    let symbolize = symbolize_args(settings, artifacts);
 
-    Ok(())
+    let firmware = resolve_firmware(repo_root, settings)?;
+
+    let test_args = steps::guest_test::RunGuestTestArgs {
+        qemu: steps::qemu::RunQemuArgs {
+            arch: settings.arch.clone(),
+            platform: settings.platform.clone(),
+            out_base: &out_base,
+            img_path: &artifacts.image.disk_image.path,
+            firmware: &firmware,
+            vars: vars_store(settings),
+            machine: machine_options(settings),
+            network: network_options(settings, &capture_path),
+            serial,
+            qmp_socket: Some(&qmp_socket_path),
+            gdb_port: None,
+            stop_at_start: false,
+            headless: true,
+            test_exit: true,
+            verbose: settings.verbose,
+            dry_run: settings.dry_run,
+        },
+        serial_log_path: &serial_log_path,
+        timeout: std::time::Duration::from_secs(settings.test.timeout_secs),
+        console_script: settings.test.script.as_deref(),
+        symbolize_elfs: symbolize.inline_elfs(),
+    };
+
+    let Some(outcome) = steps::guest_test::run_guest_test(&test_args)? else {
+        if let Some(spec_path) = &settings.test.expect {
+            eprintln!("[dry-run] check serial against: {}", spec_path);
+        }
+        return Ok(0);
+    };
+
+    steps::symbolize::report_crashes(&symbolize, &serial_log_path)?;
+
+    eprintln!("[test] {} (serial: {})", outcome, serial_log_path);
+
+    let mut exit_code = outcome.exit_code();
+
+    if let Some(spec_path) = &settings.test.expect {
+        let check = steps::serial_spec::check_serial(&steps::serial_spec::CheckSerialArgs {
+            spec_path,
+            serial_log_path: &serial_log_path,
+            bless,
+        })?;
+
+        eprintln!(
+            "[test] serial expectations {} ({}):",
+            if check.passed { "met" } else { "NOT met" },
+            spec_path
+        );
+        eprint!("{}", check.report);
+
+        // a guest that passed but printed the wrong thing still fails
+        if !check.passed && exit_code == 0 {
+            exit_code = 1;
+        }
+    }
+
+    Ok(exit_code)
 }
 
-fn run_qemu(repo_root: &camino::Utf8Path, args: &cli::RunArgs) -> Result<()> {
-    let target_arch = match args.common.arch {
-        cli::Arch::X86_64 => "x86_64",
-        cli::Arch::Aarch64 => "aarch64",
-        cli::Arch::Riscv64 => "riscv64",
+// Boot the instances described at `topology_path`, each from
+// out/<key>/topology/<name>; returns the exit code as a test.
+fn run_topology(
+    repo_root: &Utf8Path,
+    settings: &config::Settings,
+    artifacts: &BuildArtifacts,
+    topology_path: &Utf8Path,
+    test: bool,
+    bless: bool,
+) -> Result<i32> {
+    let out_base = out_base(repo_root, settings);
+    let topology_dir = out_base.join("topology");
+    let capture_path = out_base.join("net.pcap");
+
+    let firmware = resolve_firmware(repo_root, settings)?;
+
+    let topology_args = steps::topology::BootTopologyArgs {
+        topology_path,
+        topology_dir: &topology_dir,
+        img_path: &artifacts.image.disk_image.path,
+        kernel_elf_path: &artifacts.kernel.kernel_elf.path,
+        init_elf_path: &artifacts.nun_os.init_elf.path,
+        qemu: steps::qemu::RunQemuArgs {
+            arch: settings.arch.clone(),
+            platform: settings.platform.clone(),
+            out_base: &out_base,
+            img_path: &artifacts.image.disk_image.path,
+            firmware: &firmware,
+            vars: vars_store(settings),
+            machine: machine_options(settings),
+            network: network_options(settings, &capture_path),
+            serial: steps::qemu::SerialTarget::Stdio,
+            qmp_socket: None,
+            gdb_port: None,
+            stop_at_start: false,
+            headless: test,
+            test_exit: test,
+            verbose: settings.verbose,
+            dry_run: settings.dry_run,
+        },
+        symbolize: settings.qemu.symbolize,
+        test,
+        timeout: Duration::from_secs(settings.test.timeout_secs),
+        bless,
     };
 
-    let platform_name = match args.common.platform {
-        cli::Platform::Qemu => "qemu",
+    steps::topology::boot_topology(&topology_args, |img_path, init_elf_path| {
+        let image = build_image(
+            settings,
+            img_path,
+            &artifacts.a9nloader.loader_efi.path,
+            init_elf_path,
+            &artifacts.kernel.kernel_elf.path,
+        )?;
+        Ok(image.disk_image.path)
+    })
+}
+
+// Boot `elf_path` as init on the cached kernel and loader; returns the
+// guest's test exit code.
+fn run_runner(
+    repo_root: &Utf8Path,
+    settings: &config::Settings,
+    elf_path: &Utf8Path,
+) -> Result<i32> {
+    let kernel_args = kernel_args(settings);
+
+    let a9nloader_args = a9nloader_args(settings);
+
+    let kernel_artifacts = steps::scheduler::Output::new();
+    let a9nloader_artifacts = steps::scheduler::Output::new();
+
+    let pipeline = vec![
+        steps::scheduler::Step::new("kernel", &[], || {
+            kernel_artifacts.set(steps::kernel::build_kernel(repo_root, &kernel_args)?);
+            Ok(())
+        }),
+        steps::scheduler::Step::new("a9nloader", &[], || {
+            a9nloader_artifacts.set(steps::a9nloader::build_a9nloader(
+                repo_root,
+                &a9nloader_args,
+            )?);
+            Ok(())
+        }),
+    ];
+
+    let jobs = if settings.dry_run { 1 } else { settings.jobs };
+
+    steps::scheduler::run_steps(pipeline, jobs, settings.verbose)?;
+
+    let kernel = kernel_artifacts.into_inner()?;
+    let a9nloader = a9nloader_artifacts.into_inner()?;
+
+    // kept apart from the pipeline's image so neither invalidates the other
+    let runner_dir = out_base(repo_root, settings).join("runner");
+    if !settings.dry_run {
+        std::fs::create_dir_all(&runner_dir)
+            .with_context(|| format!("create runner dir: {}", runner_dir))?;
+    }
+
+    let image = build_image(
+        settings,
+        &runner_dir.join("spencer.img"),
+        &a9nloader.loader_efi.path,
+        elf_path,
+        &kernel.kernel_elf.path,
+    )?;
+
+    let serial_log_path = runner_dir.join("serial.log");
+    let qmp_socket_path = runner_dir.join("qmp.sock");
+    let capture_path = runner_dir.join("net.pcap");
+
+    let symbolize = steps::symbolize::SymbolizeArgs {
+        mode: settings.qemu.symbolize,
+        elf_paths: vec![kernel.kernel_elf.path.clone(), elf_path.to_path_buf()],
     };
 
-    let out_base = repo_root.join("out").join(format!(
-        "{}-{}-{}",
-        target_arch,
-        platform_name,
-        if args.common.release {
-            "release"
-        } else {
-            "debug"
+    let firmware = resolve_firmware(repo_root, settings)?;
+
+    let test_args = steps::guest_test::RunGuestTestArgs {
+        qemu: steps::qemu::RunQemuArgs {
+            arch: settings.arch.clone(),
+            platform: settings.platform.clone(),
+            out_base: &runner_dir,
+            img_path: &image.disk_image.path,
+            firmware: &firmware,
+            vars: vars_store(settings),
+            machine: machine_options(settings),
+            network: network_options(settings, &capture_path),
+            serial: steps::qemu::SerialTarget::Stdio,
+            qmp_socket: Some(&qmp_socket_path),
+            gdb_port: None,
+            stop_at_start: false,
+            headless: true,
+            test_exit: true,
+            verbose: settings.verbose,
+            dry_run: settings.dry_run,
         },
-    ));
+        serial_log_path: &serial_log_path,
+        timeout: std::time::Duration::from_secs(settings.test.timeout_secs),
+        console_script: None,
+        symbolize_elfs: symbolize.inline_elfs(),
+    };
 
-    let img_path = out_base.join("spencer.img");
+    let Some(outcome) = steps::guest_test::run_guest_test(&test_args)? else {
+        return Ok(0);
+    };
 
-    // OVMF paths (A9NLoader tools)
-    let ovmf_code_path = repo_root
-        .join("a9nloader-rs")
-        .join("tools")
-        .join("OVMF_CODE.fd");
+    steps::symbolize::report_crashes(&symbolize, &serial_log_path)?;
 
-    let ovmf_vars_path = repo_root
-        .join("a9nloader-rs")
-        .join("tools")
-        .join("OVMF_VARS.fd");
+    eprintln!("[runner] {} (serial: {})", outcome, serial_log_path);
 
-    let qemu_args = steps::qemu::RunQemuArgs {
-        arch: args.common.arch.clone(),
-        platform: args.common.platform.clone(),
-        out_base: &out_base,
-        img_path: &img_path,
-        ovmf_code_path: &ovmf_code_path,
-        ovmf_vars_path: &ovmf_vars_path,
-        enable_gdb: args.gdb,
-        stop_at_start: args.stop,
-        verbose: args.common.verbose,
-        dry_run: args.common.dry_run,
+    Ok(outcome.exit_code())
+}
+
+fn build_matrix_combination(
+    repo_root: &Utf8Path,
+    config: &config::ConfigFile,
+    args: &cli::MatrixArgs,
+    common: &cli::CommonArgs,
+) -> Result<steps::matrix::MatrixResult> {
+    let mut settings = config.resolve(common)?;
+    if let Some(timeout) = args.timeout {
+        settings.test.timeout_secs = timeout;
+    }
+
+    let artifacts = run_build_pipeline(repo_root, &settings)?;
+    if !args.test {
+        return Ok(steps::matrix::MatrixResult::Passed);
+    }
+
+    Ok(match run_test(repo_root, &settings, &artifacts, false)? {
+        0 => steps::matrix::MatrixResult::Passed,
+        exit_code => steps::matrix::MatrixResult::Failed(format!("test exited with {}", exit_code)),
+    })
+}
+
+fn run_doctor(
+    repo_root: &Utf8Path,
+    config: &config::ConfigFile,
+    args: &cli::DoctorArgs,
+) -> Result<()> {
+    let arches = match args.common.arch.clone().or(config.build.arch.clone()) {
+        Some(arch) => vec![arch],
+        None => cli::Arch::value_variants().to_vec(),
     };
 
-    steps::qemu::run_qemu_x86_64(&qemu_args)?;
+    let mut problems = steps::doctor::print_checks("host", &steps::doctor::host_checks(repo_root));
+
+    for arch in arches {
+        let mut common = args.common.clone();
+        common.arch = Some(arch.clone());
+        common.platform.get_or_insert(cli::Platform::Qemu);
+        let settings = config.resolve(&common)?;
+
+        let checks = steps::doctor::arch_checks(
+            repo_root,
+            &steps::doctor::ArchCheckArgs {
+                arch: arch.clone(),
+                firmware: steps::qemu::FirmwareRequest {
+                    dry_run: false,
+                    ..firmware_request(&settings)
+                },
+                debugger: &settings.debugger.command,
+            },
+        );
+        problems +=
+            steps::doctor::print_checks(steps::target::target_desc(&arch).arch_name, &checks);
+    }
+
+    if problems > 0 {
+        anyhow::bail!("{} problem(s) found", problems);
+    }
+    println!("everything needed is installed");
+
+    Ok(())
+}
+
+fn run_clean(
+    repo_root: &Utf8Path,
+    config: &config::ConfigFile,
+    args: &cli::CleanArgs,
+) -> Result<()> {
+    let narrowed = args.common.arch.is_some()
+        || args.common.platform.is_some()
+        || args.common.release
+        || args.common.debug;
+
+    let mut paths = Vec::new();
+    if !narrowed && args.component.is_empty() {
+        paths.push(repo_root.join("out"));
+        paths.push(repo_root.join("A9N").join("build"));
+        paths.push(repo_root.join("a9nloader-rs").join("target"));
+    } else {
+        let components = if args.component.is_empty() {
+            cli::CleanComponent::value_variants().to_vec()
+        } else {
+            args.component.clone()
+        };
+
+        for combination in steps::matrix::select_combinations(&args.common, &[]) {
+            let mut common = args.common.clone();
+            common.arch = Some(combination.arch.clone());
+            common.platform = Some(combination.platform.clone());
+            common.release = combination.release;
+            common.debug = !combination.release;
+            let settings = config.resolve(&common)?;
+            let out_base = out_base(repo_root, &settings);
+
+            for component in &components {
+                paths.extend(match component {
+                    cli::CleanComponent::Kernel => {
+                        steps::kernel::kernel_outputs(repo_root, &kernel_args(&settings))
+                    }
+                    cli::CleanComponent::Loader => {
+                        steps::a9nloader::a9nloader_outputs(repo_root, &a9nloader_args(&settings))
+                    }
+                    cli::CleanComponent::Nun => {
+                        steps::nun::nun_os_outputs(repo_root, &nun_os_args(&settings))
+                    }
+                    cli::CleanComponent::Image => {
+                        let mut outputs =
+                            steps::image::image_outputs(&out_base.join("spencer.img"));
+                        outputs.push(out_base.join("runner"));
+                        outputs.push(out_base.join("topology"));
+                        outputs
+                    }
+                });
+            }
+            // it would list artifacts that are gone
+            paths.push(out_base.join("manifest.json"));
+            if args.component.is_empty() {
+                paths.push(out_base);
+            }
+        }
+    }
+
+    steps::clean::clean(&steps::clean::CleanArgs {
+        paths: &paths,
+        dry_run: args.common.dry_run,
+    })
+}
+
+fn run_qmp(current_dir: &Utf8Path, socket_path: &Utf8Path, action: &cli::QmpAction) -> Result<()> {
+    let mut qmp = steps::qmp::Qmp::connect(socket_path, Duration::ZERO)
+        .context("is QEMU running? it is started by run, test, gdb and runner")?;
+
+    match action {
+        cli::QmpAction::Status => {
+            let status = qmp.status()?;
+            println!("{}", status.status);
+        }
+        cli::QmpAction::Pause => qmp.pause()?,
+        cli::QmpAction::Resume => qmp.resume()?,
+        cli::QmpAction::Screendump { path } => {
+            // QEMU writes it, relative to its own directory
+            let path = current_dir.join(path);
+            qmp.screendump(&path)?;
+            println!("{}", path);
+        }
+        cli::QmpAction::Savevm { name } => qmp.save_snapshot(name)?,
+        cli::QmpAction::Loadvm { name } => qmp.load_snapshot(name)?,
+        cli::QmpAction::Registers { cpu } => print!("{}", qmp.registers(*cpu)?),
+        cli::QmpAction::Nmi => qmp.inject_nmi()?,
+        cli::QmpAction::Powerdown => qmp.powerdown()?,
+        cli::QmpAction::Quit => qmp.quit()?,
+        cli::QmpAction::Execute { command, arguments } => {
+            let arguments = arguments
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .context("arguments must be a JSON object")?;
+            let reply = qmp.execute(command, arguments)?;
+            println!("{}", serde_json::to_string_pretty(&reply)?);
+        }
+    }
 
     Ok(())
 }
+
+// the firmware's variable store template, which every other store starts from
+fn vars_template_path(repo_root: &Utf8Path, settings: &config::Settings) -> Result<Utf8PathBuf> {
+    match steps::qemu::resolve_firmware(repo_root, &firmware_request(settings))?.firmware {
+        steps::qemu::Firmware::Pflash { vars_path, .. } => Ok(vars_path),
+        steps::qemu::Firmware::UBoot { .. } => anyhow::bail!(
+            "{} boots U-Boot, which has no UEFI variable store file",
+            config::target_key(&settings.arch, &settings.platform)
+        ),
+    }
+}
+
+fn firmware_request(settings: &config::Settings) -> steps::qemu::FirmwareRequest<'_> {
+    steps::qemu::FirmwareRequest {
+        arch: &settings.arch,
+        machine: settings.qemu.machine.as_deref(),
+        secure_boot: settings.qemu.secure_boot,
+        code_path: settings.qemu.firmware_code.as_deref(),
+        vars_path: settings.qemu.firmware_vars.as_deref(),
+        descriptor: settings.qemu.firmware_descriptor.as_deref(),
+        dry_run: settings.dry_run,
+    }
+}
+
+fn resolve_firmware(
+    repo_root: &Utf8Path,
+    settings: &config::Settings,
+) -> Result<steps::qemu::Firmware> {
+    let resolved = steps::qemu::resolve_firmware(repo_root, &firmware_request(settings))?;
+    eprintln!("[firmware] {}", resolved);
+    Ok(resolved.firmware)
+}
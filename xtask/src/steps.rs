@@ -1,7 +1,26 @@
 // common
+pub mod artifact;
+pub mod clean;
+pub mod console;
+pub mod doctor;
+pub mod fingerprint;
+pub mod firmware_descriptor;
+pub mod gdb;
+pub mod guest_test;
 pub mod image;
+pub mod matrix;
 pub mod process;
 pub mod qemu;
+pub mod qmp;
+pub mod scheduler;
+#[cfg(test)]
+pub mod scratch;
+pub mod serial_spec;
+pub mod symbolize;
+pub mod target;
+pub mod topology;
+pub mod uefi_vars;
+pub mod vars;
 
 // steps
 pub mod a9nloader;
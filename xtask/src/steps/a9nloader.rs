@@ -1,5 +1,9 @@
 use crate::cli::{Arch, Platform};
+use crate::steps::artifact::{Artifact, ArtifactKind, Provenance};
+use crate::steps::doctor::{check_rust_target, check_submodule, require};
+use crate::steps::fingerprint::{Fingerprint, Stamp};
 use crate::steps::process::run_command;
+use crate::steps::target::{Component, out_base, target_desc, validate_supported};
 use anyhow::{Context, Result, bail};
 use camino::{Utf8Path, Utf8PathBuf};
 use std::process::Command;
@@ -11,43 +15,37 @@ pub struct BuildA9nloaderArgs {
     pub release: bool,
     pub verbose: bool,
     pub dry_run: bool,
+    // rebuild even when the inputs are unchanged
+    pub force: bool,
 }
 
 pub fn build_a9nloader(
     repo_root: &Utf8Path,
     args: &BuildA9nloaderArgs,
 ) -> Result<A9nloaderArtifacts> {
-    validate_supported(&args.arch, &args.platform)?;
+    validate_supported(Component::Loader, &args.arch, &args.platform)?;
 
     let a9nloader_dir = repo_root.join("a9nloader-rs");
 
     let profile_dir_name = if args.release { "release" } else { "debug" };
 
-    let cargo_target = match to_cargo_target_triple(&args.arch) {
-        Some(value) => value,
-        None => {
-            bail!(
-                "unsupported arch for a9nloader cargo target: {:?}",
-                args.arch
-            );
-        }
-    };
-
-    let out_dir = repo_root
-        .join("out")
-        .join(format!(
-            "{}-{}-{}",
-            to_arch_name(&args.arch),
-            to_platform_name(&args.platform),
-            if args.release { "release" } else { "debug" }
-        ))
-        .join("a9nloader");
+    let target = target_desc(&args.arch);
+
+    let cargo_target = target
+        .loader_cargo_target
+        .context("the support matrix allows a loader without a cargo target")?;
+
+    let out_base = out_base(repo_root, &args.arch, &args.platform, args.release);
+
+    let out_dir = out_base.join("a9nloader");
 
     let produced_dir = a9nloader_dir
         .join("target")
         .join(cargo_target)
         .join(profile_dir_name);
 
+    let loader_path = target.loader_path(&out_base);
+
     if args.dry_run {
         eprintln!("[dry-run] cargo build (A9NLoader)");
         eprintln!("[dry-run]   dir: {}", a9nloader_dir);
@@ -57,14 +55,25 @@ pub fn build_a9nloader(
         }
         eprintln!("[dry-run] produced_dir: {}", produced_dir);
         eprintln!("[dry-run] out_dir: {}", out_dir);
-        return Ok(A9nloaderArtifacts {
-            out_dir,
-            produced_dir,
-        });
+        return Ok(a9nloader_artifacts(loader_path, Provenance::default()));
+    }
+
+    require(check_submodule(repo_root, "a9nloader-rs"))?;
+
+    let provenance = a9nloader_provenance(&a9nloader_dir);
+    let stamp = Stamp::new(&out_base, "a9nloader");
+    let fingerprint = a9nloader_fingerprint(&a9nloader_dir, args, &provenance)?;
+
+    if !args.force && stamp.is_up_to_date(&fingerprint, &[&loader_path]) {
+        eprintln!("[a9nloader] up to date: {}", loader_path);
+        return Ok(a9nloader_artifacts(loader_path, provenance));
     }
+    stamp.invalidate();
 
     std::fs::create_dir_all(&out_dir).with_context(|| format!("create out dir: {}", out_dir))?;
 
+    require(check_rust_target(repo_root, cargo_target))?;
+
     let mut build_command = Command::new("cargo");
     build_command.current_dir(&a9nloader_dir);
     build_command.arg("build");
@@ -80,42 +89,65 @@ pub fn build_a9nloader(
     copy_dir_contents(&produced_dir, &out_dir)
         .with_context(|| format!("copy a9nloader artifacts: {} -> {}", produced_dir, out_dir))?;
 
-    Ok(A9nloaderArtifacts {
-        out_dir,
-        produced_dir,
-    })
+    stamp.record(&fingerprint, &[&loader_path])?;
+
+    Ok(a9nloader_artifacts(loader_path, provenance))
 }
 
 #[derive(Clone, Debug)]
 pub struct A9nloaderArtifacts {
-    pub out_dir: Utf8PathBuf,
-    pub produced_dir: Utf8PathBuf,
+    pub loader_efi: Artifact,
 }
 
-fn validate_supported(_arch: &Arch, _platform: &Platform) -> Result<()> {
-    Ok(())
+// everything the loader step writes, for `clean`
+pub fn a9nloader_outputs(repo_root: &Utf8Path, args: &BuildA9nloaderArgs) -> Vec<Utf8PathBuf> {
+    let out_base = out_base(repo_root, &args.arch, &args.platform, args.release);
+    let mut outputs = vec![
+        out_base.join("a9nloader"),
+        Stamp::new(&out_base, "a9nloader").path().to_path_buf(),
+    ];
+    if let Some(cargo_target) = target_desc(&args.arch).loader_cargo_target {
+        outputs.push(
+            repo_root
+                .join("a9nloader-rs")
+                .join("target")
+                .join(cargo_target)
+                .join(if args.release { "release" } else { "debug" }),
+        );
+    }
+    outputs
 }
 
-fn to_arch_name(arch: &Arch) -> &'static str {
-    match arch {
-        Arch::X86_64 => "x86_64",
-        Arch::Aarch64 => "aarch64",
-        Arch::Riscv64 => "riscv64",
+fn a9nloader_artifacts(loader_path: Utf8PathBuf, provenance: Provenance) -> A9nloaderArtifacts {
+    A9nloaderArtifacts {
+        loader_efi: Artifact::new(ArtifactKind::Loader, loader_path, "a9nloader", provenance),
     }
 }
 
-fn to_platform_name(platform: &Platform) -> &'static str {
-    match platform {
-        Platform::Qemu => "qemu",
-    }
+fn a9nloader_provenance(a9nloader_dir: &Utf8Path) -> Provenance {
+    let mut provenance = Provenance::default();
+
+    // picks up a rust-toolchain file in the loader repo
+    let mut rustc_version = Command::new("rustc");
+    rustc_version.arg("-vV");
+    provenance.probe_tool("rustc", rustc_version, a9nloader_dir);
+    provenance.probe_source_commit(a9nloader_dir);
+
+    provenance
 }
 
-fn to_cargo_target_triple(arch: &Arch) -> Option<&'static str> {
-    match arch {
-        Arch::X86_64 => Some("x86_64-unknown-uefi"),
-        Arch::Aarch64 => Some("aarch64-unknown-uefi"),
-        Arch::Riscv64 => None,
-    }
+fn a9nloader_fingerprint(
+    a9nloader_dir: &Utf8Path,
+    args: &BuildA9nloaderArgs,
+    provenance: &Provenance,
+) -> Result<String> {
+    let mut fingerprint = Fingerprint::new("a9nloader");
+    fingerprint.add_str("args", &format!("{:?}/{:?}", args.arch, args.platform));
+    fingerprint.add_str("release", &args.release.to_string());
+    fingerprint.add_provenance(provenance);
+    fingerprint.add_tree(a9nloader_dir, &[".git", "target"])?;
+
+    Ok(fingerprint.finish())
 }
 
 fn copy_dir_contents(source_dir: &Utf8Path, destination_dir: &Utf8Path) -> Result<()> {
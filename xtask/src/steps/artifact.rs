@@ -0,0 +1,165 @@
+use crate::steps::fingerprint::{hash_file, to_hex};
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactKind {
+    Kernel,
+    Loader,
+    Init,
+    DiskImage,
+}
+
+// A file produced by a pipeline step.
+#[derive(Clone, Debug)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub path: Utf8PathBuf,
+    pub step: &'static str,
+    pub provenance: Provenance,
+}
+
+impl Artifact {
+    pub fn new(
+        kind: ArtifactKind,
+        path: impl Into<Utf8PathBuf>,
+        step: &'static str,
+        provenance: Provenance,
+    ) -> Self {
+        Self {
+            kind,
+            path: path.into(),
+            step,
+            provenance,
+        }
+    }
+}
+
+// What an artifact was built with; also part of the step's fingerprint.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Provenance {
+    // tool name -> first line of its version output
+    pub toolchain: BTreeMap<String, String>,
+    // HEAD of the submodule the artifact is built from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_commit: Option<String>,
+}
+
+impl Provenance {
+    // `cmake --version`, `rustc -vV`, ...; run in `current_dir` so that
+    // rust-toolchain files are honoured
+    pub fn probe_tool(&mut self, name: &str, mut command: Command, current_dir: &Utf8Path) {
+        let version = command_stdout(command.current_dir(current_dir))
+            .and_then(|stdout| stdout.lines().next().map(str::to_string))
+            .unwrap_or_else(|| "unavailable".to_string());
+        self.toolchain.insert(name.to_string(), version);
+    }
+
+    // A compiler named by a build file: the binary it resolves to on PATH and
+    // its version, so that switching either one is noticed.
+    pub fn probe_program(&mut self, program: &str, current_dir: &Utf8Path) {
+        let Some(path) = find_program(program) else {
+            self.toolchain
+                .insert(program.to_string(), "not found".to_string());
+            return;
+        };
+
+        let mut command = Command::new(&path);
+        command.arg("--version");
+        let version = command_stdout(command.current_dir(current_dir))
+            .and_then(|stdout| stdout.lines().next().map(str::to_string))
+            .unwrap_or_else(|| "unavailable".to_string());
+        self.toolchain
+            .insert(program.to_string(), format!("{} ({})", version, path));
+    }
+
+    pub fn probe_source_commit(&mut self, repo_dir: &Utf8Path) {
+        let mut command = Command::new("git");
+        command.current_dir(repo_dir).arg("rev-parse").arg("HEAD");
+        self.source_commit = command_stdout(&mut command).map(|stdout| stdout.trim().to_string());
+    }
+}
+
+// `program` as the shell would find it, with symlinks (e.g. alternatives) resolved.
+fn find_program(program: &str) -> Option<Utf8PathBuf> {
+    let candidate = if program.contains('/') {
+        Utf8PathBuf::from(program)
+    } else {
+        let path = std::env::var_os("PATH")?;
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+            .and_then(|candidate| Utf8PathBuf::from_path_buf(candidate).ok())?
+    };
+    candidate.canonicalize_utf8().ok()
+}
+
+fn command_stdout(command: &mut Command) -> Option<String> {
+    command
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// out/<arch>-<platform>-<profile>/manifest.json
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    target: &'a str,
+    profile: &'a str,
+    artifacts: Vec<ManifestEntry<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry<'a> {
+    kind: ArtifactKind,
+    // relative to the manifest's directory when inside it
+    path: &'a Utf8Path,
+    size: u64,
+    sha256: String,
+    step: &'a str,
+    #[serde(flatten)]
+    provenance: &'a Provenance,
+}
+
+pub fn write_manifest(
+    manifest_path: &Utf8Path,
+    target: &str,
+    profile: &str,
+    artifacts: &[&Artifact],
+) -> Result<()> {
+    let manifest_dir = manifest_path.parent().context("manifest has no parent")?;
+
+    let mut entries = Vec::new();
+    for artifact in artifacts {
+        let size = std::fs::metadata(&artifact.path)
+            .with_context(|| format!("stat artifact: {}", artifact.path))?
+            .len();
+
+        entries.push(ManifestEntry {
+            kind: artifact.kind,
+            path: artifact
+                .path
+                .strip_prefix(manifest_dir)
+                .unwrap_or(&artifact.path),
+            size,
+            sha256: to_hex(&hash_file(&artifact.path)?),
+            step: artifact.step,
+            provenance: &artifact.provenance,
+        });
+    }
+
+    let manifest = Manifest {
+        target,
+        profile,
+        artifacts: entries,
+    };
+
+    let json = serde_json::to_string_pretty(&manifest).context("serialize manifest")?;
+    std::fs::write(manifest_path, json + "\n")
+        .with_context(|| format!("write manifest: {}", manifest_path))
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+pub struct CleanArgs<'a> {
+    // files and directories to delete; missing ones are ignored
+    pub paths: &'a [Utf8PathBuf],
+    pub dry_run: bool,
+}
+
+pub fn clean(args: &CleanArgs) -> Result<()> {
+    let targets = existing_roots(args.paths);
+    if targets.is_empty() {
+        eprintln!("[clean] nothing to delete");
+        return Ok(());
+    }
+
+    let mut total_bytes = 0;
+    for path in &targets {
+        let bytes = disk_usage(path).with_context(|| format!("measure {}", path))?;
+        total_bytes += bytes;
+
+        if args.dry_run {
+            eprintln!("[dry-run] delete {} ({})", path, format_size(bytes));
+            continue;
+        }
+
+        eprintln!("[clean] delete {} ({})", path, format_size(bytes));
+        remove(path)?;
+    }
+
+    eprintln!(
+        "[clean] {} {} in {} path(s)",
+        if args.dry_run { "would free" } else { "freed" },
+        format_size(total_bytes),
+        targets.len()
+    );
+
+    Ok(())
+}
+
+// Drop paths that do not exist or lie inside another path being deleted,
+// keeping the order of the first mention.
+fn existing_roots(paths: &[Utf8PathBuf]) -> Vec<Utf8PathBuf> {
+    let existing: Vec<&Utf8PathBuf> = paths
+        .iter()
+        .filter(|path| path.symlink_metadata().is_ok())
+        .collect();
+
+    let mut roots: Vec<Utf8PathBuf> = Vec::new();
+    for path in existing.iter().copied() {
+        let nested = existing
+            .iter()
+            .any(|other| *other != path && path.starts_with(other));
+        if !nested && !roots.contains(path) {
+            roots.push(path.clone());
+        }
+    }
+    roots
+}
+
+// symlinks count as themselves and are not followed
+fn disk_usage(path: &Utf8Path) -> Result<u64> {
+    let metadata = path.symlink_metadata()?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut bytes = 0;
+    for entry in path.read_dir_utf8()? {
+        bytes += disk_usage(entry?.path())?;
+    }
+    Ok(bytes)
+}
+
+fn remove(path: &Utf8Path) -> Result<()> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("delete {}", path))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
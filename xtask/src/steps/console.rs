@@ -0,0 +1,297 @@
+mod script;
+
+pub use script::load_script;
+
+use crate::steps::process::interrupted;
+use crate::steps::symbolize::CrashScanner;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::bytes::Regex;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+// lines of output shown when an expectation fails
+const TAIL_LINES: usize = 10;
+
+// longest wait between checks for Ctrl-C
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct ConsoleArgs<'a> {
+    // everything received is appended here
+    pub transcript_path: &'a Utf8Path,
+    // mirror received output to stdout
+    pub echo: bool,
+    // default for `expect`; scripts may override it per step
+    pub timeout: Duration,
+    // annotate crash addresses in the echoed output
+    pub symbolize_elfs: &'a [Utf8PathBuf],
+}
+
+// Interactive access to the guest's serial port, in the style of pexpect:
+// wait for output matching a regex, then send input.
+pub struct Console {
+    writer: Box<dyn Write + Send>,
+    chunks: Receiver<Vec<u8>>,
+    // received but not yet consumed by a match
+    buffer: Vec<u8>,
+    closed: bool,
+    timeout: Duration,
+    // no expect waits past this, whatever its own timeout
+    deadline: Option<Instant>,
+    line_ending: String,
+}
+
+pub struct Match {
+    // named groups of the pattern
+    pub captures: BTreeMap<String, String>,
+}
+
+// Why an `expect` gave up; wrapped in the anyhow error so callers can tell a
+// guest that went away from one that printed the wrong thing.
+#[derive(Debug)]
+pub enum ExpectError {
+    TimedOut { pattern: String, tail: String },
+    Closed { pattern: String, tail: String },
+    Interrupted { pattern: String, tail: String },
+}
+
+impl std::fmt::Display for ExpectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (reason, pattern, tail) = match self {
+            ExpectError::TimedOut { pattern, tail } => ("timed out waiting for", pattern, tail),
+            ExpectError::Closed { pattern, tail } => {
+                ("serial console closed while waiting for", pattern, tail)
+            }
+            ExpectError::Interrupted { pattern, tail } => {
+                ("interrupted while waiting for", pattern, tail)
+            }
+        };
+        write!(f, "{} /{}/", reason, pattern)?;
+        if tail.is_empty() {
+            write!(f, " (no unmatched output)")
+        } else {
+            write!(f, "; unmatched output ends with:")?;
+            for line in tail.lines() {
+                write!(f, "\n  | {}", line)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl std::error::Error for ExpectError {}
+
+impl Console {
+    // Connect to a `-chardev socket,server=on` serial port, retrying until
+    // QEMU has created the socket.
+    pub fn connect_unix(
+        socket_path: &Utf8Path,
+        connect_timeout: Duration,
+        args: &ConsoleArgs,
+    ) -> Result<Self> {
+        let deadline = Instant::now() + connect_timeout;
+        let stream = loop {
+            match UnixStream::connect(socket_path) {
+                Ok(stream) => break stream,
+                Err(error) if Instant::now() >= deadline => {
+                    return Err(error)
+                        .with_context(|| format!("connect to serial socket: {}", socket_path));
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(50)),
+            }
+        };
+
+        let reader = stream
+            .try_clone()
+            .context("clone serial socket for reading")?;
+        Self::new(Box::new(reader), Box::new(stream), args)
+    }
+
+    // Open the slave side of a `-chardev pty` serial port.
+    pub fn open_pty(pty_path: &Utf8Path, args: &ConsoleArgs) -> Result<Self> {
+        // no line editing or echo: the guest sees exactly what is sent
+        let status = Command::new("stty")
+            .arg("-F")
+            .arg(pty_path)
+            .arg("raw")
+            .arg("-echo")
+            .status()
+            .context("run stty")?;
+        if !status.success() {
+            anyhow::bail!("stty failed on {}: {}", pty_path, status);
+        }
+
+        let pty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(pty_path)
+            .with_context(|| format!("open pty: {}", pty_path))?;
+        let reader = pty.try_clone().context("clone pty for reading")?;
+        Self::new(Box::new(reader), Box::new(pty), args)
+    }
+
+    fn new(
+        mut reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+        args: &ConsoleArgs,
+    ) -> Result<Self> {
+        let mut transcript = std::fs::File::create(args.transcript_path)
+            .with_context(|| format!("create serial log: {}", args.transcript_path))?;
+        let echo = args.echo;
+        let symbolize_elfs = args.symbolize_elfs.to_vec();
+
+        let (sender, chunks) = std::sync::mpsc::channel();
+
+        // ends when the port closes (EOF, or EIO on a pty whose QEMU side is
+        // gone) or the console is dropped
+        std::thread::spawn(move || {
+            let mut scanner = if echo {
+                CrashScanner::load(&symbolize_elfs)
+            } else {
+                None
+            };
+            let mut buffer = [0u8; 4096];
+            loop {
+                let read_size = match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => return,
+                    Ok(read_size) => read_size,
+                };
+                let chunk = &buffer[..read_size];
+                let _ = transcript.write_all(chunk);
+                if echo {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(chunk);
+                    if let Some(scanner) = &mut scanner {
+                        let _ = stdout.write_all(scanner.feed(chunk).as_bytes());
+                    }
+                    let _ = stdout.flush();
+                }
+                if sender.send(chunk.to_vec()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            writer,
+            chunks,
+            buffer: Vec::new(),
+            closed: false,
+            timeout: args.timeout,
+            deadline: None,
+            line_ending: "\r".to_string(),
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    // appended by `send_line`; "\r" is what a terminal's Enter key sends
+    pub fn set_line_ending(&mut self, line_ending: &str) {
+        self.line_ending = line_ending.to_string();
+    }
+
+    pub fn expect(&mut self, pattern: &str) -> Result<Match> {
+        self.expect_timeout(pattern, self.timeout)
+    }
+
+    pub fn expect_timeout(&mut self, pattern: &str, timeout: Duration) -> Result<Match> {
+        let regex =
+            Regex::new(pattern).with_context(|| format!("invalid pattern /{}/", pattern))?;
+
+        let mut deadline = Instant::now() + timeout;
+        if let Some(overall) = self.deadline {
+            deadline = deadline.min(overall);
+        }
+
+        loop {
+            if let Some(found) = self.take_match(&regex) {
+                return Ok(found);
+            }
+
+            if self.closed {
+                return Err(ExpectError::Closed {
+                    pattern: pattern.to_string(),
+                    tail: self.tail(),
+                }
+                .into());
+            }
+
+            if interrupted() {
+                return Err(ExpectError::Interrupted {
+                    pattern: pattern.to_string(),
+                    tail: self.tail(),
+                }
+                .into());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ExpectError::TimedOut {
+                    pattern: pattern.to_string(),
+                    tail: self.tail(),
+                }
+                .into());
+            }
+
+            match self
+                .chunks
+                .recv_timeout((deadline - now).min(POLL_INTERVAL))
+            {
+                Ok(chunk) => self.buffer.extend_from_slice(&chunk),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => self.closed = true,
+            }
+        }
+    }
+
+    pub fn send(&mut self, text: &str) -> Result<()> {
+        self.writer
+            .write_all(text.as_bytes())
+            .and_then(|()| self.writer.flush())
+            .context("write to serial console")
+    }
+
+    pub fn send_line(&mut self, text: &str) -> Result<()> {
+        let line = format!("{}{}", text, self.line_ending);
+        self.send(&line)
+    }
+
+    fn take_match(&mut self, regex: &Regex) -> Option<Match> {
+        let captures = regex.captures(&self.buffer)?;
+        let whole = captures.get(0)?;
+
+        let found = Match {
+            captures: regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    let value = captures.name(name)?;
+                    Some((
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    ))
+                })
+                .collect(),
+        };
+
+        let end = whole.end();
+        self.buffer.drain(..end);
+        Some(found)
+    }
+
+    fn tail(&self) -> String {
+        let text = String::from_utf8_lossy(&self.buffer);
+        let lines: Vec<&str> = text.lines().collect();
+        lines[lines.len().saturating_sub(TAIL_LINES)..].join("\n")
+    }
+}
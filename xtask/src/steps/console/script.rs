@@ -0,0 +1,329 @@
+use super::Console;
+use anyhow::{Context, Result, bail};
+use camino::Utf8Path;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use std::time::Duration;
+use toml::Spanned;
+
+// timeout_secs = 30                    # default for every expect
+// line_ending = "\r"                   # appended by send_line
+// wait_for_exit = true                 # after the last step, wait for the guest's verdict
+//
+// [[step]]
+// expect = "login: $"
+//
+// [[step]]
+// send_line = "root"
+//
+// [[step]]
+// expect = 'pid (?<pid>\d+)'          # named groups become variables...
+// timeout_secs = 5
+//
+// [[step]]
+// send_line = "kill ${pid}"            # ...usable in later steps
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptFile {
+    timeout_secs: Option<u64>,
+    line_ending: Option<String>,
+    #[serde(default)]
+    wait_for_exit: bool,
+    #[serde(default)]
+    step: Vec<Spanned<StepEntry>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepEntry {
+    expect: Option<String>,
+    send: Option<String>,
+    send_line: Option<String>,
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+enum Action {
+    Expect(String, Option<Duration>),
+    Send(String),
+    SendLine(String),
+}
+
+#[derive(Debug)]
+pub struct Script {
+    timeout: Option<Duration>,
+    line_ending: Option<String>,
+    wait_for_exit: bool,
+    actions: Vec<Action>,
+}
+
+pub fn load_script(script_path: &Utf8Path) -> Result<Script> {
+    let text = std::fs::read_to_string(script_path)
+        .with_context(|| format!("read console script: {}", script_path))?;
+    parse_script(&text, script_path)
+}
+
+fn parse_script(text: &str, script_path: &Utf8Path) -> Result<Script> {
+    let file: ScriptFile =
+        toml::from_str(text).with_context(|| format!("parse console script: {}", script_path))?;
+
+    let mut actions = Vec::new();
+    let mut errors = Vec::new();
+    for (index, step) in file.step.into_iter().enumerate() {
+        let line = text[..step.span().start].matches('\n').count() + 1;
+        let location = format!("step {} (line {})", index + 1, line);
+
+        let step = step.into_inner();
+        let timeout = step.timeout_secs.map(Duration::from_secs);
+        match (step.expect, step.send, step.send_line) {
+            (Some(pattern), None, None) => {
+                // variables are only known at run time; any literal stands in
+                let placeholder = VARIABLE_REFERENCE.replace_all(&pattern, "x");
+                if let Err(error) = Regex::new(&placeholder) {
+                    errors.push(format!("{}: /{}/: {}", location, pattern, error));
+                }
+                actions.push(Action::Expect(pattern, timeout));
+            }
+            (None, Some(text), None) if timeout.is_none() => actions.push(Action::Send(text)),
+            (None, None, Some(text)) if timeout.is_none() => actions.push(Action::SendLine(text)),
+            (None, _, _) if timeout.is_some() => {
+                errors.push(format!("{}: timeout_secs only applies to expect", location))
+            }
+            _ => errors.push(format!(
+                "{}: needs exactly one of expect, send, send_line",
+                location
+            )),
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "invalid console script {}:\n  {}",
+            script_path,
+            errors.join("\n  ")
+        );
+    }
+
+    Ok(Script {
+        timeout: file.timeout_secs.map(Duration::from_secs),
+        line_ending: file.line_ending,
+        wait_for_exit: file.wait_for_exit,
+        actions,
+    })
+}
+
+impl Script {
+    // whether the guest ends the test itself once the steps are done
+    pub fn wait_for_exit(&self) -> bool {
+        self.wait_for_exit
+    }
+
+    pub fn run(&self, console: &mut Console) -> Result<()> {
+        if let Some(timeout) = self.timeout {
+            console.set_timeout(timeout);
+        }
+        if let Some(line_ending) = &self.line_ending {
+            console.set_line_ending(line_ending);
+        }
+
+        let mut variables = BTreeMap::new();
+        for (index, action) in self.actions.iter().enumerate() {
+            run_action(action, console, &mut variables)
+                .with_context(|| format!("console script step {}", index + 1))?;
+        }
+        Ok(())
+    }
+}
+
+fn run_action(
+    action: &Action,
+    console: &mut Console,
+    variables: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    match action {
+        Action::Expect(pattern, timeout) => {
+            let pattern = substitute(pattern, variables, true)?;
+            let found = match timeout {
+                Some(timeout) => console.expect_timeout(&pattern, *timeout)?,
+                None => console.expect(&pattern)?,
+            };
+            variables.extend(found.captures);
+            Ok(())
+        }
+        Action::Send(text) => console.send(&substitute(text, variables, false)?),
+        Action::SendLine(text) => console.send_line(&substitute(text, variables, false)?),
+    }
+}
+
+// Replace `${name}` with a captured value; in patterns the value matches literally.
+fn substitute(
+    text: &str,
+    variables: &BTreeMap<String, String>,
+    is_pattern: bool,
+) -> Result<String> {
+    let mut unknown = None;
+    let replaced = VARIABLE_REFERENCE.replace_all(text, |captures: &regex::Captures| {
+        let name = &captures[1];
+        match variables.get(name) {
+            Some(value) if is_pattern => regex::escape(value),
+            Some(value) => value.clone(),
+            None => {
+                unknown.get_or_insert_with(|| name.to_string());
+                String::new()
+            }
+        }
+    });
+
+    if let Some(name) = unknown {
+        bail!("${{{}}} was not captured by an earlier expect", name);
+    }
+    Ok(replaced.into_owned())
+}
+
+static VARIABLE_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("valid regex"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Script> {
+        parse_script(text, Utf8Path::new("boot.script"))
+    }
+
+    fn parse_error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn parses_settings_and_steps_in_order() {
+        let script = parse(
+            r#"
+            timeout_secs = 30
+            line_ending = "\r"
+            wait_for_exit = true
+
+            [[step]]
+            expect = "login: $"
+
+            [[step]]
+            send_line = "root"
+
+            [[step]]
+            expect = 'pid (?<pid>\d+)'
+            timeout_secs = 5
+
+            [[step]]
+            send = "kill ${pid}"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(script.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(script.line_ending.as_deref(), Some("\r"));
+        assert!(script.wait_for_exit());
+        assert!(matches!(
+            script.actions.as_slice(),
+            [
+                Action::Expect(login, None),
+                Action::SendLine(user),
+                Action::Expect(pid, Some(timeout)),
+                Action::Send(kill),
+            ] if login == "login: $"
+                && user == "root"
+                && pid == r"pid (?<pid>\d+)"
+                && *timeout == Duration::from_secs(5)
+                && kill == "kill ${pid}"
+        ));
+    }
+
+    #[test]
+    fn defaults_leave_the_console_settings_alone() {
+        let script = parse("").unwrap();
+        assert_eq!(script.timeout, None);
+        assert_eq!(script.line_ending, None);
+        assert!(!script.wait_for_exit());
+        assert!(script.actions.is_empty());
+    }
+
+    #[test]
+    fn variables_in_patterns_are_checked_with_a_placeholder() {
+        // "${count}+" is valid once the variable is filled in
+        assert!(parse("[[step]]\nexpect = 'total ${count}+'\n").is_ok());
+    }
+
+    #[test]
+    fn step_errors_name_the_step_and_its_line() {
+        let error = parse_error(
+            r#"timeout_secs = 10
+
+[[step]]
+expect = "ok"
+
+[[step]]
+expect = "a"
+send = "b"
+
+[[step]]
+
+[[step]]
+send_line = "root"
+timeout_secs = 5
+
+[[step]]
+expect = "(unclosed"
+"#,
+        );
+
+        assert!(error.starts_with("invalid console script boot.script:\n"));
+        assert!(error.contains("step 2 (line 6): needs exactly one of expect, send, send_line"));
+        assert!(error.contains("step 3 (line 10): needs exactly one of expect, send, send_line"));
+        assert!(error.contains("step 4 (line 12): timeout_secs only applies to expect"));
+        assert!(error.contains("step 5 (line 16): /(unclosed/: regex parse error"));
+        assert!(!error.contains("step 1 "));
+    }
+
+    #[test]
+    fn toml_errors_carry_the_position() {
+        let error = parse_error("[[step]]\nexpect = \"ok\"\nsend_lines = \"typo\"\n");
+        assert!(error.contains("parse console script: boot.script"));
+        assert!(error.contains("line 3"));
+        assert!(error.contains("unknown field `send_lines`"));
+    }
+
+    fn variables(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn substitute_escapes_values_in_patterns_only() {
+        let variables = variables(&[("addr", "0x1f.0"), ("pid", "42")]);
+
+        assert_eq!(
+            substitute("at ${addr} (${pid})", &variables, false).unwrap(),
+            "at 0x1f.0 (42)"
+        );
+        assert_eq!(
+            substitute("at ${addr}$", &variables, true).unwrap(),
+            r"at 0x1f\.0$"
+        );
+        // not a reference
+        assert_eq!(
+            substitute("$pid ${1x}", &variables, false).unwrap(),
+            "$pid ${1x}"
+        );
+    }
+
+    #[test]
+    fn substitute_rejects_unknown_variables() {
+        let error = substitute("kill ${pid}", &BTreeMap::new(), false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "${pid} was not captured by an earlier expect"
+        );
+    }
+}
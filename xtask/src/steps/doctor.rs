@@ -0,0 +1,458 @@
+use crate::cli::Arch;
+use crate::steps::qemu::{FirmwareRequest, kvm_unusable_reason, qemu_binary, resolve_firmware};
+use crate::steps::target::{Component, target_desc, validate_supported};
+use anyhow::{Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    // optional tool, or something that could not be verified
+    Warning,
+    // a build or run will fail
+    Problem,
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    // version when ok, what is wrong otherwise
+    pub detail: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn problem(name: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Problem,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    // a problem that does not stop the build
+    fn optional(self) -> Self {
+        match self.status {
+            CheckStatus::Problem => Self {
+                status: CheckStatus::Warning,
+                ..self
+            },
+            _ => self,
+        }
+    }
+}
+
+pub struct ArchCheckArgs<'a> {
+    pub arch: Arch,
+    // the firmware run would look for
+    pub firmware: FirmwareRequest<'a>,
+    pub debugger: &'a str,
+}
+
+// Submodules, cmake and the Rust toolchains every target needs.
+pub fn host_checks(repo_root: &Utf8Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    for submodule in ["A9N", "Nun", "a9nloader-rs"] {
+        checks.push(check_submodule(repo_root, submodule));
+    }
+
+    checks.push(check_tool("cmake", &["--version"], None));
+    checks.push(check_tool("cargo", &["--version"], None));
+    checks.push(check_nightly(repo_root));
+    checks.push(check_tool("git", &["--version"], None).optional());
+
+    checks
+}
+
+// Cross toolchain, UEFI target, QEMU, firmware and debugger for one arch.
+pub fn arch_checks(repo_root: &Utf8Path, args: &ArchCheckArgs) -> Vec<Check> {
+    let target = target_desc(&args.arch);
+    let mut checks = check_cross_toolchain(repo_root, &args.arch);
+
+    if validate_supported(Component::Loader, &args.arch, &crate::cli::Platform::Qemu).is_ok()
+        && let Some(cargo_target) = target.loader_cargo_target
+    {
+        checks.push(check_rust_target(repo_root, cargo_target));
+    }
+
+    let qemu = qemu_binary(&args.arch);
+    checks.push(check_tool(qemu, &["--version"], None));
+
+    checks.push(check_firmware(repo_root, args));
+
+    // KVM never applies to a foreign arch, so only same-arch guests get a check
+    if std::env::consts::ARCH == target.arch_name {
+        checks.push(check_kvm(&args.arch).optional());
+    }
+
+    checks.push(check_tool(args.debugger, &["--version"], None).optional());
+
+    checks
+}
+
+// Without KVM `--accel auto` falls back to TCG, which is much slower.
+fn check_kvm(arch: &Arch) -> Check {
+    match kvm_unusable_reason(arch) {
+        None => Check::ok("kvm", "/dev/kvm"),
+        Some(reason) => Check::problem(
+            "kvm",
+            format!("{} (TCG will be used)", reason),
+            "enable virtualization in the firmware settings and add yourself to the kvm group: sudo usermod -aG kvm $USER",
+        ),
+    }
+}
+
+// Print one block of the report; returns the number of problems.
+pub fn print_checks(title: &str, checks: &[Check]) -> usize {
+    println!("{}", title);
+
+    let name_width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    for check in checks {
+        let label = match check.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warn",
+            CheckStatus::Problem => "FAIL",
+        };
+        println!(
+            "  {:<4}  {:<name_width$}  {}",
+            label, check.name, check.detail
+        );
+        if check.status != CheckStatus::Ok
+            && let Some(fix) = &check.fix
+        {
+            println!("        {:<name_width$}  fix: {}", "", fix);
+        }
+    }
+
+    checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Problem)
+        .count()
+}
+
+// For build steps: fail with the doctor's guidance instead of a confusing
+// error further down.
+pub fn require(check: Check) -> Result<()> {
+    if check.status != CheckStatus::Problem {
+        return Ok(());
+    }
+    bail!(
+        "{}: {}\n  fix: {}\n  (`cargo xtask doctor` checks the whole toolchain)",
+        check.name,
+        check.detail,
+        check.fix.unwrap_or_default()
+    );
+}
+
+// Guidance for a program that could not be spawned because it is not installed.
+pub fn missing_program_hint(program: &OsStr) -> String {
+    let program = program.to_string_lossy();
+    format!(
+        "{} is not installed or not on PATH\n  fix: {}\n  (`cargo xtask doctor` checks the whole toolchain)",
+        program,
+        install_hint(&program)
+    )
+}
+
+pub fn check_submodule(repo_root: &Utf8Path, name: &str) -> Check {
+    let path = repo_root.join(name);
+    let fix = format!("git submodule update --init --recursive {}", name);
+
+    let entries = match std::fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(_) => return Check::problem(format!("submodule {}", name), "missing", fix),
+    };
+
+    // an uninitialised submodule is an empty directory (or only a .git file)
+    let initialised = entries
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_name() != ".git");
+    if !initialised {
+        return Check::problem(format!("submodule {}", name), "not initialised", fix);
+    }
+
+    let mut command = Command::new("git");
+    command
+        .current_dir(&path)
+        .arg("rev-parse")
+        .arg("--short")
+        .arg("HEAD");
+    let detail = probe(command)
+        .map(|commit| format!("at {}", commit))
+        .unwrap_or_else(|_| "present".to_string());
+    Check::ok(format!("submodule {}", name), detail)
+}
+
+fn check_tool(program: &str, args: &[&str], current_dir: Option<&Utf8Path>) -> Check {
+    let mut command = Command::new(program);
+    command.args(args);
+    if let Some(current_dir) = current_dir {
+        command.current_dir(current_dir);
+    }
+
+    match probe(command) {
+        Ok(version) => Check::ok(program, version),
+        Err(error) => Check::problem(program, error, install_hint(program)),
+    }
+}
+
+// Nun is built with `cargo +nightly -Z build-std`, which needs rust-src.
+pub fn check_nightly(repo_root: &Utf8Path) -> Check {
+    let core_dir = repo_root.join("core");
+    let current_dir = core_dir.exists().then_some(core_dir.as_path());
+
+    let nightly = check_tool("rustc", &["+nightly", "--version"], current_dir);
+    if nightly.status != CheckStatus::Ok {
+        return Check::problem(
+            "rust nightly",
+            nightly.detail,
+            "rustup toolchain install nightly --component rust-src",
+        );
+    }
+
+    let mut command = Command::new("rustc");
+    command.arg("+nightly").arg("--print").arg("sysroot");
+    if let Some(current_dir) = current_dir {
+        command.current_dir(current_dir);
+    }
+    let has_rust_src = probe(command).is_ok_and(|sysroot| {
+        Utf8PathBuf::from(sysroot)
+            .join("lib/rustlib/src/rust/library")
+            .exists()
+    });
+    if !has_rust_src {
+        return Check::problem(
+            "rust nightly",
+            format!("{}, without rust-src", nightly.detail),
+            "rustup component add rust-src --toolchain nightly",
+        );
+    }
+
+    Check::ok("rust nightly", format!("{} + rust-src", nightly.detail))
+}
+
+pub fn check_rust_target(repo_root: &Utf8Path, cargo_target: &str) -> Check {
+    // the loader repo may pin its own toolchain
+    let loader_dir = repo_root.join("a9nloader-rs");
+
+    let mut command = Command::new("rustc");
+    command
+        .arg("--print")
+        .arg("target-libdir")
+        .arg("--target")
+        .arg(cargo_target);
+    if loader_dir.exists() {
+        command.current_dir(&loader_dir);
+    }
+
+    let installed = probe(command).is_ok_and(|libdir| {
+        std::fs::read_dir(libdir).is_ok_and(|mut entries| entries.next().is_some())
+    });
+    if installed {
+        Check::ok(format!("target {}", cargo_target), "installed")
+    } else {
+        Check::problem(
+            format!("target {}", cargo_target),
+            "not installed",
+            format!("rustup target add {} (in a9nloader-rs)", cargo_target),
+        )
+    }
+}
+
+// The compilers named in A9N/src/hal/<arch>/toolchain.cmake.
+pub fn check_cross_toolchain(repo_root: &Utf8Path, arch: &Arch) -> Vec<Check> {
+    let arch_name = target_desc(arch).arch_name;
+    let toolchain_file = repo_root
+        .join("A9N")
+        .join("src")
+        .join("hal")
+        .join(arch_name)
+        .join("toolchain.cmake");
+
+    let Ok(text) = std::fs::read_to_string(&toolchain_file) else {
+        return vec![Check::problem(
+            format!("{} toolchain.cmake", arch_name),
+            format!("cannot read {}", toolchain_file),
+            "git submodule update --init --recursive A9N",
+        )];
+    };
+
+    let tools = toolchain_programs(&text);
+    if tools.is_empty() {
+        return vec![Check {
+            name: format!("{} toolchain.cmake", arch_name),
+            status: CheckStatus::Warning,
+            detail: "names no compiler; cmake picks the host default".to_string(),
+            fix: None,
+        }];
+    }
+
+    tools
+        .into_iter()
+        .map(|(variable, program)| {
+            if program.contains("${") {
+                return Check {
+                    name: variable,
+                    status: CheckStatus::Warning,
+                    detail: format!("cannot resolve {}", program),
+                    fix: None,
+                };
+            }
+
+            let mut check = check_tool(&program, &["--version"], None);
+            check.name = format!("{} ({})", program, variable);
+            if check.status == CheckStatus::Problem {
+                check.fix = Some(format!(
+                    "install the {} cross toolchain named in {}, or put {} on PATH",
+                    arch_name, toolchain_file, program
+                ));
+            }
+            check
+        })
+        .collect()
+}
+
+// CMAKE_<LANG>_COMPILER and friends from `set(...)`, with `${VAR}` expanded
+// from earlier `set`s in the same file.
+pub fn toolchain_programs(text: &str) -> Vec<(String, String)> {
+    let set = Regex::new(r#"(?m)^\s*set\s*\(\s*([A-Za-z_][A-Za-z0-9_]*)\s+"?([^")\s]+)"?"#)
+        .expect("valid regex");
+    let reference = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("valid regex");
+    let program_variable =
+        Regex::new(r"^CMAKE_(C|CXX|ASM|ASM_NASM)_COMPILER$|^CMAKE_(LINKER|AR|OBJCOPY)$")
+            .expect("valid regex");
+
+    let mut variables: BTreeMap<String, String> = BTreeMap::new();
+    let mut programs = Vec::new();
+    for captures in set.captures_iter(text) {
+        let name = captures[1].to_string();
+        let value = reference
+            .replace_all(&captures[2], |reference: &regex::Captures| {
+                variables
+                    .get(&reference[1])
+                    .cloned()
+                    .unwrap_or_else(|| reference[0].to_string())
+            })
+            .into_owned();
+
+        if program_variable.is_match(&name) {
+            programs.push((name.clone(), value.clone()));
+        }
+        variables.insert(name, value);
+    }
+    programs
+}
+
+fn check_firmware(repo_root: &Utf8Path, args: &ArchCheckArgs) -> Check {
+    let name = format!("{} firmware", target_desc(&args.arch).arch_name);
+
+    match resolve_firmware(repo_root, &args.firmware) {
+        Ok(firmware) => Check::ok(name, firmware.to_string()),
+        Err(error) => {
+            // the first line says what is missing; the rest is the search
+            let error = error.to_string();
+            let summary = error
+                .lines()
+                .next()
+                .and_then(|line| line.split("; searched").next())
+                .unwrap_or_default()
+                .to_string();
+            if args.firmware.code_path.is_some() {
+                Check::problem(
+                    name,
+                    summary,
+                    "fix qemu.firmware_code / qemu.firmware_vars in spencer.toml",
+                )
+            } else {
+                Check::problem(name, summary, firmware_hint(&args.arch))
+            }
+        }
+    }
+}
+
+pub fn firmware_hint(arch: &Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => {
+            "install ovmf (Debian/Ubuntu: apt install ovmf, Fedora: dnf install edk2-ovmf), or copy OVMF_CODE.fd / OVMF_VARS.fd into a9nloader-rs/tools"
+        }
+        Arch::Aarch64 => "install AAVMF (Debian/Ubuntu: apt install qemu-efi-aarch64)",
+        Arch::Riscv64 => {
+            "install edk2 for riscv64 (Debian/Ubuntu: apt install qemu-efi-riscv64) or U-Boot (apt install u-boot-qemu)"
+        }
+    }
+}
+
+fn install_hint(program: &str) -> String {
+    match program {
+        "cmake" => "install CMake (Debian/Ubuntu: apt install cmake; macOS: brew install cmake)"
+            .to_string(),
+        "cargo" | "rustc" | "rustup" => "install Rust with rustup (https://rustup.rs)".to_string(),
+        "git" => "install git".to_string(),
+        "qemu-system-x86_64" => {
+            "install QEMU (Debian/Ubuntu: apt install qemu-system-x86; macOS: brew install qemu)"
+                .to_string()
+        }
+        "qemu-system-aarch64" => {
+            "install QEMU (Debian/Ubuntu: apt install qemu-system-arm; macOS: brew install qemu)"
+                .to_string()
+        }
+        "qemu-system-riscv64" => {
+            "install QEMU (Debian/Ubuntu: apt install qemu-system-misc; macOS: brew install qemu)"
+                .to_string()
+        }
+        "gdb" | "gdb-multiarch" => {
+            "install a multi-arch gdb (Debian/Ubuntu: apt install gdb-multiarch) or pass --debugger"
+                .to_string()
+        }
+        "stty" => "install coreutils".to_string(),
+        program => format!("install {} or put it on PATH", program),
+    }
+}
+
+// First line of the program's output, or why it could not be run.
+fn probe(mut command: Command) -> std::result::Result<String, String> {
+    let output = command.output().map_err(|error| match error.kind() {
+        std::io::ErrorKind::NotFound => "not found".to_string(),
+        _ => error.to_string(),
+    })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr
+            .lines()
+            .next()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("exited with {}", output.status)));
+    }
+
+    // some tools (older gdb, nasm) print the version on stderr
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Ok(stdout
+        .lines()
+        .chain(stderr.lines())
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
@@ -0,0 +1,189 @@
+use crate::steps::artifact::Provenance;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::UNIX_EPOCH;
+
+// Hash of everything a step depends on. When it matches the stamp recorded by
+// the last successful run (and the outputs still exist) the step is skipped.
+pub struct Fingerprint {
+    hasher: Sha256,
+}
+
+impl Fingerprint {
+    pub fn new(step: &str) -> Self {
+        let mut fingerprint = Self {
+            hasher: Sha256::new(),
+        };
+        fingerprint.add_str("step", step);
+        fingerprint
+    }
+
+    pub fn add_str(&mut self, key: &str, value: &str) {
+        // length-prefixed so that ("ab", "c") and ("a", "bc") differ
+        for part in [key, value] {
+            self.hasher.update((part.len() as u64).to_le_bytes());
+            self.hasher.update(part.as_bytes());
+        }
+    }
+
+    // Source tree by path, size and mtime (like make), skipping `excluded` dir names.
+    pub fn add_tree(&mut self, root: &Utf8Path, excluded: &[&str]) -> Result<()> {
+        let mut files = Vec::new();
+        collect_files(root, excluded, &mut files)?;
+        files.sort();
+
+        self.add_str("tree", root.as_str());
+        for file in files {
+            let metadata = std::fs::metadata(&file).with_context(|| format!("stat: {}", file))?;
+            let relative = file.strip_prefix(root).unwrap_or(&file);
+            self.add_str(
+                relative.as_str(),
+                &format!("{}:{}", metadata.len(), mtime_nanos(&metadata)),
+            );
+        }
+
+        Ok(())
+    }
+
+    // Exact file contents; a missing file is an input too (e.g. no Cargo.lock yet).
+    pub fn add_file_contents(&mut self, path: &Utf8Path) -> Result<()> {
+        if !path.exists() {
+            self.add_str("missing", path.as_str());
+            return Ok(());
+        }
+
+        self.add_str("file", path.as_str());
+        self.hasher.update(hash_file(path)?);
+        Ok(())
+    }
+
+    pub fn add_provenance(&mut self, provenance: &Provenance) {
+        for (tool, version) in &provenance.toolchain {
+            self.add_str(tool, version);
+        }
+        self.add_str(
+            "source_commit",
+            provenance.source_commit.as_deref().unwrap_or("unknown"),
+        );
+    }
+
+    pub fn finish(self) -> String {
+        to_hex(&self.hasher.finalize())
+    }
+}
+
+pub fn hash_file(path: &Utf8Path) -> Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("open: {}", path))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 1024 * 64];
+    loop {
+        let read_size = file
+            .read(&mut buffer)
+            .with_context(|| format!("read: {}", path))?;
+        if read_size == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read_size]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Per-step stamp under out/<...>/.fingerprint/: the input fingerprint plus the
+// size and mtime of each output, so an output touched since (e.g. the image
+// written to by a guest) is rebuilt as well.
+pub struct Stamp {
+    path: Utf8PathBuf,
+}
+
+impl Stamp {
+    pub fn new(out_base: &Utf8Path, step: &str) -> Self {
+        Self {
+            path: out_base.join(".fingerprint").join(step),
+        }
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    pub fn is_up_to_date(&self, fingerprint: &str, outputs: &[&Utf8Path]) -> bool {
+        let Ok(recorded) = std::fs::read_to_string(&self.path) else {
+            return false;
+        };
+
+        match stamp_contents(fingerprint, outputs) {
+            Some(current) => recorded == current,
+            None => false,
+        }
+    }
+
+    // nothing is recorded while an output is missing; the step then runs again
+    pub fn record(&self, fingerprint: &str, outputs: &[&Utf8Path]) -> Result<()> {
+        let Some(contents) = stamp_contents(fingerprint, outputs) else {
+            return Ok(());
+        };
+
+        let dir = self.path.parent().context("stamp has no parent")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("create stamp dir: {}", dir))?;
+        std::fs::write(&self.path, contents).with_context(|| format!("write stamp: {}", self.path))
+    }
+
+    // forget the last run, so a step that fails half-way is not skipped next time
+    pub fn invalidate(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// None when an output is missing
+fn stamp_contents(fingerprint: &str, outputs: &[&Utf8Path]) -> Option<String> {
+    let mut contents = format!("{}\n", fingerprint);
+    for output in outputs {
+        let metadata = std::fs::metadata(output).ok()?;
+        contents.push_str(&format!(
+            "{} {} {}\n",
+            output,
+            metadata.len(),
+            mtime_nanos(&metadata)
+        ));
+    }
+    Some(contents)
+}
+
+fn mtime_nanos(metadata: &std::fs::Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+}
+
+fn collect_files(dir: &Utf8Path, excluded: &[&str], files: &mut Vec<Utf8PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("read_dir: {}", dir))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+
+        if excluded.contains(&file_name) {
+            continue;
+        }
+
+        let path = dir.join(file_name);
+        if file_type.is_dir() {
+            collect_files(&path, excluded, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
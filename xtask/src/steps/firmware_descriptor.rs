@@ -0,0 +1,435 @@
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+// QEMU's firmware descriptor format (docs/interop/firmware.json), as installed
+// by the distribution packages of edk2, e.g.
+// /usr/share/qemu/firmware/60-edk2-x86_64.json:
+//
+// {
+//     "interface-types": ["uefi"],
+//     "mapping": {
+//         "device": "flash",
+//         "executable": { "filename": "/usr/share/OVMF/OVMF_CODE_4M.fd", "format": "raw" },
+//         "nvram-template": { "filename": "/usr/share/OVMF/OVMF_VARS_4M.fd", "format": "raw" }
+//     },
+//     "targets": [{ "architecture": "x86_64", "machines": ["pc-i440fx-*", "pc-q35-*"] }],
+//     "features": ["acpi-s3", "verbose-dynamic"]
+// }
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DescriptorFile {
+    #[serde(default)]
+    interface_types: Vec<String>,
+    mapping: Mapping,
+    #[serde(default)]
+    targets: Vec<DescriptorTarget>,
+    #[serde(default)]
+    features: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Mapping {
+    device: String,
+    // only for flash; split unless given
+    mode: Option<String>,
+    executable: Option<FlashFile>,
+    nvram_template: Option<FlashFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlashFile {
+    filename: Utf8PathBuf,
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DescriptorTarget {
+    architecture: String,
+    #[serde(default)]
+    machines: Vec<String>,
+}
+
+// What the firmware has to run on and support; None accepts anything.
+pub struct FirmwareQuery<'a> {
+    // QEMU's name for the arch: x86_64, aarch64, riscv64
+    pub arch_name: &'a str,
+    // machine type as passed to -M, e.g. q35 or virt
+    pub machine: Option<&'a str>,
+    pub secure_boot: Option<bool>,
+}
+
+pub struct Descriptor {
+    pub path: Utf8PathBuf,
+    pub code_path: Utf8PathBuf,
+    pub vars_path: Utf8PathBuf,
+    // secure boot builds of OVMF keep the variable store in SMM
+    pub requires_smm: bool,
+}
+
+// A file in an earlier directory hides the one of the same name in a later one.
+pub fn descriptor_dirs() -> Vec<Utf8PathBuf> {
+    let config_home = std::env::var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(Utf8PathBuf::from)
+        .or_else(|| {
+            std::env::var("HOME")
+                .ok()
+                .map(|home| Utf8PathBuf::from(home).join(".config"))
+        });
+
+    let mut dirs = Vec::new();
+    if let Some(config_home) = config_home {
+        dirs.push(config_home.join("qemu/firmware"));
+    }
+    dirs.push(Utf8PathBuf::from("/etc/qemu/firmware"));
+    dirs.push(Utf8PathBuf::from("/usr/share/qemu/firmware"));
+    dirs
+}
+
+// The first descriptor, in file name order, that fits the query, and why
+// each one before it did not.
+pub fn find_descriptor(query: &FirmwareQuery) -> (Option<Descriptor>, Vec<String>) {
+    find_descriptor_in(&descriptor_dirs(), query)
+}
+
+// `find_descriptor` over `dirs`, highest priority first.
+fn find_descriptor_in(
+    dirs: &[Utf8PathBuf],
+    query: &FirmwareQuery,
+) -> (Option<Descriptor>, Vec<String>) {
+    let mut files: Vec<(String, Utf8PathBuf)> = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = dir.read_dir_utf8() else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string();
+            if name.ends_with(".json") && !files.iter().any(|(taken, _)| *taken == name) {
+                files.push((name, entry.into_path()));
+            }
+        }
+    }
+    files.sort();
+
+    let mut rejected = Vec::new();
+    for (_, path) in files {
+        // an empty file masks a descriptor without replacing it
+        if std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() == 0) {
+            continue;
+        }
+        match load_descriptor(&path, query) {
+            Ok(descriptor) => return (Some(descriptor), rejected),
+            Err(error) => rejected.push(format!("{}: {:#}", path, error)),
+        }
+    }
+    (None, rejected)
+}
+
+// The firmware the descriptor at `path` describes, or why it does not fit the query.
+pub fn load_descriptor(path: &Utf8Path, query: &FirmwareQuery) -> Result<Descriptor> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("read descriptor: {}", path))?;
+    let file: DescriptorFile = serde_json::from_str(&text).context("invalid descriptor")?;
+
+    if !file.interface_types.iter().any(|kind| kind == "uefi") {
+        bail!("not UEFI firmware");
+    }
+    if file.mapping.device != "flash" {
+        bail!("{} mapping is not supported", file.mapping.device);
+    }
+    let mode = file.mapping.mode.as_deref().unwrap_or("split");
+    if mode != "split" {
+        bail!("{} flash mode is not supported", mode);
+    }
+    let (Some(executable), Some(nvram_template)) =
+        (&file.mapping.executable, &file.mapping.nvram_template)
+    else {
+        bail!("no executable and nvram-template");
+    };
+    for flash in [executable, nvram_template] {
+        let format = flash.format.as_deref().unwrap_or("raw");
+        if format != "raw" {
+            bail!(
+                "{} is {}, only raw images are supported",
+                flash.filename,
+                format
+            );
+        }
+    }
+
+    let fits_target = file.targets.iter().any(|target| {
+        target.architecture == query.arch_name
+            && query.machine.is_none_or(|machine| {
+                target
+                    .machines
+                    .iter()
+                    .any(|pattern| machine_matches(pattern, machine))
+            })
+    });
+    if !fits_target {
+        match query.machine {
+            Some(machine) => bail!("not for {} on {}", query.arch_name, machine),
+            None => bail!("not for {}", query.arch_name),
+        }
+    }
+
+    let secure_boot = file.features.iter().any(|feature| feature == "secure-boot");
+    match (query.secure_boot, secure_boot) {
+        (Some(true), false) => bail!("no secure boot"),
+        (Some(false), true) => bail!("a secure boot build"),
+        _ => {}
+    }
+
+    // paths are absolute in practice; anything else is taken as relative to the descriptor
+    let base_dir = path.parent().unwrap_or(Utf8Path::new("."));
+    let code_path = base_dir.join(&executable.filename);
+    let vars_path = base_dir.join(&nvram_template.filename);
+    for file_path in [&code_path, &vars_path] {
+        if !file_path.exists() {
+            bail!("{} is missing", file_path);
+        }
+    }
+
+    Ok(Descriptor {
+        path: path.to_path_buf(),
+        code_path,
+        vars_path,
+        requires_smm: file
+            .features
+            .iter()
+            .any(|feature| feature == "requires-smm"),
+    })
+}
+
+// Descriptors list versioned machine types ("pc-q35-*"); an alias such as
+// q35 stands for the latest of its versions.
+fn machine_matches(pattern: &str, machine: &str) -> bool {
+    let Ok(pattern) = glob::Pattern::new(pattern) else {
+        return false;
+    };
+    let versioned = match machine {
+        "pc" => "pc-i440fx",
+        "q35" => "pc-q35",
+        alias => alias,
+    };
+    pattern.matches(machine) || pattern.matches(&format!("{}-latest", versioned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::scratch::ScratchDir;
+
+    // user, etc and system descriptor dirs plus firmware files, removed on drop
+    struct ScratchDirs {
+        scratch: ScratchDir,
+        dirs: Vec<Utf8PathBuf>,
+    }
+
+    impl ScratchDirs {
+        fn new(name: &str) -> Self {
+            let scratch = ScratchDir::new(&format!("firmware-{}", name));
+            let dirs: Vec<_> = ["user", "etc", "system"]
+                .iter()
+                .map(|dir| scratch.join(dir))
+                .collect();
+            for dir in &dirs {
+                std::fs::create_dir_all(dir).unwrap();
+            }
+            for firmware in ["CODE.fd", "VARS.fd", "CODE.secboot.fd", "VARS.secboot.fd"] {
+                scratch.write(firmware, firmware);
+            }
+            Self { scratch, dirs }
+        }
+
+        // `dir` is 0 (user), 1 (etc) or 2 (system)
+        fn write(&self, dir: usize, name: &str, text: &str) -> Utf8PathBuf {
+            let path = self.dirs[dir].join(name);
+            std::fs::write(&path, text).unwrap();
+            path
+        }
+
+        fn find(&self, query: &FirmwareQuery) -> (Option<Descriptor>, Vec<String>) {
+            find_descriptor_in(&self.dirs, query)
+        }
+    }
+
+    // a descriptor for `code` and `vars` next to the descriptor dirs
+    fn descriptor(
+        arch: &str,
+        machines: &[&str],
+        features: &[&str],
+        code: &str,
+        vars: &str,
+    ) -> String {
+        serde_json::json!({
+            "interface-types": ["uefi"],
+            "mapping": {
+                "device": "flash",
+                "executable": { "filename": format!("../{}", code), "format": "raw" },
+                "nvram-template": { "filename": format!("../{}", vars), "format": "raw" },
+            },
+            "targets": [{ "architecture": arch, "machines": machines }],
+            "features": features,
+        })
+        .to_string()
+    }
+
+    fn plain(arch: &str) -> String {
+        descriptor(arch, &["pc-q35-*"], &[], "CODE.fd", "VARS.fd")
+    }
+
+    fn secure_boot() -> String {
+        descriptor(
+            "x86_64",
+            &["pc-q35-*"],
+            &["secure-boot", "requires-smm"],
+            "CODE.secboot.fd",
+            "VARS.secboot.fd",
+        )
+    }
+
+    const X86_64: FirmwareQuery = FirmwareQuery {
+        arch_name: "x86_64",
+        machine: None,
+        secure_boot: None,
+    };
+
+    #[test]
+    fn earlier_names_win_across_dirs() {
+        let scratch = ScratchDirs::new("order");
+        scratch.write(2, "50-system.json", &plain("x86_64"));
+        let etc = scratch.write(1, "40-etc.json", &plain("x86_64"));
+        scratch.write(0, "60-user.json", &plain("x86_64"));
+
+        // the file name decides, not the directory
+        let (found, rejected) = scratch.find(&X86_64);
+        assert_eq!(found.unwrap().path, etc);
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn earlier_dirs_hide_the_same_name() {
+        let scratch = ScratchDirs::new("mask");
+        scratch.write(2, "50-edk2.json", &plain("x86_64"));
+        let etc = scratch.write(1, "50-edk2.json", &secure_boot());
+        let user = scratch.write(0, "50-edk2.json", &plain("aarch64"));
+
+        // the user's replacement does not fit, and the system's is hidden behind it
+        let (found, rejected) = scratch.find(&X86_64);
+        assert!(found.is_none());
+        assert_eq!(rejected, [format!("{}: not for x86_64", user)]);
+
+        // an empty file hides it without replacing it
+        std::fs::write(&user, "").unwrap();
+        std::fs::write(&etc, "").unwrap();
+        let (found, rejected) = scratch.find(&X86_64);
+        assert!(found.is_none());
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn filters_by_arch_machine_and_secure_boot() {
+        let scratch = ScratchDirs::new("filters");
+        let aarch64 = scratch.write(2, "10-aarch64.json", &plain("aarch64"));
+        let secure = scratch.write(2, "20-secboot.json", &secure_boot());
+        let i440fx = scratch.write(
+            2,
+            "30-i440fx.json",
+            &descriptor("x86_64", &["pc-i440fx-*"], &[], "CODE.fd", "VARS.fd"),
+        );
+        let q35 = scratch.write(2, "40-q35.json", &plain("x86_64"));
+
+        let (found, rejected) = scratch.find(&X86_64);
+        let found = found.unwrap();
+        assert_eq!(found.path, secure);
+        assert_eq!(found.code_path, scratch.dirs[2].join("../CODE.secboot.fd"));
+        assert!(found.requires_smm);
+        assert_eq!(rejected, [format!("{}: not for x86_64", aarch64)]);
+
+        let query = FirmwareQuery {
+            secure_boot: Some(false),
+            ..X86_64
+        };
+        let (found, rejected) = scratch.find(&query);
+        assert_eq!(found.unwrap().path, i440fx);
+        assert_eq!(rejected[1], format!("{}: a secure boot build", secure));
+
+        // q35 is taken as the latest pc-q35 version
+        let query = FirmwareQuery {
+            machine: Some("q35"),
+            secure_boot: Some(false),
+            ..X86_64
+        };
+        let (found, rejected) = scratch.find(&query);
+        let found = found.unwrap();
+        assert_eq!(found.path, q35);
+        assert!(!found.requires_smm);
+        assert_eq!(rejected[2], format!("{}: not for x86_64 on q35", i440fx));
+
+        let query = FirmwareQuery {
+            machine: Some("pc-i440fx-9.0"),
+            secure_boot: Some(true),
+            ..X86_64
+        };
+        let (found, rejected) = scratch.find(&query);
+        assert!(found.is_none());
+        assert_eq!(rejected.len(), 4);
+        assert_eq!(rejected[2], format!("{}: no secure boot", i440fx));
+    }
+
+    #[test]
+    fn rejects_unsupported_descriptors() {
+        let scratch = ScratchDirs::new("unsupported");
+        let memory = plain("x86_64").replace(r#""device":"flash""#, r#""device":"memory""#);
+        let qcow2 = plain("x86_64").replace(r#""format":"raw""#, r#""format":"qcow2""#);
+        let missing = descriptor("x86_64", &[], &[], "CODE.fd", "MISSING.fd");
+        let cases = [
+            (
+                "10-bios.json",
+                plain("x86_64").replace("uefi", "bios"),
+                "not UEFI firmware",
+            ),
+            ("20-memory.json", memory, "memory mapping is not supported"),
+            ("30-qcow2.json", qcow2, "only raw images are supported"),
+            ("40-missing.json", missing, "MISSING.fd is missing"),
+            ("50-broken.json", "{".to_string(), "invalid descriptor"),
+        ];
+        for (name, text, _) in &cases {
+            scratch.write(1, name, text);
+        }
+
+        // nothing fits: the caller falls back to its own firmware, and says why
+        let (found, rejected) = scratch.find(&X86_64);
+        assert!(found.is_none());
+        assert_eq!(rejected.len(), cases.len());
+        for ((name, _, message), rejection) in cases.iter().zip(&rejected) {
+            assert!(rejection.starts_with(scratch.dirs[1].join(name).as_str()));
+            assert!(rejection.contains(message), "{}", rejection);
+        }
+    }
+
+    #[test]
+    fn skips_missing_dirs_and_other_files() {
+        let scratch = ScratchDirs::new("skip");
+        scratch.write(0, "10-notes.txt", "not a descriptor");
+        let dirs = [scratch.scratch.join("absent"), scratch.dirs[0].clone()];
+        let (found, rejected) = find_descriptor_in(&dirs, &X86_64);
+        assert!(found.is_none());
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn matches_machine_aliases() {
+        assert!(machine_matches("pc-q35-*", "q35"));
+        assert!(machine_matches("pc-q35-*", "pc-q35-8.2"));
+        assert!(machine_matches("pc-i440fx-*", "pc"));
+        assert!(!machine_matches("pc-i440fx-*", "q35"));
+        assert!(machine_matches("virt-*", "virt"));
+        assert!(machine_matches("virt", "virt"));
+        assert!(!machine_matches("[", "virt"));
+    }
+}
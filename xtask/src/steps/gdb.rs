@@ -0,0 +1,83 @@
+use crate::cli::Arch;
+use crate::steps::process::run_command;
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use std::process::Command;
+
+#[derive(Clone, Debug)]
+pub struct RunGdbArgs<'a> {
+    pub arch: Arch,
+    pub debugger: &'a str,
+    pub out_base: &'a Utf8Path,
+
+    pub kernel_elf_path: &'a Utf8Path,
+    pub init_elf_path: &'a Utf8Path,
+
+    pub gdb_port: u16,
+
+    pub verbose: bool,
+    pub dry_run: bool,
+}
+
+pub fn run_gdb(args: &RunGdbArgs) -> Result<()> {
+    let script_path = args.out_base.join("gdb").join("spencer.gdb");
+    let script = gdb_script(args);
+
+    if args.dry_run {
+        eprintln!("[dry-run] {} -x {}", args.debugger, script_path);
+        for line in script.lines() {
+            eprintln!("[dry-run]   {}", line);
+        }
+        return Ok(());
+    }
+
+    let script_dir = script_path.parent().context("gdb script has no parent")?;
+    std::fs::create_dir_all(script_dir)
+        .with_context(|| format!("create gdb script dir: {}", script_dir))?;
+    std::fs::write(&script_path, script)
+        .with_context(|| format!("write gdb script: {}", script_path))?;
+
+    if args.verbose {
+        eprintln!("[gdb] script: {}", script_path);
+    }
+
+    let mut command = Command::new(args.debugger);
+    command.arg("-q").arg("-x").arg(&script_path);
+
+    run_command(command, args.verbose, args.debugger)?;
+
+    Ok(())
+}
+
+fn gdb_script(args: &RunGdbArgs) -> String {
+    let mut script = String::new();
+
+    script.push_str("set pagination off\n");
+    script.push_str("set confirm off\n");
+    script.push_str(&format!(
+        "set architecture {}\n",
+        to_gdb_architecture(&args.arch)
+    ));
+    script.push_str(&format!("symbol-file {}\n", quote(args.kernel_elf_path)));
+    script.push_str(&format!("add-symbol-file {}\n", quote(args.init_elf_path)));
+    script.push_str(&format!("target remote 127.0.0.1:{}\n", args.gdb_port));
+
+    script
+}
+
+fn quote(path: &Utf8Path) -> String {
+    // gdb accepts double-quoted file names containing spaces
+    if path.as_str().contains(' ') {
+        format!("\"{}\"", path)
+    } else {
+        path.to_string()
+    }
+}
+
+fn to_gdb_architecture(arch: &Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "i386:x86-64",
+        Arch::Aarch64 => "aarch64",
+        Arch::Riscv64 => "riscv:rv64",
+    }
+}
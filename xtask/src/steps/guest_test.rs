@@ -0,0 +1,474 @@
+use crate::steps::console::{Console, ConsoleArgs, ExpectError, load_script};
+use crate::steps::process::{ChildGuard, catch_interrupts, interrupted, output_prefix};
+use crate::steps::qemu::{
+    RunQemuArgs, SerialTarget, TestExitDevice, spawn_qemu_captured, test_exit_device,
+};
+use crate::steps::qmp::shut_down;
+use crate::steps::symbolize::CrashScanner;
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::ExitStatus;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+// value the x86_64 guest writes to isa-debug-exit to report success
+const ISA_DEBUG_EXIT_SUCCESS: u32 = 0x10;
+
+// process exit codes for outcomes the guest did not choose
+pub const EXIT_TIMEOUT: i32 = 124;
+pub const EXIT_NO_RESULT: i32 = 125;
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+pub struct RunGuestTestArgs<'a> {
+    // serial must be Stdio, or UnixSocket / Pty when a console script is given
+    pub qemu: RunQemuArgs<'a>,
+    pub serial_log_path: &'a Utf8Path,
+    pub timeout: Duration,
+    // expect/send steps run against the serial console while the guest runs
+    pub console_script: Option<&'a Utf8Path>,
+    // annotate crash addresses on the terminal with their source location
+    pub symbolize_elfs: &'a [Utf8PathBuf],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    // failure code reported by the guest
+    Failed(u32),
+    TimedOut,
+    // QEMU ended without the guest reporting (crash, reset, QEMU error)
+    NoResult(Option<i32>),
+    // a console script step failed; the message says which and why
+    ScriptFailed(String),
+    // Ctrl-C
+    Interrupted,
+    // shut down with the other instances of a topology before it ended itself
+    Stopped,
+}
+
+impl TestOutcome {
+    // 0 = pass, guest failure codes as-is (1..=123), 124 = timeout, 125 = no result,
+    // 130 = Ctrl-C
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TestOutcome::Passed => 0,
+            TestOutcome::Failed(code @ 1..=123) => *code as i32,
+            TestOutcome::Failed(_) => 1,
+            TestOutcome::TimedOut => EXIT_TIMEOUT,
+            TestOutcome::NoResult(_) => EXIT_NO_RESULT,
+            TestOutcome::ScriptFailed(_) => 1,
+            TestOutcome::Interrupted => EXIT_INTERRUPTED,
+            TestOutcome::Stopped => 0,
+        }
+    }
+}
+
+impl std::fmt::Display for TestOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestOutcome::Passed => write!(f, "passed"),
+            TestOutcome::Failed(code) => write!(f, "failed (guest code {})", code),
+            TestOutcome::TimedOut => write!(f, "timed out"),
+            TestOutcome::NoResult(Some(status)) => {
+                write!(f, "no result (QEMU exited with {})", status)
+            }
+            TestOutcome::NoResult(None) => write!(f, "no result (QEMU was killed)"),
+            TestOutcome::ScriptFailed(message) => write!(f, "console script failed: {}", message),
+            TestOutcome::Interrupted => write!(f, "interrupted"),
+            TestOutcome::Stopped => write!(f, "stopped with the other instances"),
+        }
+    }
+}
+
+// Boot headless, tee the serial console to stdout and `serial_log_path`, and
+// wait for the guest to end QEMU through its test exit device. On timeout or
+// Ctrl-C the guest is shut down through QMP when a socket is given.
+// With a console script the script drives the serial port instead. A
+// completed script passes the test unless the guest already reported
+// otherwise, or the script asks to wait for the guest's own verdict.
+// Returns None in dry-run.
+pub fn run_guest_test(args: &RunGuestTestArgs) -> Result<Option<TestOutcome>> {
+    let device = test_exit_device(&args.qemu.arch);
+
+    match (args.qemu.serial, args.console_script) {
+        (SerialTarget::Stdio, None) => {}
+        (SerialTarget::UnixSocket(_) | SerialTarget::Pty, Some(_)) => {}
+        (serial, script) => bail!(
+            "serial {:?} does not fit console script {:?}",
+            serial,
+            script
+        ),
+    }
+
+    // parse errors are reported before anything boots
+    let script = args.console_script.map(load_script).transpose()?;
+
+    if args.qemu.dry_run {
+        eprintln!("[dry-run] serial log: {}", args.serial_log_path);
+        eprintln!("[dry-run] timeout: {}s", args.timeout.as_secs());
+        if let Some(script_path) = args.console_script {
+            eprintln!("[dry-run] console script: {}", script_path);
+        }
+    }
+
+    if !args.qemu.dry_run {
+        catch_interrupts()?;
+    }
+
+    let Some(mut qemu) = spawn_qemu_captured(&args.qemu)? else {
+        return Ok(None);
+    };
+
+    let deadline = Instant::now() + args.timeout;
+
+    // set when several guests share the terminal (matrix --parallel)
+    let prefix = output_prefix();
+
+    let stderr = qemu.take_stderr().context("QEMU stderr is not captured")?;
+    let pty_path = forward_stderr(stderr, prefix.clone());
+
+    let stdout = qemu.take_stdout().context("QEMU stdout is not captured")?;
+
+    let Some(script) = script else {
+        let serial_thread = tee_serial(stdout, args.serial_log_path, args.symbolize_elfs, prefix)?;
+
+        let status = wait_until(&mut qemu, deadline)?;
+
+        stop_qemu(qemu, args.qemu.qmp_socket);
+
+        // the log is written as it arrives; after a timeout a leftover process may
+        // still hold the stream open, so the reader is not waited for
+        if status.is_some() {
+            serial_thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("serial reader panicked"))?
+                .context("copy serial output")?;
+        }
+
+        let outcome = match status {
+            Some(status) => decode_exit_status(device, status),
+            None if interrupted() => TestOutcome::Interrupted,
+            None => TestOutcome::TimedOut,
+        };
+        return Ok(Some(outcome));
+    };
+
+    // the serial port is elsewhere; stdout only carries QEMU's own messages
+    let stdout_prefix = prefix.clone();
+    std::thread::spawn(move || match stdout_prefix {
+        Some(prefix) => {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                println!("[{}] {}", prefix, line);
+            }
+        }
+        None => {
+            let mut stdout = stdout;
+            let _ = std::io::copy(&mut stdout, &mut std::io::stdout());
+        }
+    });
+
+    // a prefixed run keeps the console to serial.log; a failing step shows its tail
+    let console_args = ConsoleArgs {
+        transcript_path: args.serial_log_path,
+        echo: prefix.is_none(),
+        timeout: args.timeout,
+        symbolize_elfs: args.symbolize_elfs,
+    };
+    let console = match args.qemu.serial {
+        SerialTarget::UnixSocket(socket_path) => {
+            // QEMU creates the socket, then waits for this connection before
+            // starting the guest
+            wait_for(&mut qemu, deadline, || socket_path.exists().then_some(()))?
+                .map(|()| Console::connect_unix(socket_path, Duration::from_secs(5), &console_args))
+        }
+        _ => wait_for(&mut qemu, deadline, || pty_path.try_recv().ok())?
+            .map(|pty_path| Console::open_pty(&pty_path, &console_args)),
+    };
+    let mut console = match console {
+        Some(console) => console?,
+        // QEMU ended or the deadline passed before the port was ready
+        None => {
+            let status = qemu.try_wait()?;
+            stop_qemu(qemu, args.qemu.qmp_socket);
+            return Ok(Some(match status {
+                Some(status) => decode_exit_status(device, status),
+                None if interrupted() => TestOutcome::Interrupted,
+                None => TestOutcome::TimedOut,
+            }));
+        }
+    };
+    console.set_deadline(deadline);
+
+    let script_result = script.run(&mut console);
+
+    // the guest may still be on its way to the exit device when the console
+    // closes under a failing step
+    let status = match &script_result {
+        Ok(()) if script.wait_for_exit() => wait_until(&mut qemu, deadline)?,
+        Ok(()) => qemu.try_wait()?,
+        Err(error) if is_console_closed(error) => {
+            wait_until(&mut qemu, Instant::now() + Duration::from_secs(2))?
+        }
+        Err(_) => qemu.try_wait()?,
+    };
+
+    // stops QEMU when the script finished first
+    stop_qemu(qemu, args.qemu.qmp_socket);
+
+    let outcome = match (script_result, status) {
+        (Ok(()), None) if interrupted() => TestOutcome::Interrupted,
+        (Ok(()), None) if script.wait_for_exit() => TestOutcome::TimedOut,
+        (Ok(()), None) => TestOutcome::Passed,
+        (Ok(()), Some(status)) => decode_exit_status(device, status),
+        (Err(error), status) => {
+            let guest_failed = status
+                .map(|status| decode_exit_status(device, status))
+                .filter(|outcome| matches!(outcome, TestOutcome::Failed(_)));
+            match guest_failed {
+                Some(outcome) => outcome,
+                None if interrupted() => TestOutcome::Interrupted,
+                None if Instant::now() >= deadline => TestOutcome::TimedOut,
+                None => TestOutcome::ScriptFailed(format!("{:#}", error)),
+            }
+        }
+    };
+
+    Ok(Some(outcome))
+}
+
+// Annotations go to the terminal only, so serial.log stays what the guest printed.
+// With a prefix the terminal gets whole lines, each starting with `[prefix]`, so
+// that several guests can share it.
+pub fn tee_serial(
+    serial: impl Read + Send + 'static,
+    serial_log_path: &Utf8Path,
+    symbolize_elfs: &[Utf8PathBuf],
+    prefix: Option<String>,
+) -> Result<std::thread::JoinHandle<std::io::Result<()>>> {
+    let mut serial_log = std::fs::File::create(serial_log_path)
+        .with_context(|| format!("create serial log: {}", serial_log_path))?;
+    let symbolize_elfs = symbolize_elfs.to_vec();
+
+    Ok(std::thread::spawn(move || -> std::io::Result<()> {
+        let mut serial = serial;
+        let mut scanner = CrashScanner::load(&symbolize_elfs);
+        let mut buffer = [0u8; 4096];
+        // an unterminated line, when prefixing
+        let mut pending = Vec::new();
+        let mut stdout = std::io::stdout();
+        loop {
+            let read_size = serial.read(&mut buffer)?;
+            let chunk = &buffer[..read_size];
+            if read_size == 0 {
+                if let Some(prefix) = &prefix
+                    && !pending.is_empty()
+                {
+                    stdout.write_all(prefix_line(prefix, &pending).as_bytes())?;
+                }
+                return Ok(());
+            }
+            serial_log.write_all(chunk)?;
+            let annotations = match &mut scanner {
+                Some(scanner) => scanner.feed(chunk),
+                None => String::new(),
+            };
+
+            let Some(prefix) = &prefix else {
+                stdout.write_all(chunk)?;
+                stdout.write_all(annotations.as_bytes())?;
+                stdout.flush()?;
+                continue;
+            };
+            pending.extend_from_slice(chunk);
+            let mut text = String::new();
+            while let Some(newline) = pending.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=newline).collect();
+                text.push_str(&prefix_line(prefix, &line));
+            }
+            for line in annotations.lines() {
+                text.push_str(&prefix_line(prefix, line.as_bytes()));
+            }
+            // one write, so lines from different guests do not mix
+            stdout.write_all(text.as_bytes())?;
+            stdout.flush()?;
+        }
+    }))
+}
+
+fn prefix_line(prefix: &str, line: &[u8]) -> String {
+    format!(
+        "[{}] {}\n",
+        prefix,
+        String::from_utf8_lossy(line).trim_end()
+    )
+}
+
+// Pass QEMU's stderr through, picking out the pty it allocates for the serial
+// port ("char device redirected to /dev/pts/N (label serial0)").
+pub fn forward_stderr(
+    stderr: impl Read + Send + 'static,
+    prefix: Option<String>,
+) -> Receiver<Utf8PathBuf> {
+    let (sender, pty_path) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if let Some(rest) = line.strip_prefix("char device redirected to ")
+                && let Some((path, label)) = rest.split_once(' ')
+                && label == "(label serial0)"
+            {
+                let _ = sender.send(Utf8PathBuf::from(path));
+            }
+            match &prefix {
+                Some(prefix) => eprintln!("[{}] {}", prefix, line),
+                None => eprintln!("{}", line),
+            }
+        }
+    });
+
+    pty_path
+}
+
+// QEMU's exit status, or None when it is still running at `deadline` or on Ctrl-C.
+fn wait_until(qemu: &mut ChildGuard, deadline: Instant) -> Result<Option<ExitStatus>> {
+    wait_for(qemu, deadline, || None::<()>)?;
+    qemu.try_wait()
+}
+
+// Poll `ready` until it yields, giving up when QEMU exits or at `deadline`.
+fn wait_for<T>(
+    qemu: &mut ChildGuard,
+    deadline: Instant,
+    mut ready: impl FnMut() -> Option<T>,
+) -> Result<Option<T>> {
+    loop {
+        if let Some(value) = ready() {
+            return Ok(Some(value));
+        }
+        if qemu.try_wait()?.is_some() || Instant::now() >= deadline || interrupted() {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// A QEMU that is still running is asked to power down, then to quit; the
+// guard kills it if that does not work either.
+pub fn stop_qemu(mut qemu: ChildGuard, qmp_socket: Option<&Utf8Path>) {
+    if let Some(qmp_socket) = qmp_socket {
+        shut_down(&mut qemu, qmp_socket);
+    }
+}
+
+fn is_console_closed(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<ExpectError>(),
+        Some(ExpectError::Closed { .. })
+    )
+}
+
+pub fn decode_exit_status(device: TestExitDevice, status: ExitStatus) -> TestOutcome {
+    let Some(code) = status.code() else {
+        return TestOutcome::NoResult(None);
+    };
+
+    match device {
+        TestExitDevice::IsaDebugExit => {
+            // even codes never come from the device, and 1 (`outl(0xf4, 0)`)
+            // is indistinguishable from QEMU's own error status
+            if code == ((ISA_DEBUG_EXIT_SUCCESS << 1) | 1) as i32 {
+                TestOutcome::Passed
+            } else if code > 1 && code & 1 == 1 {
+                TestOutcome::Failed((code >> 1) as u32)
+            } else {
+                TestOutcome::NoResult(Some(code))
+            }
+        }
+        TestExitDevice::Semihosting | TestExitDevice::SifiveTest => match code {
+            0 => TestOutcome::Passed,
+            code => TestOutcome::Failed(code as u32),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    // a wait status: exited with `code`
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[test]
+    fn decodes_isa_debug_exit() {
+        let cases = [
+            // outl(0xf4, 0x10) passes
+            (0x21, TestOutcome::Passed),
+            (0x03, TestOutcome::Failed(1)),
+            (0x0b, TestOutcome::Failed(5)),
+            (0xff, TestOutcome::Failed(0x7f)),
+            // QEMU's own statuses: never from the device
+            (0, TestOutcome::NoResult(Some(0))),
+            (1, TestOutcome::NoResult(Some(1))),
+            (2, TestOutcome::NoResult(Some(2))),
+        ];
+        for (code, outcome) in cases {
+            let decoded = decode_exit_status(TestExitDevice::IsaDebugExit, exited(code));
+            assert_eq!(decoded, outcome, "exit code {:#x}", code);
+        }
+    }
+
+    #[test]
+    fn decodes_guest_chosen_codes() {
+        for device in [TestExitDevice::Semihosting, TestExitDevice::SifiveTest] {
+            let decoded = |code| decode_exit_status(device, exited(code));
+            assert_eq!(decoded(0), TestOutcome::Passed);
+            assert_eq!(decoded(1), TestOutcome::Failed(1));
+            assert_eq!(decoded(42), TestOutcome::Failed(42));
+        }
+    }
+
+    #[test]
+    fn killed_qemu_has_no_result() {
+        // SIGKILL
+        let killed = ExitStatus::from_raw(9);
+        for device in [
+            TestExitDevice::IsaDebugExit,
+            TestExitDevice::Semihosting,
+            TestExitDevice::SifiveTest,
+        ] {
+            assert_eq!(
+                decode_exit_status(device, killed),
+                TestOutcome::NoResult(None)
+            );
+        }
+    }
+
+    #[test]
+    fn maps_outcomes_to_exit_codes() {
+        let cases = [
+            (TestOutcome::Passed, 0),
+            (TestOutcome::Failed(1), 1),
+            (TestOutcome::Failed(123), 123),
+            // would read as one of the codes below, or not fit
+            (TestOutcome::Failed(124), 1),
+            (TestOutcome::Failed(0x7f), 1),
+            (TestOutcome::Failed(0), 1),
+            (TestOutcome::TimedOut, 124),
+            (TestOutcome::NoResult(Some(1)), 125),
+            (TestOutcome::NoResult(None), 125),
+            (TestOutcome::ScriptFailed("step 1".to_string()), 1),
+            (TestOutcome::Interrupted, 130),
+            (TestOutcome::Stopped, 0),
+        ];
+        for (outcome, exit_code) in cases {
+            assert_eq!(outcome.exit_code(), exit_code, "{}", outcome);
+        }
+    }
+}
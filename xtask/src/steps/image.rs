@@ -1,62 +1,455 @@
-use anyhow::{Context, Result};
-use camino::Utf8Path;
-use fscommon::BufStream;
+mod esp_layout;
+mod gpt;
+
+use crate::cli::ImageLayout;
+use crate::steps::artifact::{Artifact, ArtifactKind, Provenance};
+use crate::steps::fingerprint::{Fingerprint, Stamp};
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use fscommon::{BufStream, StreamSlice};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use uuid::Uuid;
+
+type FatStream = BufStream<StreamSlice<File>>;
+
+const MIB: u64 = 1024 * 1024;
 
 pub struct BuildImgArgs<'a> {
     pub img_path: &'a Utf8Path,
 
-    pub bootx64_efi_source_path: &'a Utf8Path,
-    pub init_elf_source_path: &'a Utf8Path,
-    pub kernel_elf_source_path: &'a Utf8Path,
+    // built-in entries; the ESP layout file (if any) is applied on top
+    pub entries: &'a [EspEntry],
+    pub esp_layout_path: Option<&'a Utf8Path>,
+
+    pub layout: ImageLayout,
+    // extra partitions after the ESP (gpt layout only)
+    pub data_partitions: &'a [DataPartition],
 
-    pub image_size_mib: u64,
+    // size of the FAT volume (the whole file for superfloppy, the ESP for gpt);
+    // computed from the packed files + slack when not given
+    pub image_size_mib: Option<u64>,
+    pub slack_mib: u64,
     pub verbose: bool,
     pub dry_run: bool,
+    // rebuild even when no packed file changed
+    pub force: bool,
+}
+
+// A file placed on the ESP: absolute path inside the image <- host file.
+#[derive(Clone, Debug)]
+pub struct EspEntry {
+    pub image_path: String,
+    pub host_path: Utf8PathBuf,
+}
+
+impl EspEntry {
+    pub fn new(image_path: impl Into<String>, host_path: impl Into<Utf8PathBuf>) -> Self {
+        Self {
+            image_path: image_path.into(),
+            host_path: host_path.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DataPartition {
+    pub name: String,
+    pub size_mib: u64,
+    // raw contents copied to the start of the partition
+    pub raw_image_path: Option<Utf8PathBuf>,
+}
+
+// Byte ranges of everything placed on the disk.
+struct DiskLayout {
+    total_bytes: u64,
+    fat_range: (u64, u64),
+    partitions: Vec<gpt::GptPartition>,
+    data_ranges: Vec<(u64, u64)>,
+}
+
+fn plan_layout(args: &BuildImgArgs, fat_size_mib: u64) -> Result<DiskLayout> {
+    let fat_bytes = fat_size_mib * MIB;
+
+    match args.layout {
+        ImageLayout::Superfloppy => {
+            if !args.data_partitions.is_empty() {
+                bail!("data partitions require --image-layout gpt");
+            }
+
+            Ok(DiskLayout {
+                total_bytes: fat_bytes,
+                fat_range: (0, fat_bytes),
+                partitions: Vec::new(),
+                data_ranges: Vec::new(),
+            })
+        }
+        ImageLayout::Gpt => {
+            let sectors_per_mib = MIB / gpt::SECTOR_SIZE;
+
+            let mut partitions = Vec::new();
+            let mut next_lba = gpt::ALIGNMENT_SECTORS;
+
+            let mut push = |type_guid: Uuid, name: &str, size_mib: u64| {
+                let first_lba = next_lba;
+                let last_lba = first_lba + size_mib * sectors_per_mib - 1;
+                next_lba = (last_lba + 1).next_multiple_of(gpt::ALIGNMENT_SECTORS);
+
+                partitions.push(gpt::GptPartition {
+                    type_guid,
+                    unique_guid: Uuid::new_v4(),
+                    name: name.to_string(),
+                    first_lba,
+                    last_lba,
+                });
+            };
+
+            push(gpt::ESP_TYPE_GUID, "EFI System Partition", fat_size_mib);
+            for data_partition in args.data_partitions {
+                if data_partition.size_mib == 0 {
+                    bail!("data partition '{}' has zero size", data_partition.name);
+                }
+                push(
+                    gpt::BASIC_DATA_TYPE_GUID,
+                    &data_partition.name,
+                    data_partition.size_mib,
+                );
+            }
+
+            if partitions.len() > 128 {
+                bail!("too many partitions: {}", partitions.len());
+            }
+
+            let total_sectors =
+                (next_lba + gpt::backup_sectors()).next_multiple_of(gpt::ALIGNMENT_SECTORS);
+
+            let byte_range = |partition: &gpt::GptPartition| {
+                (
+                    partition.first_lba * gpt::SECTOR_SIZE,
+                    (partition.last_lba + 1) * gpt::SECTOR_SIZE,
+                )
+            };
+
+            Ok(DiskLayout {
+                total_bytes: total_sectors * gpt::SECTOR_SIZE,
+                fat_range: byte_range(&partitions[0]),
+                data_ranges: partitions[1..].iter().map(byte_range).collect(),
+                partitions,
+            })
+        }
+    }
+}
+
+// smallest volume fatfs will format as FAT32 (>= 65525 clusters of 512 bytes)
+const FAT32_MIN_MIB: u64 = 33;
+
+// FAT32 layout as chosen by fatfs::format_volume: 8 reserved sectors, 2 FATs.
+struct Fat32Geometry {
+    cluster_bytes: u64,
+    data_bytes: u64,
+}
+
+fn fat32_geometry(volume_bytes: u64) -> Fat32Geometry {
+    let sector_bytes = 512;
+
+    let cluster_bytes = if volume_bytes <= 260 * MIB {
+        512
+    } else if volume_bytes <= 8 * 1024 * MIB {
+        4 * 1024
+    } else {
+        (volume_bytes.next_power_of_two() / (2 * 1024 * MIB) * 1024).min(32 * 1024)
+    };
+
+    let total_sectors = volume_bytes / sector_bytes;
+    let sectors_per_cluster = cluster_bytes / sector_bytes;
+
+    // slightly overestimates the FAT, which keeps the estimate conservative
+    let sectors_per_fat = (total_sectors / sectors_per_cluster + 2)
+        .saturating_mul(4)
+        .div_ceil(sector_bytes);
+    let data_sectors = total_sectors.saturating_sub(8 + 2 * sectors_per_fat);
+
+    Fat32Geometry {
+        cluster_bytes,
+        data_bytes: data_sectors / sectors_per_cluster * cluster_bytes,
+    }
+}
+
+// Bytes the entries occupy on a volume with the given cluster size:
+// file data rounded up to clusters, plus the directories holding the
+// short + long name entries (every directory is at least one cluster).
+fn payload_bytes(entries: &[EspEntry], file_sizes: &[u64], cluster_bytes: u64) -> Result<u64> {
+    let mut bytes = 0;
+    let mut dir_entry_bytes: BTreeMap<Vec<&str>, u64> = Default::default();
+
+    for (entry, file_size) in entries.iter().zip(file_sizes) {
+        let (dir_names, file_name) = split_image_path(&entry.image_path)?;
+
+        bytes += file_size.next_multiple_of(cluster_bytes);
+
+        // every prefix of the path is a directory holding the next component
+        for depth in 0..=dir_names.len() {
+            let name = dir_names.get(depth).copied().unwrap_or(file_name);
+            let name_entries = 1 + (name.encode_utf16().count() as u64).div_ceil(13);
+            *dir_entry_bytes
+                .entry(dir_names[..depth].to_vec())
+                .or_default() += name_entries * 32;
+        }
+    }
+
+    for entry_bytes in dir_entry_bytes.values() {
+        // "." and ".." in every non-root directory
+        bytes += (entry_bytes + 64).next_multiple_of(cluster_bytes);
+    }
+
+    Ok(bytes)
+}
+
+fn host_file_sizes(entries: &[EspEntry]) -> Result<Vec<u64>> {
+    entries
+        .iter()
+        .map(|entry| {
+            std::fs::metadata(entry.host_path.as_std_path())
+                .map(|metadata| metadata.len())
+                .with_context(|| format!("stat {} <- {}", entry.image_path, entry.host_path))
+        })
+        .collect()
 }
 
-pub fn build_fat_img(args: &BuildImgArgs) -> Result<()> {
+// Smallest FAT32-legal volume that holds the entries plus `slack_mib`.
+fn required_fat_size_mib(entries: &[EspEntry], file_sizes: &[u64], slack_mib: u64) -> Result<u64> {
+    let payload = payload_bytes(entries, file_sizes, 512)?;
+    let mut size_mib = FAT32_MIN_MIB.max((payload + slack_mib * MIB).div_ceil(MIB));
+
+    loop {
+        let geometry = fat32_geometry(size_mib * MIB);
+        let needed = payload_bytes(entries, file_sizes, geometry.cluster_bytes)? + slack_mib * MIB;
+        if geometry.data_bytes >= needed {
+            return Ok(size_mib);
+        }
+
+        // don't step past a larger cluster size: its smaller FATs may fit sooner
+        let next_mib = size_mib + (needed - geometry.data_bytes).div_ceil(MIB);
+        size_mib = (size_mib + 1..next_mib)
+            .find(|&mib| fat32_geometry(mib * MIB).cluster_bytes != geometry.cluster_bytes)
+            .unwrap_or(next_mib);
+    }
+}
+
+fn choose_fat_size_mib(
+    args: &BuildImgArgs,
+    entries: &[EspEntry],
+    file_sizes: &[u64],
+) -> Result<u64> {
+    let required_mib = required_fat_size_mib(entries, file_sizes, 0)?;
+
+    match args.image_size_mib {
+        Some(size_mib) => {
+            if size_mib < required_mib {
+                let files: u64 = file_sizes.iter().sum();
+                bail!(
+                    "--image-size {} MiB is too small: the packed files ({} bytes in {} entries) need a FAT32 volume of at least {} MiB",
+                    size_mib,
+                    files,
+                    entries.len(),
+                    required_mib
+                );
+            }
+            Ok(size_mib)
+        }
+        None => required_fat_size_mib(entries, file_sizes, args.slack_mib),
+    }
+}
+
+// Built-in entries + the ESP layout file, checked for conflicts and missing sources.
+fn collect_entries(args: &BuildImgArgs) -> Result<Vec<EspEntry>> {
+    let mut entries = args.entries.to_vec();
+
+    if let Some(esp_layout_path) = args.esp_layout_path {
+        let layout_entries = esp_layout::load_esp_layout(esp_layout_path)?;
+        if args.verbose {
+            eprintln!(
+                "[img] {} entries from ESP layout {}",
+                layout_entries.len(),
+                esp_layout_path
+            );
+        }
+        entries.extend(layout_entries);
+    }
+
+    let mut errors = Vec::new();
+
+    // FAT names are case-insensitive
+    let mut files: BTreeMap<String, &EspEntry> = BTreeMap::new();
+    let mut dirs: BTreeMap<String, &EspEntry> = BTreeMap::new();
+
+    for entry in &entries {
+        let (dir_names, _) = match split_image_path(&entry.image_path) {
+            Ok(split) => split,
+            Err(error) => {
+                errors.push(format!(
+                    "{} <- {}: {}",
+                    entry.image_path, entry.host_path, error
+                ));
+                continue;
+            }
+        };
+
+        let key = entry.image_path.to_uppercase();
+        if let Some(existing) = files.insert(key, entry) {
+            errors.push(format!(
+                "{} is provided twice: {} and {}",
+                entry.image_path, existing.host_path, entry.host_path
+            ));
+        }
+
+        for depth in 1..=dir_names.len() {
+            let dir = format!("/{}", dir_names[..depth].join("/"));
+            dirs.entry(dir.to_uppercase()).or_insert(entry);
+        }
+
+        // in dry-run the built-in sources usually do not exist yet
+        if !args.dry_run && !entry.host_path.is_file() {
+            errors.push(format!(
+                "{}: source is missing or not a file: {}",
+                entry.image_path, entry.host_path
+            ));
+        }
+    }
+
+    for (key, entry) in &files {
+        if let Some(child) = dirs.get(key) {
+            errors.push(format!(
+                "{} is both a file ({}) and a directory (holding {})",
+                entry.image_path, entry.host_path, child.image_path
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("invalid image contents:\n  {}", errors.join("\n  "));
+    }
+
+    Ok(entries)
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageArtifacts {
+    pub disk_image: Artifact,
+}
+
+pub fn build_fat_img(args: &BuildImgArgs) -> Result<ImageArtifacts> {
+    let entries = collect_entries(args)?;
+
+    let parent = args.img_path.parent().context("img_path has no parent")?;
+    let stamp = Stamp::new(parent, "image");
+
+    let fingerprint = if args.dry_run {
+        String::new()
+    } else {
+        let fingerprint = image_fingerprint(args, &entries)?;
+        if !args.force && stamp.is_up_to_date(&fingerprint, &[args.img_path]) {
+            eprintln!("[img] up to date: {}", args.img_path);
+            return Ok(image_artifacts(args.img_path));
+        }
+        stamp.invalidate();
+        fingerprint
+    };
+
+    let fat_size_mib = if args.dry_run {
+        args.image_size_mib
+            .unwrap_or(FAT32_MIN_MIB.max(args.slack_mib))
+    } else {
+        let file_sizes = host_file_sizes(&entries)?;
+        choose_fat_size_mib(args, &entries, &file_sizes)?
+    };
+
+    let layout = plan_layout(args, fat_size_mib)?;
+
+    for data_partition in args.data_partitions {
+        // like the ESP sources, raw images may not exist yet in dry-run
+        if !args.dry_run
+            && let Some(raw_image_path) = &data_partition.raw_image_path
+        {
+            let size = std::fs::metadata(raw_image_path)
+                .with_context(|| format!("stat raw image: {}", raw_image_path))?
+                .len();
+            if size > data_partition.size_mib * MIB {
+                bail!(
+                    "raw image {} ({} bytes) does not fit data partition '{}' ({} MiB)",
+                    raw_image_path,
+                    size,
+                    data_partition.name,
+                    data_partition.size_mib
+                );
+            }
+        }
+    }
+
     if args.dry_run {
-        eprintln!("[dry-run] create img: {}", args.img_path);
-        eprintln!(
-            "[dry-run]   /EFI/BOOT/BOOTX64.EFI <- {}",
-            args.bootx64_efi_source_path
-        );
         eprintln!(
-            "[dry-run]   /kernel/init.elf      <- {}",
-            args.init_elf_source_path
+            "[dry-run] create img: {} ({:?}, {} bytes)",
+            args.img_path, args.layout, layout.total_bytes
         );
-        eprintln!(
-            "[dry-run]   /kernel/kernel.elf    <- {}",
-            args.kernel_elf_source_path
-        );
-        return Ok(());
+        for partition in &layout.partitions {
+            eprintln!(
+                "[dry-run]   partition '{}': LBA {}..={}",
+                partition.name, partition.first_lba, partition.last_lba
+            );
+        }
+        let width = entries
+            .iter()
+            .map(|entry| entry.image_path.len())
+            .max()
+            .unwrap_or(0);
+        for entry in &entries {
+            eprintln!(
+                "[dry-run]   {:<width$} <- {}",
+                entry.image_path,
+                entry.host_path,
+                width = width
+            );
+        }
+        return Ok(image_artifacts(args.img_path));
     }
 
-    let parent = args.img_path.parent().context("img_path has no parent")?;
     std::fs::create_dir_all(parent.as_std_path())
         .with_context(|| format!("create img parent dir: {}", parent))?;
 
-    let image_size_bytes = args.image_size_mib * 1024 * 1024;
-
     // Create & size
     {
         let file = File::create(args.img_path.as_std_path())
             .with_context(|| format!("create img file: {}", args.img_path))?;
-        file.set_len(image_size_bytes)
-            .with_context(|| format!("set img size: {} bytes", image_size_bytes))?;
+        file.set_len(layout.total_bytes)
+            .with_context(|| format!("set img size: {} bytes", layout.total_bytes))?;
+    }
+
+    // Partition table
+    if !layout.partitions.is_empty() {
+        let mut file = open_img(args.img_path)?;
+
+        gpt::write_gpt(
+            &mut file,
+            layout.total_bytes / gpt::SECTOR_SIZE,
+            &layout.partitions,
+        )
+        .context("write GPT")?;
+
+        for (data_partition, (start, _)) in args.data_partitions.iter().zip(&layout.data_ranges) {
+            if let Some(raw_image_path) = &data_partition.raw_image_path {
+                let mut raw_image = File::open(raw_image_path.as_std_path())
+                    .with_context(|| format!("open raw image: {}", raw_image_path))?;
+                file.seek(SeekFrom::Start(*start))?;
+                std::io::copy(&mut raw_image, &mut file)
+                    .with_context(|| format!("copy raw image into '{}'", data_partition.name))?;
+            }
+        }
     }
 
     // Format FAT32
     {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(args.img_path.as_std_path())
-            .with_context(|| format!("open img for format: {}", args.img_path))?;
-
-        let stream = BufStream::new(file);
+        let stream = open_fat_stream(args.img_path, layout.fat_range)?;
 
         let format_options = fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32);
 
@@ -65,43 +458,133 @@ pub fn build_fat_img(args: &BuildImgArgs) -> Result<()> {
 
     // Open FS and write files
     {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(args.img_path.as_std_path())
-            .with_context(|| format!("open img for fs: {}", args.img_path))?;
-
-        let stream = BufStream::new(file);
+        let stream = open_fat_stream(args.img_path, layout.fat_range)?;
 
         let fs = fatfs::FileSystem::new(stream, fatfs::FsOptions::new())
             .context("open FAT filesystem")?;
 
-        {
-            let root = fs.root_dir();
+        for entry in &entries {
+            let (dir_names, file_name) = split_image_path(&entry.image_path)?;
 
-            let efi_dir = ensure_dir(&root, "EFI")?;
-            let boot_dir = ensure_dir(&efi_dir, "BOOT")?;
-            let kernel_dir = ensure_dir(&root, "kernel")?;
+            let mut dir = fs.root_dir();
+            for dir_name in dir_names {
+                dir = ensure_dir(&dir, dir_name)?;
+            }
 
-            write_file_from_host(&boot_dir, "BOOTX64.EFI", args.bootx64_efi_source_path)?;
-            write_file_from_host(&kernel_dir, "init.elf", args.init_elf_source_path)?;
-            write_file_from_host(&kernel_dir, "kernel.elf", args.kernel_elf_source_path)?;
+            write_file_from_host(&dir, file_name, &entry.host_path)
+                .with_context(|| format!("write {}", entry.image_path))?;
+        }
+
+        if args.verbose {
+            let stats = fs.stats().context("FAT filesystem stats")?;
+            let cluster_bytes = stats.cluster_size() as u64;
+            let total = stats.total_clusters() as u64 * cluster_bytes;
+            let free = stats.free_clusters() as u64 * cluster_bytes;
+            eprintln!(
+                "[img] FAT32 volume: {} MiB ({}), used: {} KiB, free: {} KiB",
+                fat_size_mib,
+                if args.image_size_mib.is_some() {
+                    "explicit"
+                } else {
+                    "auto"
+                },
+                (total - free) / 1024,
+                free / 1024
+            );
         }
 
         fs.unmount().context("unmount FAT filesystem")?;
     }
 
+    stamp.record(&fingerprint, &[args.img_path])?;
+
     if args.verbose {
         eprintln!("[img] created: {}", args.img_path);
     }
 
-    Ok(())
+    Ok(image_artifacts(args.img_path))
+}
+
+// everything the image step writes, for `clean`
+pub fn image_outputs(img_path: &Utf8Path) -> Vec<Utf8PathBuf> {
+    let mut outputs = vec![img_path.to_path_buf()];
+    if let Some(parent) = img_path.parent() {
+        outputs.push(Stamp::new(parent, "image").path().to_path_buf());
+    }
+    outputs
+}
+
+fn image_artifacts(img_path: &Utf8Path) -> ImageArtifacts {
+    ImageArtifacts {
+        disk_image: Artifact::new(
+            ArtifactKind::DiskImage,
+            img_path,
+            "image",
+            Provenance::default(),
+        ),
+    }
+}
+
+// Exact contents of everything packed, so the image is rebuilt only when an
+// artifact's hash changed (a rebuilt but identical kernel keeps the image).
+fn image_fingerprint(args: &BuildImgArgs, entries: &[EspEntry]) -> Result<String> {
+    let mut fingerprint = Fingerprint::new("image");
+    fingerprint.add_str("layout", &format!("{:?}", args.layout));
+    fingerprint.add_str("image_size_mib", &format!("{:?}", args.image_size_mib));
+    fingerprint.add_str("slack_mib", &args.slack_mib.to_string());
+
+    for data_partition in args.data_partitions {
+        fingerprint.add_str(&data_partition.name, &data_partition.size_mib.to_string());
+        if let Some(raw_image_path) = &data_partition.raw_image_path {
+            fingerprint.add_file_contents(raw_image_path)?;
+        }
+    }
+
+    for entry in entries {
+        fingerprint.add_str("entry", &entry.image_path);
+        fingerprint.add_file_contents(&entry.host_path)?;
+    }
+
+    Ok(fingerprint.finish())
+}
+
+fn open_img(img_path: &Utf8Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(img_path.as_std_path())
+        .with_context(|| format!("open img: {}", img_path))
+}
+
+fn open_fat_stream(img_path: &Utf8Path, (start, end): (u64, u64)) -> Result<FatStream> {
+    let slice = StreamSlice::new(open_img(img_path)?, start, end)
+        .with_context(|| format!("slice img: {}..{}", start, end))?;
+
+    Ok(BufStream::new(slice))
+}
+
+// "/EFI/BOOT/BOOTX64.EFI" -> (["EFI", "BOOT"], "BOOTX64.EFI")
+fn split_image_path(image_path: &str) -> Result<(Vec<&str>, &str)> {
+    let Some(relative) = image_path.strip_prefix('/') else {
+        bail!("image path must be absolute: {}", image_path);
+    };
+
+    let mut components: Vec<&str> = relative.split('/').collect();
+    if components
+        .iter()
+        .any(|component| component.is_empty() || *component == "." || *component == "..")
+    {
+        bail!("invalid image path: {}", image_path);
+    }
+
+    let file_name = components.pop().unwrap_or_default();
+    Ok((components, file_name))
 }
 
 fn ensure_dir<'a>(
-    parent: &'a fatfs::Dir<BufStream<File>>,
+    parent: &fatfs::Dir<'a, FatStream>,
     name: &str,
-) -> Result<fatfs::Dir<'a, BufStream<File>>> {
+) -> Result<fatfs::Dir<'a, FatStream>> {
     if let Ok(dir) = parent.open_dir(name) {
         return Ok(dir);
     }
@@ -115,7 +598,7 @@ fn ensure_dir<'a>(
 }
 
 fn write_file_from_host(
-    dir: &fatfs::Dir<BufStream<File>>,
+    dir: &fatfs::Dir<FatStream>,
     file_name: &str,
     host_path: &Utf8Path,
 ) -> Result<()> {
@@ -144,3 +627,258 @@ fn write_file_from_host(
     fat_file.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::scratch::ScratchDir;
+
+    fn gpt_args(data_partitions: &[DataPartition]) -> BuildImgArgs<'_> {
+        BuildImgArgs {
+            img_path: Utf8Path::new("/nonexistent/disk.img"),
+            entries: &[],
+            esp_layout_path: None,
+            layout: ImageLayout::Gpt,
+            data_partitions,
+            image_size_mib: None,
+            slack_mib: 0,
+            verbose: false,
+            dry_run: true,
+            force: false,
+        }
+    }
+
+    #[test]
+    fn gpt_partitions_are_mib_aligned() {
+        let data_partitions = [
+            DataPartition {
+                name: "one".to_string(),
+                size_mib: 3,
+                raw_image_path: None,
+            },
+            DataPartition {
+                name: "two".to_string(),
+                size_mib: 1,
+                raw_image_path: None,
+            },
+        ];
+        let layout = plan_layout(&gpt_args(&data_partitions), 33).unwrap();
+
+        let sizes_mib = [33, 3, 1];
+        assert_eq!(layout.partitions.len(), sizes_mib.len());
+        let mut previous_last_lba = gpt::ALIGNMENT_SECTORS - 1;
+        for (partition, size_mib) in layout.partitions.iter().zip(sizes_mib) {
+            assert_eq!(partition.first_lba % gpt::ALIGNMENT_SECTORS, 0);
+            assert_eq!(partition.first_lba, previous_last_lba + 1);
+            assert_eq!(
+                (partition.last_lba + 1 - partition.first_lba) * gpt::SECTOR_SIZE,
+                size_mib * MIB
+            );
+            previous_last_lba = partition.last_lba;
+        }
+
+        // the backup GPT fits after the last partition
+        let total_sectors = layout.total_bytes / gpt::SECTOR_SIZE;
+        assert_eq!(total_sectors % gpt::ALIGNMENT_SECTORS, 0);
+        assert!(previous_last_lba < total_sectors - 1 - gpt::backup_sectors());
+        assert_eq!(layout.fat_range, (MIB, 34 * MIB));
+    }
+
+    // Formats a sparse volume the way build_fat_img does and reads back what
+    // fatfs chose.
+    fn formatted_stats(size_mib: u64) -> fatfs::FileSystemStats {
+        let scratch = ScratchDir::new(&format!("fat-{}", size_mib));
+        let path = scratch.join("fat.img");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(size_mib * MIB).unwrap();
+
+        let mut stream = BufStream::new(file);
+        let format_options = fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32);
+        fatfs::format_volume(&mut stream, format_options).unwrap();
+        fatfs::FileSystem::new(stream, fatfs::FsOptions::new())
+            .unwrap()
+            .stats()
+            .unwrap()
+    }
+
+    #[test]
+    fn cluster_size_steps_up_after_260_mib() {
+        assert_eq!(fat32_geometry(FAT32_MIN_MIB * MIB).cluster_bytes, 512);
+        assert_eq!(fat32_geometry(260 * MIB).cluster_bytes, 512);
+        assert_eq!(fat32_geometry(261 * MIB).cluster_bytes, 4 * 1024);
+        assert_eq!(fat32_geometry(8 * 1024 * MIB).cluster_bytes, 4 * 1024);
+        assert_eq!(fat32_geometry(8 * 1024 * MIB + MIB).cluster_bytes, 8 * 1024);
+    }
+
+    #[test]
+    fn geometry_matches_fatfs_around_260_mib() {
+        for size_mib in [FAT32_MIN_MIB, 259, 260, 261, 262] {
+            let geometry = fat32_geometry(size_mib * MIB);
+            let stats = formatted_stats(size_mib);
+
+            assert_eq!(
+                geometry.cluster_bytes,
+                stats.cluster_size() as u64,
+                "{} MiB",
+                size_mib
+            );
+            // the estimate may only err on the small side; the root
+            // directory takes one cluster
+            let usable = (stats.free_clusters() as u64 + 1) * stats.cluster_size() as u64;
+            assert!(geometry.data_bytes <= usable, "{} MiB", size_mib);
+            assert!(usable - geometry.data_bytes < MIB, "{} MiB", size_mib);
+        }
+    }
+
+    fn fits(entries: &[EspEntry], file_sizes: &[u64], size_mib: u64) -> bool {
+        let geometry = fat32_geometry(size_mib * MIB);
+        geometry.data_bytes >= payload_bytes(entries, file_sizes, geometry.cluster_bytes).unwrap()
+    }
+
+    #[test]
+    fn required_size_is_the_smallest_that_fits() {
+        let entries = [EspEntry::new("/EFI/BOOT/BOOTX64.EFI", "/unused")];
+
+        // below, at and above the point where 512 byte clusters stop
+        for file_mib in [200, 255, 256, 257, 300] {
+            let file_sizes = [file_mib * MIB + 1];
+            let size_mib = required_fat_size_mib(&entries, &file_sizes, 0).unwrap();
+
+            assert!(fits(&entries, &file_sizes, size_mib), "{} MiB", file_mib);
+            assert!(
+                !fits(&entries, &file_sizes, size_mib - 1),
+                "{} MiB",
+                file_mib
+            );
+        }
+
+        // a file that only fits once the FATs shrink to 4 KiB clusters
+        let file_sizes = [257 * MIB];
+        assert!(!fits(&entries, &file_sizes, 260));
+        assert_eq!(
+            required_fat_size_mib(&entries, &file_sizes, 0).unwrap(),
+            261
+        );
+    }
+
+    #[test]
+    fn small_payloads_get_the_fat32_minimum() {
+        let entries = [EspEntry::new("/EFI/BOOT/BOOTX64.EFI", "/unused")];
+        assert_eq!(
+            required_fat_size_mib(&entries, &[1], 0).unwrap(),
+            FAT32_MIN_MIB
+        );
+
+        // slack is free space on top of the payload
+        let size_mib = required_fat_size_mib(&entries, &[1], 40).unwrap();
+        let geometry = fat32_geometry(size_mib * MIB);
+        let payload = payload_bytes(&entries, &[1], geometry.cluster_bytes).unwrap();
+        assert!(geometry.data_bytes >= payload + 40 * MIB);
+        assert!(fat32_geometry((size_mib - 1) * MIB).data_bytes < payload + 40 * MIB);
+    }
+
+    fn collect_error(entries: &[EspEntry], dry_run: bool) -> String {
+        let args = BuildImgArgs {
+            entries,
+            dry_run,
+            ..gpt_args(&[])
+        };
+        match collect_entries(&args) {
+            Ok(_) => panic!("entries accepted"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn duplicate_image_paths_conflict_case_insensitively() {
+        let error = collect_error(
+            &[
+                EspEntry::new("/EFI/BOOT/BOOTX64.EFI", "/a/loader.efi"),
+                EspEntry::new("/efi/boot/bootx64.efi", "/b/loader.efi"),
+            ],
+            true,
+        );
+        assert!(
+            error.contains(
+                "/efi/boot/bootx64.efi is provided twice: /a/loader.efi and /b/loader.efi"
+            )
+        );
+    }
+
+    #[test]
+    fn file_and_directory_conflict() {
+        let error = collect_error(
+            &[
+                EspEntry::new("/boot", "/a/boot"),
+                EspEntry::new("/BOOT/kernel.elf", "/a/kernel.elf"),
+            ],
+            true,
+        );
+        assert!(
+            error.contains(
+                "/boot is both a file (/a/boot) and a directory (holding /BOOT/kernel.elf)"
+            )
+        );
+    }
+
+    #[test]
+    fn bad_paths_and_missing_sources_are_reported_together() {
+        let error = collect_error(
+            &[
+                EspEntry::new("relative.txt", "/a/relative.txt"),
+                EspEntry::new("/a/../b", "/a/b"),
+                EspEntry::new("/missing.txt", "/nonexistent/missing.txt"),
+            ],
+            false,
+        );
+        assert!(error.contains("image path must be absolute: relative.txt"));
+        assert!(error.contains("invalid image path: /a/../b"));
+        assert!(error.contains("/missing.txt: source is missing or not a file"));
+
+        // sources are not checked in dry-run
+        let args = BuildImgArgs {
+            entries: &[EspEntry::new("/missing.txt", "/nonexistent/missing.txt")],
+            ..gpt_args(&[])
+        };
+        assert_eq!(collect_entries(&args).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dry_run_does_not_need_raw_images() {
+        let data_partitions = [DataPartition {
+            name: "data".to_string(),
+            size_mib: 1,
+            raw_image_path: Some("/nonexistent/data.img".into()),
+        }];
+        let args = gpt_args(&data_partitions);
+        assert!(build_fat_img(&args).is_ok());
+
+        let args = BuildImgArgs {
+            dry_run: false,
+            ..args
+        };
+        let Err(error) = build_fat_img(&args) else {
+            panic!("missing raw image accepted");
+        };
+        assert!(format!("{:#}", error).contains("stat raw image: /nonexistent/data.img"));
+    }
+
+    #[test]
+    fn zero_sized_data_partition_is_rejected() {
+        let data_partitions = [DataPartition {
+            name: "empty".to_string(),
+            size_mib: 0,
+            raw_image_path: None,
+        }];
+        let Err(error) = plan_layout(&gpt_args(&data_partitions), 33) else {
+            panic!("zero-sized partition accepted");
+        };
+        assert!(error.to_string().contains("'empty' has zero size"));
+    }
+}
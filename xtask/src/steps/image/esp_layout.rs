@@ -0,0 +1,264 @@
+use super::EspEntry;
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// [files]
+// "/config/init.toml" = "config/init.toml"   # single file
+// "/fonts/"           = "assets/**/*.psf"    # glob, keeps the path below the first wildcard
+// "/test/"            = "testdata"           # directory tree
+//
+// Host paths are relative to the layout file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EspLayoutFile {
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+}
+
+pub fn load_esp_layout(layout_path: &Utf8Path) -> Result<Vec<EspEntry>> {
+    let text = std::fs::read_to_string(layout_path)
+        .with_context(|| format!("read ESP layout: {}", layout_path))?;
+    let layout: EspLayoutFile =
+        toml::from_str(&text).with_context(|| format!("parse ESP layout: {}", layout_path))?;
+
+    let base_dir = layout_path.parent().unwrap_or(Utf8Path::new("."));
+
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+
+    for (image_path, host_pattern) in &layout.files {
+        if let Err(error) = expand(base_dir, image_path, host_pattern, &mut entries) {
+            errors.push(format!(
+                "{} = \"{}\": {:#}",
+                image_path, host_pattern, error
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "invalid ESP layout {}:\n  {}",
+            layout_path,
+            errors.join("\n  ")
+        );
+    }
+
+    Ok(entries)
+}
+
+fn expand(
+    base_dir: &Utf8Path,
+    image_path: &str,
+    host_pattern: &str,
+    entries: &mut Vec<EspEntry>,
+) -> Result<()> {
+    // only the pattern as written may hold wildcards; the base dir is literal
+    if is_glob(host_pattern) {
+        let Some(image_dir) = image_path.strip_suffix('/') else {
+            bail!("a glob source needs a directory destination ending in '/'");
+        };
+
+        let prefix = base_dir.join(glob_prefix(Utf8Path::new(host_pattern)));
+        let host_pattern = if Utf8Path::new(host_pattern).is_absolute() || base_dir == "" {
+            host_pattern.to_string()
+        } else {
+            format!(
+                "{}/{}",
+                glob::Pattern::escape(base_dir.as_str()),
+                host_pattern
+            )
+        };
+
+        let mut matched = false;
+        for path in glob::glob(host_pattern.as_str()).context("invalid glob")? {
+            let path = path.context("read glob match")?;
+            let path = Utf8PathBuf::from_path_buf(path)
+                .map_err(|path| anyhow::anyhow!("non-utf8 path: {}", path.display()))?;
+
+            if path.is_dir() {
+                continue;
+            }
+
+            let relative = path.strip_prefix(&prefix).unwrap_or(&path);
+            entries.push(EspEntry::new(format!("{}/{}", image_dir, relative), path));
+            matched = true;
+        }
+
+        if !matched {
+            bail!("glob matched no files");
+        }
+
+        return Ok(());
+    }
+
+    let host_pattern = base_dir.join(host_pattern);
+    if !host_pattern.exists() {
+        bail!("source does not exist: {}", host_pattern);
+    }
+
+    if host_pattern.is_dir() {
+        let image_dir = image_path.trim_end_matches('/');
+        return expand_dir(&host_pattern, image_dir, entries);
+    }
+
+    let image_path = match image_path.strip_suffix('/') {
+        Some(image_dir) => format!(
+            "{}/{}",
+            image_dir,
+            host_pattern
+                .file_name()
+                .context("source has no file name")?
+        ),
+        None => image_path.to_string(),
+    };
+
+    entries.push(EspEntry::new(image_path, host_pattern));
+    Ok(())
+}
+
+fn expand_dir(host_dir: &Utf8Path, image_dir: &str, entries: &mut Vec<EspEntry>) -> Result<()> {
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir(host_dir).with_context(|| format!("read_dir: {}", host_dir))? {
+        let entry = entry?;
+        let file_name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("non-utf8 file name: {:?}", name))?;
+        children.push(file_name);
+    }
+    children.sort();
+
+    for file_name in children {
+        let host_path = host_dir.join(&file_name);
+        let image_path = format!("{}/{}", image_dir, file_name);
+
+        if host_path.is_dir() {
+            expand_dir(&host_path, &image_path, entries)?;
+        } else {
+            entries.push(EspEntry::new(image_path, host_path));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+// Directory part of the pattern before the first wildcard component.
+fn glob_prefix(pattern: &Utf8Path) -> Utf8PathBuf {
+    let mut prefix = Utf8PathBuf::new();
+    for component in pattern.components() {
+        if is_glob(component.as_str()) {
+            break;
+        }
+        prefix.push(component);
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::scratch::ScratchDir;
+
+    // Lay out `files` (each holding its own name) in a scratch dir whose name
+    // has glob metacharacters in it.
+    fn scratch_with(name: &str, files: &[&str]) -> ScratchDir {
+        let scratch = ScratchDir::new(&format!("[esp] {}", name));
+        for file in files {
+            scratch.write(file, file);
+        }
+        scratch
+    }
+
+    fn image_paths(entries: &[EspEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.image_path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn expands_files_dirs_and_globs() {
+        let scratch = scratch_with(
+            "expand",
+            &[
+                "config/init.toml",
+                "assets/a/one.psf",
+                "assets/b/two.psf",
+                "assets/readme.txt",
+                "testdata/x.bin",
+                "testdata/sub/y.bin",
+            ],
+        );
+        let layout_path = scratch.write(
+            "esp.toml",
+            r#"
+            [files]
+            "/config/init.toml" = "config/init.toml"
+            "/etc/" = "config/init.toml"
+            "/fonts/" = "assets/**/*.psf"
+            "/test/" = "testdata"
+            "#,
+        );
+
+        let entries = load_esp_layout(&layout_path).unwrap();
+        assert_eq!(
+            image_paths(&entries),
+            [
+                "/config/init.toml",
+                "/etc/init.toml",
+                "/fonts/a/one.psf",
+                "/fonts/b/two.psf",
+                "/test/sub/y.bin",
+                "/test/x.bin",
+            ]
+        );
+        assert_eq!(entries[2].host_path, scratch.join("assets/a/one.psf"));
+    }
+
+    #[test]
+    fn base_dir_is_not_a_glob() {
+        // the scratch dir name holds "[esp]", which must not make a plain
+        // file source a glob or be read as a character class
+        let scratch = scratch_with("literal", &["kernel.elf"]);
+
+        let mut entries = Vec::new();
+        expand(scratch.path(), "/kernel.elf", "kernel.elf", &mut entries).unwrap();
+        assert_eq!(image_paths(&entries), ["/kernel.elf"]);
+
+        let mut entries = Vec::new();
+        expand(scratch.path(), "/boot/", "*.elf", &mut entries).unwrap();
+        assert_eq!(image_paths(&entries), ["/boot/kernel.elf"]);
+    }
+
+    #[test]
+    fn reports_every_bad_entry() {
+        let scratch = scratch_with("errors", &["one.psf"]);
+        let layout_path = scratch.write(
+            "esp.toml",
+            r#"
+            [files]
+            "/fonts" = "*.psf"
+            "/missing.txt" = "missing.txt"
+            "/none/" = "*.ttf"
+            "#,
+        );
+
+        let error = format!("{:#}", load_esp_layout(&layout_path).unwrap_err());
+        assert!(error.contains(r#"/fonts = "*.psf": a glob source needs a directory destination"#));
+        assert!(error.contains(r#"/missing.txt = "missing.txt": source does not exist"#));
+        assert!(error.contains(r#"/none/ = "*.ttf": glob matched no files"#));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let scratch = scratch_with("unknown", &[]);
+        let layout_path = scratch.write("esp.toml", "[file]\n\"/a\" = \"a\"\n");
+        assert!(load_esp_layout(&layout_path).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use std::io::{Seek, SeekFrom, Write};
+use uuid::Uuid;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+// partitions start on 1 MiB boundaries
+pub const ALIGNMENT_SECTORS: u64 = 2048;
+
+pub const ESP_TYPE_GUID: Uuid = Uuid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+pub const BASIC_DATA_TYPE_GUID: Uuid = Uuid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7);
+
+const ENTRY_COUNT: u64 = 128;
+const ENTRY_SIZE: u64 = 128;
+const ENTRY_ARRAY_SECTORS: u64 = ENTRY_COUNT * ENTRY_SIZE / SECTOR_SIZE;
+const HEADER_SIZE: usize = 92;
+
+#[derive(Clone, Debug)]
+pub struct GptPartition {
+    pub type_guid: Uuid,
+    pub unique_guid: Uuid,
+    pub name: String,
+    pub first_lba: u64,
+    // inclusive
+    pub last_lba: u64,
+}
+
+// Sectors reserved at the end of the disk for the backup entry array + header.
+pub const fn backup_sectors() -> u64 {
+    ENTRY_ARRAY_SECTORS + 1
+}
+
+pub fn write_gpt<T: Write + Seek>(
+    disk: &mut T,
+    total_sectors: u64,
+    partitions: &[GptPartition],
+) -> std::io::Result<()> {
+    let disk_guid = Uuid::new_v4();
+
+    let last_lba = total_sectors - 1;
+    let first_usable_lba = 2 + ENTRY_ARRAY_SECTORS;
+    let last_usable_lba = last_lba - backup_sectors();
+
+    let entries = encode_entries(partitions);
+    let entries_crc = crc32fast::hash(&entries);
+
+    // protective MBR
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&protective_mbr(total_sectors))?;
+
+    // primary header (LBA 1) + entries (LBA 2..)
+    let primary = encode_header(&HeaderFields {
+        my_lba: 1,
+        alternate_lba: last_lba,
+        first_usable_lba,
+        last_usable_lba,
+        disk_guid,
+        entries_lba: 2,
+        entries_crc,
+    });
+    disk.seek(SeekFrom::Start(SECTOR_SIZE))?;
+    disk.write_all(&primary)?;
+    disk.seek(SeekFrom::Start(2 * SECTOR_SIZE))?;
+    disk.write_all(&entries)?;
+
+    // backup entries + header (last LBA)
+    let backup_entries_lba = last_lba - ENTRY_ARRAY_SECTORS;
+    let backup = encode_header(&HeaderFields {
+        my_lba: last_lba,
+        alternate_lba: 1,
+        first_usable_lba,
+        last_usable_lba,
+        disk_guid,
+        entries_lba: backup_entries_lba,
+        entries_crc,
+    });
+    disk.seek(SeekFrom::Start(backup_entries_lba * SECTOR_SIZE))?;
+    disk.write_all(&entries)?;
+    disk.seek(SeekFrom::Start(last_lba * SECTOR_SIZE))?;
+    disk.write_all(&backup)?;
+
+    disk.flush()
+}
+
+fn protective_mbr(total_sectors: u64) -> [u8; SECTOR_SIZE as usize] {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+
+    let entry = &mut mbr[446..462];
+    entry[0] = 0x00; // not bootable
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]); // CHS of LBA 1
+    entry[4] = 0xEE; // GPT protective
+    entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let size = (total_sectors - 1).min(u32::MAX as u64) as u32;
+    entry[12..16].copy_from_slice(&size.to_le_bytes());
+
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    mbr
+}
+
+struct HeaderFields {
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Uuid,
+    entries_lba: u64,
+    entries_crc: u32,
+}
+
+fn encode_header(fields: &HeaderFields) -> [u8; SECTOR_SIZE as usize] {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+
+    let header = &mut sector[..HEADER_SIZE];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+    // 16..20: header crc, filled below
+    header[24..32].copy_from_slice(&fields.my_lba.to_le_bytes());
+    header[32..40].copy_from_slice(&fields.alternate_lba.to_le_bytes());
+    header[40..48].copy_from_slice(&fields.first_usable_lba.to_le_bytes());
+    header[48..56].copy_from_slice(&fields.last_usable_lba.to_le_bytes());
+    header[56..72].copy_from_slice(&fields.disk_guid.to_bytes_le());
+    header[72..80].copy_from_slice(&fields.entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+    header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&fields.entries_crc.to_le_bytes());
+
+    let header_crc = crc32fast::hash(header);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+    sector
+}
+
+fn encode_entries(partitions: &[GptPartition]) -> Vec<u8> {
+    let mut entries = vec![0u8; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+
+    for (partition, entry) in partitions
+        .iter()
+        .zip(entries.chunks_exact_mut(ENTRY_SIZE as usize))
+    {
+        entry[0..16].copy_from_slice(&partition.type_guid.to_bytes_le());
+        entry[16..32].copy_from_slice(&partition.unique_guid.to_bytes_le());
+        entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+        // 48..56: attributes (none)
+
+        // name: UTF-16LE, up to 36 code units
+        for (index, unit) in partition.name.encode_utf16().take(36).enumerate() {
+            let offset = 56 + index * 2;
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const TOTAL_SECTORS: u64 = 8 * 2048;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn sector(disk: &[u8], lba: u64) -> &[u8] {
+        let start = (lba * SECTOR_SIZE) as usize;
+        &disk[start..start + SECTOR_SIZE as usize]
+    }
+
+    fn test_partitions() -> Vec<GptPartition> {
+        vec![
+            GptPartition {
+                type_guid: ESP_TYPE_GUID,
+                unique_guid: Uuid::new_v4(),
+                name: "EFI System Partition".to_string(),
+                first_lba: ALIGNMENT_SECTORS,
+                last_lba: 3 * ALIGNMENT_SECTORS - 1,
+            },
+            GptPartition {
+                type_guid: BASIC_DATA_TYPE_GUID,
+                unique_guid: Uuid::new_v4(),
+                name: "data".to_string(),
+                first_lba: 3 * ALIGNMENT_SECTORS,
+                last_lba: 4 * ALIGNMENT_SECTORS - 1,
+            },
+        ]
+    }
+
+    fn write_test_disk(partitions: &[GptPartition]) -> Vec<u8> {
+        let mut disk = Cursor::new(vec![0u8; (TOTAL_SECTORS * SECTOR_SIZE) as usize]);
+        write_gpt(&mut disk, TOTAL_SECTORS, partitions).unwrap();
+        disk.into_inner()
+    }
+
+    // The header as written, and its CRC recomputed with the CRC field zeroed.
+    fn check_header_crc(header_sector: &[u8]) -> &[u8] {
+        let header = &header_sector[..HEADER_SIZE];
+        let mut zeroed = header.to_vec();
+        zeroed[16..20].fill(0);
+        assert_eq!(u32_at(header, 16), crc32fast::hash(&zeroed));
+        header
+    }
+
+    fn entries_at(disk: &[u8], lba: u64) -> &[u8] {
+        let start = (lba * SECTOR_SIZE) as usize;
+        &disk[start..start + (ENTRY_COUNT * ENTRY_SIZE) as usize]
+    }
+
+    #[test]
+    fn protective_mbr_covers_the_disk() {
+        let disk = write_test_disk(&test_partitions());
+        let mbr = sector(&disk, 0);
+
+        assert_eq!(&mbr[510..512], &[0x55, 0xAA]);
+        assert_eq!(mbr[446 + 4], 0xEE);
+        assert_eq!(u32_at(mbr, 446 + 8), 1);
+        assert_eq!(u32_at(mbr, 446 + 12) as u64, TOTAL_SECTORS - 1);
+    }
+
+    #[test]
+    fn primary_header_fields_and_crcs() {
+        let disk = write_test_disk(&test_partitions());
+        let header = check_header_crc(sector(&disk, 1));
+
+        assert_eq!(&header[0..8], b"EFI PART");
+        assert_eq!(u32_at(header, 12) as usize, HEADER_SIZE);
+        assert_eq!(u64_at(header, 24), 1);
+        assert_eq!(u64_at(header, 32), TOTAL_SECTORS - 1);
+        assert_eq!(u64_at(header, 40), 2 + ENTRY_ARRAY_SECTORS);
+        assert_eq!(u64_at(header, 48), TOTAL_SECTORS - 1 - backup_sectors());
+        assert_eq!(u64_at(header, 72), 2);
+        assert_eq!(u32_at(header, 80) as u64, ENTRY_COUNT);
+        assert_eq!(u32_at(header, 84) as u64, ENTRY_SIZE);
+        assert_eq!(u32_at(header, 88), crc32fast::hash(entries_at(&disk, 2)));
+    }
+
+    #[test]
+    fn backup_header_mirrors_the_primary() {
+        let disk = write_test_disk(&test_partitions());
+        let primary = check_header_crc(sector(&disk, 1));
+        let backup = check_header_crc(sector(&disk, TOTAL_SECTORS - 1));
+
+        let backup_entries_lba = TOTAL_SECTORS - 1 - ENTRY_ARRAY_SECTORS;
+        assert_eq!(u64_at(backup, 24), TOTAL_SECTORS - 1);
+        assert_eq!(u64_at(backup, 32), 1);
+        assert_eq!(u64_at(backup, 72), backup_entries_lba);
+        // backup entries sit right after the last usable LBA
+        assert_eq!(u64_at(backup, 48) + 1, backup_entries_lba);
+
+        // same disk and same entries, only the location fields differ
+        assert_eq!(&backup[40..72], &primary[40..72]);
+        assert_eq!(u32_at(backup, 88), u32_at(primary, 88));
+        assert_eq!(entries_at(&disk, backup_entries_lba), entries_at(&disk, 2));
+    }
+
+    #[test]
+    fn entries_record_the_partitions() {
+        let partitions = test_partitions();
+        let disk = write_test_disk(&partitions);
+        let entries = entries_at(&disk, 2);
+
+        for (partition, entry) in partitions
+            .iter()
+            .zip(entries.chunks_exact(ENTRY_SIZE as usize))
+        {
+            assert_eq!(&entry[0..16], &partition.type_guid.to_bytes_le());
+            assert_eq!(&entry[16..32], &partition.unique_guid.to_bytes_le());
+            assert_eq!(u64_at(entry, 32), partition.first_lba);
+            assert_eq!(u64_at(entry, 40), partition.last_lba);
+            assert_eq!(u64_at(entry, 32) % ALIGNMENT_SECTORS, 0);
+            assert_eq!((u64_at(entry, 40) + 1) % ALIGNMENT_SECTORS, 0);
+
+            let name: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            assert_eq!(String::from_utf16(&name).unwrap(), partition.name);
+        }
+
+        // unused entries stay zeroed
+        assert!(
+            entries[partitions.len() * ENTRY_SIZE as usize..]
+                .iter()
+                .all(|&byte| byte == 0)
+        );
+    }
+}
@@ -1,7 +1,11 @@
 use crate::cli::{Arch, Platform};
+use crate::steps::artifact::{Artifact, ArtifactKind, Provenance};
+use crate::steps::doctor::{check_cross_toolchain, check_submodule, require, toolchain_programs};
+use crate::steps::fingerprint::{Fingerprint, Stamp};
 use crate::steps::process::run_command;
+use crate::steps::target::{Component, build_key, out_base, target_desc, validate_supported};
 use anyhow::{Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use std::process::Command;
 
 #[derive(Clone, Debug)]
@@ -11,33 +15,26 @@ pub struct BuildKernelArgs {
     pub release: bool,
     pub verbose: bool,
     pub dry_run: bool,
+    // rebuild even when the inputs are unchanged
+    pub force: bool,
 }
 
-pub fn build_kernel(repo_root: &Utf8Path, args: &BuildKernelArgs) -> Result<()> {
-    validate_supported(&args.arch, &args.platform)?;
+#[derive(Clone, Debug)]
+pub struct KernelArtifacts {
+    pub kernel_elf: Artifact,
+}
+
+pub fn build_kernel(repo_root: &Utf8Path, args: &BuildKernelArgs) -> Result<KernelArtifacts> {
+    validate_supported(Component::Kernel, &args.arch, &args.platform)?;
 
-    let target_arch = to_a9n_target_arch(&args.arch);
-    let platform_name = to_platform_name(&args.platform);
+    let target_arch = target_desc(&args.arch).arch_name;
     let build_type = if args.release { "Release" } else { "Debug" };
 
     let a9n_dir = repo_root.join("A9N");
 
-    let build_dir = a9n_dir.join("build").join(format!(
-        "{}-{}-{}",
-        target_arch,
-        platform_name,
-        if args.release { "release" } else { "debug" }
-    ));
-
-    let install_prefix = repo_root
-        .join("out")
-        .join(format!(
-            "{}-{}-{}",
-            target_arch,
-            platform_name,
-            if args.release { "release" } else { "debug" }
-        ))
-        .join("a9n");
+    let (build_dir, out_base) = kernel_dirs(repo_root, args);
+
+    let install_prefix = out_base.join("a9n");
 
     let toolchain_file = a9n_dir
         .join("src")
@@ -45,6 +42,8 @@ pub fn build_kernel(repo_root: &Utf8Path, args: &BuildKernelArgs) -> Result<()>
         .join(target_arch)
         .join("toolchain.cmake");
 
+    let kernel_path = target_desc(&args.arch).kernel_path(&out_base);
+
     if args.dry_run {
         eprintln!("[dry-run] cmake -S {} -B {}", a9n_dir, build_dir);
         eprintln!("[dry-run]   -DARCH={}", target_arch);
@@ -53,14 +52,30 @@ pub fn build_kernel(repo_root: &Utf8Path, args: &BuildKernelArgs) -> Result<()>
         eprintln!("[dry-run]   -DCMAKE_INSTALL_PREFIX={}", install_prefix);
         eprintln!("[dry-run] cmake --build {}", build_dir);
         eprintln!("[dry-run] cmake --install {}", build_dir);
-        return Ok(());
+        return Ok(kernel_artifacts(kernel_path, Provenance::default()));
     }
 
+    require(check_submodule(repo_root, "A9N"))?;
+
+    let provenance = kernel_provenance(&a9n_dir, &toolchain_file);
+    let stamp = Stamp::new(&out_base, "kernel");
+    let fingerprint = kernel_fingerprint(&a9n_dir, args, &provenance)?;
+
+    if !args.force && stamp.is_up_to_date(&fingerprint, &[&kernel_path]) {
+        eprintln!("[kernel] up to date: {}", kernel_path);
+        return Ok(kernel_artifacts(kernel_path, provenance));
+    }
+    stamp.invalidate();
+
     std::fs::create_dir_all(&build_dir)
         .with_context(|| format!("create build dir: {}", build_dir))?;
     std::fs::create_dir_all(&install_prefix)
         .with_context(|| format!("create install prefix: {}", install_prefix))?;
 
+    for check in check_cross_toolchain(repo_root, &args.arch) {
+        require(check)?;
+    }
+
     let mut configure_command = Command::new("cmake");
     configure_command
         .current_dir(&a9n_dir)
@@ -92,24 +107,67 @@ pub fn build_kernel(repo_root: &Utf8Path, args: &BuildKernelArgs) -> Result<()>
         .arg(&build_dir);
     run_command(install_command, args.verbose, "cmake install (A9N kernel)")?;
 
+    stamp.record(&fingerprint, &[&kernel_path])?;
+
     eprintln!("installed to: {}", install_prefix);
-    Ok(())
+    Ok(kernel_artifacts(kernel_path, provenance))
 }
 
-fn validate_supported(_arch: &Arch, _platform: &Platform) -> Result<()> {
-    Ok(())
+// everything the kernel step writes, for `clean`
+pub fn kernel_outputs(repo_root: &Utf8Path, args: &BuildKernelArgs) -> Vec<Utf8PathBuf> {
+    let (build_dir, out_base) = kernel_dirs(repo_root, args);
+    vec![
+        build_dir,
+        out_base.join("a9n"),
+        Stamp::new(&out_base, "kernel").path().to_path_buf(),
+    ]
 }
 
-fn to_a9n_target_arch(arch: &Arch) -> &'static str {
-    match arch {
-        Arch::X86_64 => "x86_64",
-        Arch::Aarch64 => "aarch64",
-        Arch::Riscv64 => "riscv64",
+// (A9N/build/<key>, out/<key>)
+fn kernel_dirs(repo_root: &Utf8Path, args: &BuildKernelArgs) -> (Utf8PathBuf, Utf8PathBuf) {
+    let key = build_key(&args.arch, &args.platform, args.release);
+    (
+        repo_root.join("A9N").join("build").join(key),
+        out_base(repo_root, &args.arch, &args.platform, args.release),
+    )
+}
+
+fn kernel_artifacts(kernel_path: Utf8PathBuf, provenance: Provenance) -> KernelArtifacts {
+    KernelArtifacts {
+        kernel_elf: Artifact::new(ArtifactKind::Kernel, kernel_path, "kernel", provenance),
     }
 }
 
-fn to_platform_name(platform: &Platform) -> &'static str {
-    match platform {
-        Platform::Qemu => "qemu",
+fn kernel_provenance(a9n_dir: &Utf8Path, toolchain_file: &Utf8Path) -> Provenance {
+    let mut provenance = Provenance::default();
+
+    let mut cmake_version = Command::new("cmake");
+    cmake_version.arg("--version");
+    provenance.probe_tool("cmake", cmake_version, a9n_dir);
+
+    // toolchain.cmake itself is part of the source tree hash; the compilers it
+    // names are not
+    let text = std::fs::read_to_string(toolchain_file).unwrap_or_default();
+    for (_, program) in toolchain_programs(&text) {
+        if !program.contains("${") {
+            provenance.probe_program(&program, a9n_dir);
+        }
     }
+    provenance.probe_source_commit(a9n_dir);
+
+    provenance
+}
+
+fn kernel_fingerprint(
+    a9n_dir: &Utf8Path,
+    args: &BuildKernelArgs,
+    provenance: &Provenance,
+) -> Result<String> {
+    let mut fingerprint = Fingerprint::new("kernel");
+    fingerprint.add_str("args", &format!("{:?}/{:?}", args.arch, args.platform));
+    fingerprint.add_str("release", &args.release.to_string());
+    fingerprint.add_provenance(provenance);
+    fingerprint.add_tree(a9n_dir, &[".git", "build"])?;
+
+    Ok(fingerprint.finish())
 }
@@ -0,0 +1,182 @@
+use crate::cli::{Arch, CommonArgs, MatrixArgs, Platform};
+use crate::steps::process::with_output_prefix;
+use crate::steps::target::{build_key, validate_pipeline_supported};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct MatrixCombination {
+    pub arch: Arch,
+    pub platform: Platform,
+    pub release: bool,
+    // also the name of its out directory
+    pub name: String,
+}
+
+pub enum MatrixResult {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+// `build` gets the command line narrowed to one combination; it runs with the
+// combination's name as the output prefix, on up to --parallel threads at once.
+pub fn run_matrix(
+    args: &MatrixArgs,
+    build: impl Fn(&CommonArgs) -> Result<MatrixResult> + Sync,
+) -> Result<()> {
+    if args.parallel == 0 {
+        bail!("--parallel must be at least 1");
+    }
+
+    let filters = args
+        .filter
+        .iter()
+        .map(|filter| {
+            glob::Pattern::new(filter).with_context(|| format!("invalid filter: {}", filter))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let combinations = select_combinations(&args.common, &filters);
+    if combinations.is_empty() {
+        bail!("no combination matches the given filters");
+    }
+
+    let queue = Mutex::new(combinations.iter().enumerate());
+    let results: Mutex<Vec<Option<(MatrixResult, Duration)>>> =
+        Mutex::new(combinations.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..args.parallel.min(combinations.len()) {
+            scope.spawn(|| {
+                loop {
+                    let Some((index, combination)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+
+                    let started = Instant::now();
+                    let result = with_output_prefix(&combination.name, || {
+                        std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            run_matrix_combination(&args.common, combination, &build)
+                        }))
+                    })
+                    .unwrap_or_else(|_| MatrixResult::Failed("panicked".to_string()));
+
+                    results.lock().unwrap()[index] = Some((result, started.elapsed()));
+                }
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+
+    let name_width = combinations
+        .iter()
+        .map(|combination| combination.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("combination".len());
+
+    println!(
+        "{:<name_width$}  {:<6}  {:>8}  detail",
+        "combination", "result", "time"
+    );
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    let mut failures = Vec::new();
+    for (combination, result) in combinations.iter().zip(results) {
+        let (result, elapsed) = result.expect("every combination ran");
+        let time = format!("{:.1}s", elapsed.as_secs_f64());
+        let (label, time, detail) = match &result {
+            MatrixResult::Passed => {
+                passed += 1;
+                ("pass", time, "")
+            }
+            MatrixResult::Failed(message) => {
+                failed += 1;
+                failures.push((&combination.name, message.clone()));
+                ("FAIL", time, message.lines().next().unwrap_or_default())
+            }
+            MatrixResult::Skipped(reason) => {
+                skipped += 1;
+                ("skip", "-".to_string(), reason.as_str())
+            }
+        };
+        let row = format!(
+            "{:<name_width$}  {:<6}  {:>8}  {}",
+            combination.name, label, time, detail
+        );
+        println!("{}", row.trim_end());
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+
+    for (name, message) in &failures {
+        eprintln!("\n[{}] {}", name, message);
+    }
+
+    if failed > 0 {
+        bail!("{} of {} combinations failed", failed, combinations.len());
+    }
+
+    Ok(())
+}
+
+// every arch × platform × profile allowed by the narrowing flags and filters
+pub fn select_combinations(
+    common: &CommonArgs,
+    filters: &[glob::Pattern],
+) -> Vec<MatrixCombination> {
+    let mut combinations = Vec::new();
+    for arch in Arch::value_variants() {
+        if common.arch.as_ref().is_some_and(|only| only != arch) {
+            continue;
+        }
+        for platform in Platform::value_variants() {
+            if common
+                .platform
+                .as_ref()
+                .is_some_and(|only| only != platform)
+            {
+                continue;
+            }
+            for release in [false, true] {
+                if (common.release && !release) || (common.debug && release) {
+                    continue;
+                }
+
+                let name = build_key(arch, platform, release);
+                if !filters.is_empty() && !filters.iter().any(|filter| filter.matches(&name)) {
+                    continue;
+                }
+
+                combinations.push(MatrixCombination {
+                    arch: arch.clone(),
+                    platform: platform.clone(),
+                    release,
+                    name,
+                });
+            }
+        }
+    }
+    combinations
+}
+
+fn run_matrix_combination(
+    common: &CommonArgs,
+    combination: &MatrixCombination,
+    build: impl Fn(&CommonArgs) -> Result<MatrixResult>,
+) -> MatrixResult {
+    if let Err(error) = validate_pipeline_supported(&combination.arch, &combination.platform) {
+        return MatrixResult::Skipped(error.to_string());
+    }
+
+    let mut common = common.clone();
+    common.arch = Some(combination.arch.clone());
+    common.platform = Some(combination.platform.clone());
+    common.release = combination.release;
+    common.debug = !combination.release;
+
+    build(&common).unwrap_or_else(|error| MatrixResult::Failed(format!("{:#}", error)))
+}
@@ -1,5 +1,9 @@
 use crate::cli::{Arch, Platform};
+use crate::steps::artifact::{Artifact, ArtifactKind, Provenance};
+use crate::steps::doctor::{check_nightly, check_submodule, require};
+use crate::steps::fingerprint::{Fingerprint, Stamp};
 use crate::steps::process::run_command;
+use crate::steps::target::{Component, out_base, target_desc, validate_supported};
 use anyhow::{Context, Result, bail};
 use camino::{Utf8Path, Utf8PathBuf};
 use std::process::Command;
@@ -11,33 +15,31 @@ pub struct BuildNunOsArgs {
     pub release: bool,
     pub verbose: bool,
     pub dry_run: bool,
+    // rebuild even when the inputs are unchanged
+    pub force: bool,
 
     pub use_nightly_build_std: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct NunOsArtifacts {
-    pub cargo_target_json: Utf8PathBuf,
-    pub cargo_target_dir: Utf8PathBuf,
+    pub init_elf: Artifact,
 }
 
 pub fn build_nun_os(repo_root: &Utf8Path, args: &BuildNunOsArgs) -> Result<NunOsArtifacts> {
-    validate_supported(&args.arch, &args.platform)?;
+    validate_supported(Component::Init, &args.arch, &args.platform)?;
 
     let os_dir = repo_root.join("core");
     let os_manifest = os_dir.join("Cargo.toml");
 
     let target_json = nun_custom_target_json(repo_root, &args.arch);
 
-    let out_base = repo_root.join("out").join(format!(
-        "{}-{}-{}",
-        to_arch_name(&args.arch),
-        to_platform_name(&args.platform),
-        if args.release { "release" } else { "debug" }
-    ));
+    let out_base = out_base(repo_root, &args.arch, &args.platform, args.release);
 
     let cargo_target_dir = out_base.join("nun_os_target_dir");
 
+    let init_path = target_desc(&args.arch).init_path(&cargo_target_dir, args.release);
+
     if args.dry_run {
         eprintln!("[dry-run] cargo build (Nun OS)");
         eprintln!("[dry-run]   dir: {}", os_dir);
@@ -53,15 +55,14 @@ pub fn build_nun_os(repo_root: &Utf8Path, args: &BuildNunOsArgs) -> Result<NunOs
             );
         }
 
-        return Ok(NunOsArtifacts {
-            cargo_target_json: target_json,
-            cargo_target_dir,
-        });
+        return Ok(nun_os_artifacts(init_path, Provenance::default()));
     }
 
     std::fs::create_dir_all(&cargo_target_dir)
         .with_context(|| format!("create cargo target dir: {}", cargo_target_dir))?;
 
+    require(check_submodule(repo_root, "Nun"))?;
+
     if !os_manifest.exists() {
         bail!("OS manifest not found: {}", os_manifest);
     }
@@ -70,6 +71,18 @@ pub fn build_nun_os(repo_root: &Utf8Path, args: &BuildNunOsArgs) -> Result<NunOs
         bail!("Nun custom target json not found: {}", target_json);
     }
 
+    let provenance = nun_os_provenance(repo_root);
+    let stamp = Stamp::new(&out_base, "nun");
+    let fingerprint = nun_os_fingerprint(repo_root, args, &provenance)?;
+
+    if !args.force && stamp.is_up_to_date(&fingerprint, &[&init_path]) {
+        eprintln!("[nun] up to date: {}", init_path);
+        return Ok(nun_os_artifacts(init_path, provenance));
+    }
+    stamp.invalidate();
+
+    require(check_nightly(repo_root))?;
+
     let mut command = Command::new("cargo");
     command.current_dir(&os_dir);
 
@@ -94,33 +107,65 @@ pub fn build_nun_os(repo_root: &Utf8Path, args: &BuildNunOsArgs) -> Result<NunOs
 
     run_command(command, args.verbose, "cargo build (Nun OS)")?;
 
-    Ok(NunOsArtifacts {
-        cargo_target_json: target_json,
-        cargo_target_dir,
-    })
-}
+    stamp.record(&fingerprint, &[&init_path])?;
 
-fn nun_custom_target_json(repo_root: &Utf8Path, arch: &Arch) -> Utf8PathBuf {
-    repo_root
-        .join("Nun")
-        .join("arch")
-        .join(format!("{}-unknown-a9n.json", to_arch_name(arch)))
+    Ok(nun_os_artifacts(init_path, provenance))
 }
 
-fn validate_supported(_arch: &Arch, _platform: &Platform) -> Result<()> {
-    Ok(())
+// everything the Nun step writes, for `clean`
+pub fn nun_os_outputs(repo_root: &Utf8Path, args: &BuildNunOsArgs) -> Vec<Utf8PathBuf> {
+    let out_base = out_base(repo_root, &args.arch, &args.platform, args.release);
+    vec![
+        out_base.join("nun_os_target_dir"),
+        Stamp::new(&out_base, "nun").path().to_path_buf(),
+    ]
 }
 
-fn to_arch_name(arch: &Arch) -> &'static str {
-    match arch {
-        Arch::X86_64 => "x86_64",
-        Arch::Aarch64 => "aarch64",
-        Arch::Riscv64 => "riscv64",
+fn nun_os_artifacts(init_path: Utf8PathBuf, provenance: Provenance) -> NunOsArtifacts {
+    NunOsArtifacts {
+        init_elf: Artifact::new(ArtifactKind::Init, init_path, "nun", provenance),
     }
 }
 
-fn to_platform_name(platform: &Platform) -> &'static str {
-    match platform {
-        Platform::Qemu => "qemu",
-    }
+fn nun_os_provenance(repo_root: &Utf8Path) -> Provenance {
+    let mut provenance = Provenance::default();
+
+    let mut rustc_version = Command::new("rustc");
+    rustc_version.arg("+nightly").arg("-vV");
+    provenance.probe_tool("rustc", rustc_version, &repo_root.join("core"));
+    provenance.probe_source_commit(&repo_root.join("Nun"));
+
+    provenance
+}
+
+fn nun_os_fingerprint(
+    repo_root: &Utf8Path,
+    args: &BuildNunOsArgs,
+    provenance: &Provenance,
+) -> Result<String> {
+    let os_dir = repo_root.join("core");
+    let nun_dir = repo_root.join("Nun");
+
+    let mut fingerprint = Fingerprint::new("nun");
+    fingerprint.add_str("args", &format!("{:?}/{:?}", args.arch, args.platform));
+    fingerprint.add_str("release", &args.release.to_string());
+    fingerprint.add_str(
+        "use_nightly_build_std",
+        &args.use_nightly_build_std.to_string(),
+    );
+    fingerprint.add_provenance(provenance);
+    fingerprint.add_file_contents(&repo_root.join("Cargo.toml"))?;
+    fingerprint.add_file_contents(&repo_root.join("Cargo.lock"))?;
+    fingerprint.add_file_contents(&repo_root.join(".cargo").join("config.toml"))?;
+    fingerprint.add_tree(&os_dir, &["target"])?;
+    fingerprint.add_tree(&nun_dir, &[".git", "target"])?;
+
+    Ok(fingerprint.finish())
+}
+
+fn nun_custom_target_json(repo_root: &Utf8Path, arch: &Arch) -> Utf8PathBuf {
+    repo_root
+        .join("Nun")
+        .join("arch")
+        .join(format!("{}.json", target_desc(arch).nun_target_triple))
 }
@@ -1,14 +1,48 @@
+use crate::steps::doctor::missing_program_hint;
 use anyhow::{Context, Result, bail};
-use std::process::Command;
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+thread_local! {
+    // set while a scheduler step or matrix combination runs on this thread
+    static OUTPUT_PREFIX: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Run `f` with the output of every command it runs prefixed by `[prefix]`,
+// so that steps running side by side stay readable.
+pub fn with_output_prefix<T>(prefix: &str, f: impl FnOnce() -> T) -> T {
+    let previous = OUTPUT_PREFIX.with(|cell| cell.replace(Some(prefix.to_string())));
+    let result = f();
+    OUTPUT_PREFIX.with(|cell| *cell.borrow_mut() = previous);
+    result
+}
+
+// The prefix set on this thread, for threads started on its behalf.
+pub fn output_prefix() -> Option<String> {
+    OUTPUT_PREFIX.with(|cell| cell.borrow().clone())
+}
 
 pub fn run_command(mut command: Command, verbose: bool, context: &str) -> Result<()> {
+    let prefix = output_prefix();
+
     if verbose {
-        eprintln!("[cmd] {:?}", command);
+        match &prefix {
+            Some(prefix) => eprintln!("[{}] [cmd] {:?}", prefix, command),
+            None => eprintln!("[cmd] {:?}", command),
+        }
     }
 
-    let status = command
-        .status()
-        .with_context(|| format!("failed to spawn: {}", context))?;
+    let program = command.get_program().to_os_string();
+    let status = match &prefix {
+        Some(prefix) => status_with_prefix(command, prefix),
+        None => command.status(),
+    }
+    .map_err(|error| spawn_error(error, &program, context))?;
 
     if !status.success() {
         bail!("command failed: {} (exit={})", context, status);
@@ -16,3 +50,128 @@ pub fn run_command(mut command: Command, verbose: bool, context: &str) -> Result
 
     Ok(())
 }
+
+fn status_with_prefix(mut command: Command, prefix: &str) -> std::io::Result<ExitStatus> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| copy_prefixed(stdout, std::io::stdout(), prefix));
+        scope.spawn(|| copy_prefixed(stderr, std::io::stderr(), prefix));
+        child.wait()
+    })
+}
+
+fn copy_prefixed(source: impl Read, mut sink: impl Write, prefix: &str) {
+    for line in BufReader::new(source).split(b'\n') {
+        let Ok(line) = line else {
+            break;
+        };
+        // one write per line so lines from different steps do not mix
+        let line = format!(
+            "[{}] {}\n",
+            prefix,
+            String::from_utf8_lossy(&line).trim_end()
+        );
+        let _ = sink.write_all(line.as_bytes());
+    }
+}
+
+pub fn spawn_command(mut command: Command, verbose: bool, context: &str) -> Result<ChildGuard> {
+    if verbose {
+        eprintln!("[cmd] {:?}", command);
+    }
+
+    let child = command
+        .spawn()
+        .map_err(|error| spawn_error(error, command.get_program(), context))?;
+
+    Ok(ChildGuard {
+        child,
+        context: context.to_string(),
+        verbose,
+        remove_on_drop: Vec::new(),
+    })
+}
+
+// A missing program gets the same install guidance as `cargo xtask doctor`.
+fn spawn_error(error: std::io::Error, program: &OsStr, context: &str) -> anyhow::Error {
+    let message = format!("failed to spawn: {}", context);
+    if error.kind() == std::io::ErrorKind::NotFound {
+        anyhow::Error::new(error).context(format!("{}: {}", message, missing_program_hint(program)))
+    } else {
+        anyhow::Error::new(error).context(message)
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Keep xtask alive on Ctrl-C so that a foreground child (e.g. the debugger)
+// can handle SIGINT itself and we still get to tear down background children.
+// The interrupt is recorded for `interrupted`; calling this again is a no-op.
+pub fn catch_interrupts() -> Result<()> {
+    static INSTALLED: OnceLock<()> = OnceLock::new();
+    if INSTALLED.get().is_some() {
+        return Ok(());
+    }
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))
+        .context("install SIGINT handler")?;
+    let _ = INSTALLED.set(());
+    Ok(())
+}
+
+// Whether Ctrl-C was pressed since `catch_interrupts`.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Background child process that is killed and reaped when dropped.
+pub struct ChildGuard {
+    child: Child,
+    context: String,
+    verbose: bool,
+    // files only the child used, deleted once it is reaped
+    remove_on_drop: Vec<PathBuf>,
+}
+
+impl ChildGuard {
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.child.stderr.take()
+    }
+
+    pub fn remove_on_drop(&mut self, path: PathBuf) {
+        self.remove_on_drop.push(path);
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.child
+            .try_wait()
+            .with_context(|| format!("wait for: {}", self.context))
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if !matches!(self.child.try_wait(), Ok(Some(_))) {
+            if self.verbose {
+                eprintln!("[cmd] stopping: {}", self.context);
+            }
+
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+
+        for path in &self.remove_on_drop {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
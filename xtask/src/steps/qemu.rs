@@ -1,8 +1,19 @@
-use crate::cli::{Arch, Platform};
-use crate::steps::process::run_command;
+use crate::cli::{Accelerator, Arch, NetworkMode, NicModel, Platform};
+use crate::steps::doctor::firmware_hint;
+use crate::steps::firmware_descriptor::{
+    FirmwareQuery, descriptor_dirs, find_descriptor, load_descriptor,
+};
+use crate::steps::process::{ChildGuard, run_command, spawn_command};
+use crate::steps::target::target_desc;
 use anyhow::{Context, Result, bail};
-use camino::Utf8Path;
-use std::process::Command;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// gdbstub port unless debugger.gdb_port says otherwise (QEMU's `-s` uses it too)
+pub const GDB_STUB_PORT: u16 = 1234;
 
 #[derive(Clone, Debug)]
 pub struct RunQemuArgs<'a> {
@@ -12,77 +23,1011 @@ pub struct RunQemuArgs<'a> {
 
     pub img_path: &'a Utf8Path,
 
-    pub ovmf_code_path: &'a Utf8Path,
-    pub ovmf_vars_path: &'a Utf8Path,
+    pub firmware: &'a Firmware,
+    // which copy of the firmware's variable store the guest gets
+    pub vars: VarsStore<'a>,
+
+    pub machine: MachineOptions<'a>,
+    pub network: NetworkOptions<'a>,
+
+    pub serial: SerialTarget<'a>,
+    // QMP control socket (see steps::qmp); QEMU does not wait for a client
+    pub qmp_socket: Option<&'a Utf8Path>,
 
-    pub enable_gdb: bool,
+    // gdbstub on this port of 127.0.0.1
+    pub gdb_port: Option<u16>,
     pub stop_at_start: bool,
 
+    // no display window
+    pub headless: bool,
+    // add the arch's test exit device and exit instead of rebooting
+    pub test_exit: bool,
+
     pub verbose: bool,
     pub dry_run: bool,
 }
 
-pub fn run_qemu_x86_64(args: &RunQemuArgs) -> Result<()> {
-    if args.arch != Arch::X86_64 {
-        bail!("run_qemu_x86_64 called with non-x86_64 arch");
+// -m / -cpu / -smp / -M / -accel; None keeps the arch's default
+#[derive(Clone, Copy, Debug)]
+pub struct MachineOptions<'a> {
+    pub memory: &'a str,
+    pub cpu: Option<&'a str>,
+    pub smp: Option<&'a str>,
+    pub machine: Option<&'a str>,
+    pub accel: Accelerator,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkOptions<'a> {
+    pub mode: NetworkMode,
+    pub nic: NicModel,
+    pub mac: Option<&'a str>,
+    // user mode host forwards, e.g. "tcp:127.0.0.1:8080-:80"
+    pub hostfwd: &'a [String],
+    // required in socket mode
+    pub socket: Option<&'a SocketBackend>,
+    // pcap file all traffic through the NIC is recorded to
+    pub capture: Option<&'a Utf8Path>,
+}
+
+// How a socket netdev reaches the other instances on its segment.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SocketBackend {
+    // UDP multicast group, e.g. "230.0.0.1:5100"; any number of instances can join
+    Multicast(String),
+    // point-to-point over TCP: one instance listens, the other connects
+    Listen(String),
+    Connect(String),
+}
+
+// The UEFI variable store QEMU runs with; `Firmware::Pflash::vars_path` is
+// only the template every store starts from.
+#[derive(Clone, Copy, Debug)]
+pub enum VarsStore<'a> {
+    // a private copy of the template, deleted once QEMU has ended
+    Fresh,
+    // <out_base>/<template name>, kept between runs
+    Persistent,
+    File(&'a Utf8Path),
+}
+
+// Where the guest's first serial port is connected.
+#[derive(Clone, Copy, Debug)]
+pub enum SerialTarget<'a> {
+    // multiplexed with the QEMU monitor on the terminal
+    Stdio,
+    // as Stdio, with the guest's output also logged to a file
+    StdioLogged(&'a Utf8Path),
+    File(&'a Utf8Path),
+    // QEMU listens and waits for one client before starting the guest
+    UnixSocket(&'a Utf8Path),
+    // QEMU prints the allocated /dev/pts path on stderr
+    Pty,
+}
+
+#[derive(Clone, Debug)]
+pub enum Firmware {
+    // UEFI code + variable store mapped as pflash (OVMF / AAVMF / edk2)
+    Pflash {
+        code_path: Utf8PathBuf,
+        vars_path: Utf8PathBuf,
+        // the machine needs SMM and a pflash only SMM code may write
+        requires_smm: bool,
+    },
+    // U-Boot loaded as the S-mode payload of the default OpenSBI
+    UBoot {
+        image_path: Utf8PathBuf,
+    },
+}
+
+struct QemuMachine {
+    binary: &'static str,
+    machine: Option<&'static str>,
+    cpu: &'static str,
+    // the removable disk must be on a bus the firmware can boot from
+    disk_device: Option<&'static str>,
+    test_exit_device: TestExitDevice,
+}
+
+// How a guest under `cargo xtask test` reports its result by ending QEMU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestExitDevice {
+    // 32-bit write of v to port 0xf4; QEMU exits with (v << 1) | 1
+    IsaDebugExit,
+    // semihosting SYS_EXIT; QEMU exits with the guest's code
+    Semihosting,
+    // built-in sifive_test on riscv virt at 0x100000: 0x5555 = pass,
+    // (code << 16) | 0x3333 = fail; QEMU exits with 0 or the code
+    SifiveTest,
+}
+
+// What the guest's firmware has to be; see resolve_firmware.
+#[derive(Clone, Copy, Debug)]
+pub struct FirmwareRequest<'a> {
+    pub arch: &'a Arch,
+    // -M as configured; None for the arch's default
+    pub machine: Option<&'a str>,
+    pub secure_boot: bool,
+    // qemu.firmware_code / firmware_vars, set together
+    pub code_path: Option<&'a Utf8Path>,
+    pub vars_path: Option<&'a Utf8Path>,
+    // a descriptor to use instead of searching for one
+    pub descriptor: Option<&'a Utf8Path>,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum FirmwareOrigin {
+    Configured,
+    Descriptor(Utf8PathBuf),
+    // the repo copy or a known distribution path
+    Fallback,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolvedFirmware {
+    pub firmware: Firmware,
+    pub origin: FirmwareOrigin,
+}
+
+enum FirmwareCandidate {
+    Pflash {
+        code: &'static str,
+        vars: &'static str,
+    },
+    UBoot {
+        image: &'static str,
+    },
+}
+
+pub fn qemu_binary(arch: &Arch) -> &'static str {
+    qemu_machine(arch).binary
+}
+
+fn qemu_machine(arch: &Arch) -> QemuMachine {
+    match arch {
+        Arch::X86_64 => QemuMachine {
+            binary: "qemu-system-x86_64",
+            machine: None,
+            cpu: "max",
+            disk_device: None,
+            test_exit_device: TestExitDevice::IsaDebugExit,
+        },
+        Arch::Aarch64 => QemuMachine {
+            binary: "qemu-system-aarch64",
+            machine: Some("virt"),
+            cpu: "max",
+            disk_device: Some("virtio-blk-pci"),
+            test_exit_device: TestExitDevice::Semihosting,
+        },
+        Arch::Riscv64 => QemuMachine {
+            binary: "qemu-system-riscv64",
+            machine: Some("virt"),
+            cpu: "max",
+            disk_device: Some("virtio-blk-pci"),
+            test_exit_device: TestExitDevice::SifiveTest,
+        },
     }
+}
+
+// "kvm" or "tcg" for `-accel`.
+fn resolve_accelerator(arch: &Arch, accel: Accelerator) -> Result<&'static str> {
+    match accel {
+        Accelerator::Tcg => Ok("tcg"),
+        Accelerator::Kvm => match kvm_unusable_reason(arch) {
+            None => Ok("kvm"),
+            Some(reason) => bail!("--accel kvm: {}; use --accel tcg or auto", reason),
+        },
+        Accelerator::Auto => Ok(if kvm_unusable_reason(arch).is_none() {
+            "kvm"
+        } else {
+            "tcg"
+        }),
+    }
+}
+
+// Why KVM cannot run this guest, or None when it can.
+pub fn kvm_unusable_reason(arch: &Arch) -> Option<String> {
+    let guest_arch = target_desc(arch).arch_name;
+    if std::env::consts::ARCH != guest_arch {
+        return Some(format!(
+            "KVM cannot run {} guests on a {} host",
+            guest_arch,
+            std::env::consts::ARCH
+        ));
+    }
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+    {
+        Ok(_) => None,
+        Err(error) => Some(format!("cannot open /dev/kvm: {}", error)),
+    }
+}
+
+pub fn test_exit_device(arch: &Arch) -> TestExitDevice {
+    qemu_machine(arch).test_exit_device
+}
+
+// Relative paths are resolved against the repo root; the first existing entry wins.
+fn firmware_candidates(arch: &Arch) -> &'static [FirmwareCandidate] {
+    match arch {
+        Arch::X86_64 => &[
+            FirmwareCandidate::Pflash {
+                code: "a9nloader-rs/tools/OVMF_CODE.fd",
+                vars: "a9nloader-rs/tools/OVMF_VARS.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/OVMF/OVMF_CODE_4M.fd",
+                vars: "/usr/share/OVMF/OVMF_VARS_4M.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/OVMF/OVMF_CODE.fd",
+                vars: "/usr/share/OVMF/OVMF_VARS.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+                vars: "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+            },
+        ],
+        Arch::Aarch64 => &[
+            FirmwareCandidate::Pflash {
+                code: "a9nloader-rs/tools/AAVMF_CODE.fd",
+                vars: "a9nloader-rs/tools/AAVMF_VARS.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/AAVMF/AAVMF_CODE.fd",
+                vars: "/usr/share/AAVMF/AAVMF_VARS.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw",
+                vars: "/usr/share/edk2/aarch64/vars-template-pflash.raw",
+            },
+        ],
+        Arch::Riscv64 => &[
+            FirmwareCandidate::Pflash {
+                code: "a9nloader-rs/tools/RISCV_VIRT_CODE.fd",
+                vars: "a9nloader-rs/tools/RISCV_VIRT_VARS.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/qemu-efi-riscv64/RISCV_VIRT_CODE.fd",
+                vars: "/usr/share/qemu-efi-riscv64/RISCV_VIRT_VARS.fd",
+            },
+            FirmwareCandidate::Pflash {
+                code: "/usr/share/edk2/riscv/RISCV_VIRT_CODE.fd",
+                vars: "/usr/share/edk2/riscv/RISCV_VIRT_VARS.fd",
+            },
+            FirmwareCandidate::UBoot {
+                image: "/usr/lib/u-boot/qemu-riscv64_smode/uboot.elf",
+            },
+        ],
+    }
+}
+
+// Explicit paths first, then the descriptor given or the first one QEMU's
+// firmware directories offer for the arch, machine and secure boot setting,
+// then the repo copy and the known distribution paths.
+pub fn resolve_firmware(
+    repo_root: &Utf8Path,
+    request: &FirmwareRequest,
+) -> Result<ResolvedFirmware> {
+    let arch = request.arch;
+    match (request.code_path, request.vars_path) {
+        (Some(code_path), Some(vars_path)) => {
+            let firmware = Firmware::Pflash {
+                code_path: code_path.to_path_buf(),
+                vars_path: vars_path.to_path_buf(),
+                // secure boot builds of OVMF all need it; nothing else does
+                requires_smm: request.secure_boot && *arch == Arch::X86_64,
+            };
+            if !request.dry_run && !firmware.exists() {
+                bail!(
+                    "configured firmware missing: {}\n  fix: qemu.firmware_code / qemu.firmware_vars",
+                    firmware
+                );
+            }
+            return Ok(ResolvedFirmware {
+                firmware,
+                origin: FirmwareOrigin::Configured,
+            });
+        }
+        (None, None) => {}
+        _ => bail!("qemu.firmware_code and qemu.firmware_vars must be set together"),
+    }
+
+    // the default x86_64 machine (pc) has no SMM, which secure boot OVMF needs
+    let machine = request
+        .machine
+        .or(qemu_machine(arch).machine)
+        .unwrap_or(if request.secure_boot { "q35" } else { "pc" });
+    let query = FirmwareQuery {
+        arch_name: target_desc(arch).arch_name,
+        machine: Some(machine),
+        secure_boot: Some(request.secure_boot),
+    };
+    let from_descriptor =
+        |descriptor: crate::steps::firmware_descriptor::Descriptor| ResolvedFirmware {
+            firmware: Firmware::Pflash {
+                code_path: descriptor.code_path,
+                vars_path: descriptor.vars_path,
+                requires_smm: descriptor.requires_smm,
+            },
+            origin: FirmwareOrigin::Descriptor(descriptor.path),
+        };
+
+    // the descriptor asked for decides secure boot, and SMM picks the machine
+    if let Some(path) = request.descriptor {
+        let chosen_query = FirmwareQuery {
+            machine: request.machine,
+            secure_boot: request.secure_boot.then_some(true),
+            ..query
+        };
+        let descriptor = load_descriptor(path, &chosen_query)
+            .with_context(|| format!("firmware descriptor {}", path))?;
+        return Ok(from_descriptor(descriptor));
+    }
+
+    let (descriptor, rejected) = find_descriptor(&query);
+    if let Some(descriptor) = descriptor {
+        return Ok(from_descriptor(descriptor));
+    }
+
+    let mut searched: Vec<String> = descriptor_dirs()
+        .iter()
+        .map(|dir| format!("  descriptors in {}", dir))
+        .collect();
+    searched.extend(
+        rejected
+            .iter()
+            .map(|reason| format!("  skipped {}", reason)),
+    );
+
+    let secure_boot = if request.secure_boot {
+        "secure boot "
+    } else {
+        ""
+    };
+    // nothing is known about the fallbacks' secure boot support
+    if !request.secure_boot {
+        match fallback_firmware(repo_root, arch, request.dry_run) {
+            Ok(firmware) => {
+                return Ok(ResolvedFirmware {
+                    firmware,
+                    origin: FirmwareOrigin::Fallback,
+                });
+            }
+            Err(candidates) => {
+                searched.extend(candidates.iter().map(|firmware| format!("  {}", firmware)))
+            }
+        }
+    }
+
+    bail!(
+        "no {}UEFI firmware found for {} on {}; searched:\n{}\n  fix: {}",
+        secure_boot,
+        query.arch_name,
+        machine,
+        searched.join("\n"),
+        firmware_hint(arch)
+    );
+}
+
+// The first fallback that exists, or all of them.
+fn fallback_firmware(
+    repo_root: &Utf8Path,
+    arch: &Arch,
+    dry_run: bool,
+) -> std::result::Result<Firmware, Vec<Firmware>> {
+    let resolve = |path: &str| {
+        let path = Utf8Path::new(path);
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            repo_root.join(path)
+        }
+    };
+
+    let candidates: Vec<Firmware> = firmware_candidates(arch)
+        .iter()
+        .map(|candidate| match candidate {
+            FirmwareCandidate::Pflash { code, vars } => Firmware::Pflash {
+                code_path: resolve(code),
+                vars_path: resolve(vars),
+                requires_smm: false,
+            },
+            FirmwareCandidate::UBoot { image } => Firmware::UBoot {
+                image_path: resolve(image),
+            },
+        })
+        .collect();
+
+    if let Some(found) = candidates.iter().find(|firmware| firmware.exists()) {
+        return Ok(found.clone());
+    }
+
+    if dry_run && let Some(first) = candidates.first() {
+        return Ok(first.clone());
+    }
+
+    Err(candidates)
+}
+
+impl Firmware {
+    pub fn exists(&self) -> bool {
+        match self {
+            Firmware::Pflash {
+                code_path,
+                vars_path,
+                ..
+            } => code_path.exists() && vars_path.exists(),
+            Firmware::UBoot { image_path } => image_path.exists(),
+        }
+    }
+}
+
+impl std::fmt::Display for Firmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Firmware::Pflash {
+                code_path,
+                vars_path,
+                requires_smm,
+            } => {
+                write!(f, "pflash code={} vars={}", code_path, vars_path)?;
+                if *requires_smm {
+                    write!(f, " smm=on")?;
+                }
+                Ok(())
+            }
+            Firmware::UBoot { image_path } => write!(f, "u-boot {}", image_path),
+        }
+    }
+}
+
+impl std::fmt::Display for ResolvedFirmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.origin {
+            FirmwareOrigin::Configured => write!(f, "{} (configured)", self.firmware),
+            FirmwareOrigin::Descriptor(path) => {
+                write!(f, "{} (descriptor {})", self.firmware, path)
+            }
+            FirmwareOrigin::Fallback => write!(f, "{} (no descriptor matched)", self.firmware),
+        }
+    }
+}
+
+fn network_args(network: &NetworkOptions, gdb_port: Option<u16>) -> Result<Vec<String>> {
+    let netdev = match network.mode {
+        // without this the default machine adds a NIC of its own
+        NetworkMode::None => return Ok(vec!["-nic".to_string(), "none".to_string()]),
+        NetworkMode::User => {
+            let mut netdev = String::from("user,id=net0");
+            for hostfwd in network.hostfwd {
+                if let Some(gdb_port) = gdb_port
+                    && hostfwd_host_port(hostfwd) == Some(gdb_port)
+                {
+                    bail!(
+                        "host forward '{}' uses port {}, which the gdbstub listens on; \
+                         move one of them (debugger.gdb_port / --gdb-port)",
+                        hostfwd,
+                        gdb_port
+                    );
+                }
+                netdev.push_str(&format!(",hostfwd={}", hostfwd));
+            }
+            netdev
+        }
+        NetworkMode::Socket => match network.socket {
+            Some(SocketBackend::Multicast(group)) => format!("socket,id=net0,mcast={}", group),
+            Some(SocketBackend::Listen(address)) => format!("socket,id=net0,listen={}", address),
+            Some(SocketBackend::Connect(address)) => {
+                format!("socket,id=net0,connect={}", address)
+            }
+            None => bail!("socket networking needs a multicast group, listen or connect address"),
+        },
+    };
 
+    let mut device = format!("{},netdev=net0", nic_device(network.nic));
+    if let Some(mac) = network.mac {
+        device.push_str(&format!(",mac={}", mac));
+    }
+
+    let mut args = vec!["-netdev".to_string(), netdev, "-device".to_string(), device];
+    if let Some(path) = network.capture {
+        args.push("-object".to_string());
+        args.push(format!("filter-dump,id=dump0,netdev=net0,file={}", path));
+    }
+    Ok(args)
+}
+
+fn nic_device(nic: NicModel) -> &'static str {
+    match nic {
+        NicModel::E1000 => "e1000",
+        NicModel::VirtioNet => "virtio-net-pci",
+        NicModel::Rtl8139 => "rtl8139",
+    }
+}
+
+// "tcp:127.0.0.1:8080-:80" -> 8080; None for udp rules
+fn hostfwd_host_port(hostfwd: &str) -> Option<u16> {
+    let (host, _guest) = hostfwd.split_once('-')?;
+    if host.starts_with("udp:") {
+        return None;
+    }
+    host.rsplit(':').next()?.parse().ok()
+}
+
+// The store a non-fresh `vars` keeps between runs.
+pub fn kept_vars_path(
+    out_base: &Utf8Path,
+    vars: VarsStore,
+    template_path: &Utf8Path,
+) -> Option<Utf8PathBuf> {
+    match vars {
+        VarsStore::Fresh => None,
+        VarsStore::Persistent => {
+            Some(out_base.join(template_path.file_name().unwrap_or("VARS.fd")))
+        }
+        VarsStore::File(path) => Some(path.to_path_buf()),
+    }
+}
+
+// Start the store at `store_path` from the template, unless it exists already.
+pub fn ensure_vars_store(store_path: &Utf8Path, template_path: &Utf8Path) -> Result<()> {
+    if store_path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = store_path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("create directory: {}", parent))?;
+    }
+    std::fs::copy(template_path, store_path)
+        .with_context(|| format!("copy firmware vars: {} -> {}", template_path, store_path))?;
+    eprintln!(
+        "[vars] new variable store {} (from {})",
+        store_path, template_path
+    );
+    Ok(())
+}
+
+// A unique copy of the template, so that parallel runs never share one.
+fn fresh_vars_copy(template_path: &Utf8Path) -> Result<Utf8PathBuf> {
+    static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+    let temp_dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+        .map_err(|_| anyhow::anyhow!("temporary directory is not valid utf-8"))?;
+    let fresh_path = temp_dir.join(format!(
+        "spencer-{}-{}-{}",
+        std::process::id(),
+        COPIES.fetch_add(1, Ordering::Relaxed),
+        template_path.file_name().unwrap_or("VARS.fd")
+    ));
+    std::fs::copy(template_path, &fresh_path)
+        .with_context(|| format!("copy firmware vars: {} -> {}", template_path, fresh_path))?;
+    Ok(fresh_path)
+}
+
+struct PreparedQemu {
+    command: Command,
+    // to delete once QEMU has ended
+    fresh_vars: Option<Utf8PathBuf>,
+}
+
+pub fn run_qemu(args: &RunQemuArgs) -> Result<()> {
+    let machine = qemu_machine(&args.arch);
+
+    let Some(prepared) = prepare_qemu(args, &machine)? else {
+        return Ok(());
+    };
+
+    let result = run_command(prepared.command, args.verbose, machine.binary);
+    if let Some(fresh_vars) = prepared.fresh_vars {
+        let _ = std::fs::remove_file(fresh_vars);
+    }
+
+    result
+}
+
+// Start QEMU in the background; it is stopped when the returned guard is dropped.
+pub fn spawn_qemu(args: &RunQemuArgs) -> Result<Option<ChildGuard>> {
+    spawn_qemu_with_output(args, Stdio::inherit(), Stdio::inherit())
+}
+
+// Like `spawn_qemu`, with QEMU's stdout (the serial console for
+// `SerialTarget::Stdio`) and stderr readable through the guard.
+pub fn spawn_qemu_captured(args: &RunQemuArgs) -> Result<Option<ChildGuard>> {
+    spawn_qemu_with_output(args, Stdio::piped(), Stdio::piped())
+}
+
+fn spawn_qemu_with_output(
+    args: &RunQemuArgs,
+    stdout: Stdio,
+    stderr: Stdio,
+) -> Result<Option<ChildGuard>> {
+    let machine = qemu_machine(&args.arch);
+
+    let Some(PreparedQemu {
+        mut command,
+        fresh_vars,
+    }) = prepare_qemu(args, &machine)?
+    else {
+        return Ok(None);
+    };
+
+    // keep the terminal's Ctrl-C away from QEMU (the foreground process owns it)
+    command.process_group(0);
+    command.stdin(Stdio::null());
+    command.stdout(stdout);
+    command.stderr(stderr);
+
+    let child = spawn_command(command, args.verbose, machine.binary);
+    let child = match (child, fresh_vars) {
+        (Ok(mut child), Some(fresh_vars)) => {
+            child.remove_on_drop(fresh_vars.into_std_path_buf());
+            child
+        }
+        (Ok(child), None) => child,
+        (Err(error), fresh_vars) => {
+            if let Some(fresh_vars) = fresh_vars {
+                let _ = std::fs::remove_file(fresh_vars);
+            }
+            return Err(error);
+        }
+    };
+
+    Ok(Some(child))
+}
+
+fn prepare_qemu(args: &RunQemuArgs, machine: &QemuMachine) -> Result<Option<PreparedQemu>> {
     if args.platform != Platform::Qemu {
-        bail!("run_qemu_x86_64 called with non-qemu platform");
+        bail!("{} called with non-qemu platform", machine.binary);
     }
 
+    let requires_smm = matches!(
+        args.firmware,
+        Firmware::Pflash {
+            requires_smm: true,
+            ..
+        }
+    );
+    let machine_type = match (args.machine.machine.or(machine.machine), requires_smm) {
+        (machine_type, false) => machine_type.map(str::to_string),
+        (None, true) => Some("q35,smm=on".to_string()),
+        (Some(machine_type), true)
+            if machine_type == "pc" || machine_type.starts_with("pc-i440fx") =>
+        {
+            bail!(
+                "the firmware needs SMM, which machine {} lacks; use --machine q35",
+                machine_type
+            )
+        }
+        (Some(machine_type), true) => Some(format!("{},smm=on", machine_type)),
+    };
+    let cpu = args.machine.cpu.unwrap_or(machine.cpu);
+    let accel = resolve_accelerator(&args.arch, args.machine.accel)?;
+    let network = network_args(&args.network, args.gdb_port)?;
+
     if args.dry_run {
-        eprintln!("[dry-run] qemu-system-x86_64 ...");
+        eprintln!("[dry-run] {} ...", machine.binary);
+        if let Some(machine_type) = machine_type {
+            eprintln!("[dry-run]   -M {}", machine_type);
+        }
+        eprintln!("[dry-run]   -accel {}", accel);
+        eprintln!("[dry-run]   -m {}", args.machine.memory);
+        eprintln!("[dry-run]   -cpu {}", cpu);
+        if let Some(smp) = args.machine.smp {
+            eprintln!("[dry-run]   -smp {}", smp);
+        }
+        for pair in network.chunks(2) {
+            eprintln!("[dry-run]   {}", pair.join(" "));
+        }
         eprintln!("[dry-run]   img: {}", args.img_path);
-        eprintln!("[dry-run]   OVMF_CODE: {}", args.ovmf_code_path);
-        eprintln!("[dry-run]   OVMF_VARS: {}", args.ovmf_vars_path);
-        return Ok(());
+        match args.firmware {
+            Firmware::Pflash {
+                code_path,
+                vars_path,
+                ..
+            } => {
+                eprintln!("[dry-run]   firmware code: {}", code_path);
+                if requires_smm {
+                    eprintln!("[dry-run]   -global driver=cfi.pflash01,property=secure,value=on");
+                }
+                match kept_vars_path(args.out_base, args.vars, vars_path) {
+                    Some(store_path) => eprintln!(
+                        "[dry-run]   firmware vars: {} (kept, from {})",
+                        store_path, vars_path
+                    ),
+                    None => {
+                        eprintln!("[dry-run]   firmware vars: fresh copy of {}", vars_path)
+                    }
+                }
+            }
+            Firmware::UBoot { image_path } => {
+                eprintln!("[dry-run]   u-boot: {}", image_path);
+            }
+        }
+        match args.serial {
+            SerialTarget::Stdio => {}
+            SerialTarget::StdioLogged(path) => eprintln!("[dry-run]   serial log: {}", path),
+            SerialTarget::File(path) => eprintln!("[dry-run]   serial: {}", path),
+            SerialTarget::UnixSocket(path) => eprintln!("[dry-run]   serial socket: {}", path),
+            SerialTarget::Pty => eprintln!("[dry-run]   serial: pty"),
+        }
+        if let Some(path) = args.qmp_socket {
+            eprintln!("[dry-run]   qmp socket: {}", path);
+        }
+        if let Some(gdb_port) = args.gdb_port {
+            eprintln!("[dry-run]   -gdb tcp:127.0.0.1:{}", gdb_port);
+        }
+        if args.stop_at_start {
+            eprintln!("[dry-run]   -S");
+        }
+        if args.headless {
+            eprintln!("[dry-run]   -display none");
+        }
+        if args.test_exit {
+            eprintln!("[dry-run]   test exit: {:?}", machine.test_exit_device);
+        }
+        return Ok(None);
     }
 
-    let ovmf_vars_runtime = args.out_base.join("OVMF_VARS.fd");
-    std::fs::copy(
-        args.ovmf_vars_path.as_std_path(),
-        ovmf_vars_runtime.as_std_path(),
-    )
-    .with_context(|| {
-        format!(
-            "copy OVMF_VARS: {} -> {}",
-            args.ovmf_vars_path, ovmf_vars_runtime
-        )
-    })?;
-
-    let mut command = Command::new("qemu-system-x86_64");
-    command.arg("-m").arg("4G");
-    command.arg("-cpu").arg("max");
-    command.arg("-net").arg("none");
-    command.arg("-serial").arg("mon:stdio");
-
-    command.arg("-drive").arg(format!(
-        "if=pflash,format=raw,readonly=on,file={}",
-        args.ovmf_code_path
-    ));
+    let mut command = Command::new(machine.binary);
+    let mut fresh_vars = None;
+    if let Some(machine_type) = machine_type {
+        command.arg("-M").arg(machine_type);
+    }
+    command.arg("-accel").arg(accel);
+    command.arg("-m").arg(args.machine.memory);
+    command.arg("-cpu").arg(cpu);
+    if let Some(smp) = args.machine.smp {
+        command.arg("-smp").arg(smp);
+    }
 
-    command
-        .arg("-drive")
-        .arg(format!("if=pflash,format=raw,file={}", ovmf_vars_runtime));
+    match args.serial {
+        SerialTarget::Stdio => {
+            command.arg("-serial").arg("mon:stdio");
+        }
+        SerialTarget::StdioLogged(path) => {
+            // what mon:stdio expands to, plus the log
+            command.arg("-chardev").arg(format!(
+                "stdio,id=serial0,mux=on,signal=off,logfile={}",
+                path
+            ));
+            command.arg("-serial").arg("chardev:serial0");
+            command.arg("-mon").arg("chardev=serial0,mode=readline");
+        }
+        SerialTarget::File(path) => {
+            command.arg("-serial").arg(format!("file:{}", path));
+        }
+        SerialTarget::UnixSocket(path) => {
+            // a stale socket from an earlier run would make QEMU fail to bind
+            let _ = std::fs::remove_file(path);
+            command
+                .arg("-chardev")
+                .arg(format!("socket,id=serial0,path={},server=on,wait=on", path));
+            command.arg("-serial").arg("chardev:serial0");
+        }
+        SerialTarget::Pty => {
+            command.arg("-chardev").arg("pty,id=serial0");
+            command.arg("-serial").arg("chardev:serial0");
+        }
+    }
+
+    if let Some(path) = args.qmp_socket {
+        let _ = std::fs::remove_file(path);
+        command
+            .arg("-qmp")
+            .arg(format!("unix:{},server=on,wait=off", path));
+    }
 
-    command
-        .arg("-drive")
-        .arg(format!("format=raw,file={}", args.img_path));
+    match args.firmware {
+        Firmware::Pflash {
+            code_path,
+            vars_path,
+            requires_smm,
+        } => {
+            if *requires_smm {
+                // only SMM code may write the variable store
+                command
+                    .arg("-global")
+                    .arg("driver=cfi.pflash01,property=secure,value=on");
+            }
+            let vars_runtime = match kept_vars_path(args.out_base, args.vars, vars_path) {
+                Some(store_path) => {
+                    ensure_vars_store(&store_path, vars_path)?;
+                    store_path
+                }
+                None => {
+                    let fresh_path = fresh_vars_copy(vars_path)?;
+                    fresh_vars = Some(fresh_path.clone());
+                    fresh_path
+                }
+            };
 
-    command
-        .arg("-netdev")
-        .arg("user,id=net0,hostfwd=tcp:127.0.0.1:1234-:80");
-    command.arg("-device").arg("e1000,netdev=net0");
+            command.arg("-drive").arg(format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                code_path
+            ));
+
+            command
+                .arg("-drive")
+                .arg(format!("if=pflash,format=raw,unit=1,file={}", vars_runtime));
+        }
+        Firmware::UBoot { image_path } => {
+            command.arg("-bios").arg("default");
+            command.arg("-kernel").arg(image_path);
+        }
+    }
 
-    if args.enable_gdb {
-        command.arg("-s");
+    match machine.disk_device {
+        Some(disk_device) => {
+            command.arg("-drive").arg(format!(
+                "if=none,id=disk0,format=raw,file={}",
+                args.img_path
+            ));
+            command
+                .arg("-device")
+                .arg(format!("{},drive=disk0", disk_device));
+        }
+        None => {
+            command
+                .arg("-drive")
+                .arg(format!("format=raw,file={}", args.img_path));
+        }
+    }
+
+    command.args(&network);
+
+    if let Some(gdb_port) = args.gdb_port {
+        command
+            .arg("-gdb")
+            .arg(format!("tcp:127.0.0.1:{}", gdb_port));
     }
     if args.stop_at_start {
         command.arg("-S");
     }
 
-    run_command(command, args.verbose, "qemu-system-x86_64")?;
+    if args.headless {
+        command.arg("-display").arg("none");
+    }
+
+    if args.test_exit {
+        // a crash or reset ends the run instead of booting again
+        command.arg("-no-reboot");
+        match machine.test_exit_device {
+            TestExitDevice::IsaDebugExit => {
+                command
+                    .arg("-device")
+                    .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+            }
+            TestExitDevice::Semihosting => {
+                command
+                    .arg("-semihosting-config")
+                    .arg("enable=on,target=native");
+            }
+            TestExitDevice::SifiveTest => {}
+        }
+    }
 
-    Ok(())
+    Ok(Some(PreparedQemu {
+        command,
+        fresh_vars,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_network(hostfwd: &[String]) -> NetworkOptions<'_> {
+        NetworkOptions {
+            mode: NetworkMode::User,
+            nic: NicModel::E1000,
+            mac: None,
+            hostfwd,
+            socket: None,
+            capture: None,
+        }
+    }
+
+    #[test]
+    fn reads_hostfwd_host_ports() {
+        assert_eq!(hostfwd_host_port("tcp:127.0.0.1:8080-:80"), Some(8080));
+        assert_eq!(hostfwd_host_port("tcp::2222-:22"), Some(2222));
+        assert_eq!(hostfwd_host_port("::2222-10.0.2.15:22"), Some(2222));
+        // a udp port cannot collide with the gdbstub's tcp one
+        assert_eq!(hostfwd_host_port("udp::1234-:1234"), None);
+        // QEMU picks the host port
+        assert_eq!(hostfwd_host_port("tcp::-:22"), None);
+        assert_eq!(hostfwd_host_port("tcp::2222"), None);
+    }
+
+    #[test]
+    fn rejects_a_hostfwd_on_the_gdbstub_port() {
+        let hostfwd = ["tcp::2222-:22".to_string(), "tcp::1234-:80".to_string()];
+        let network = user_network(&hostfwd);
+
+        let error = network_args(&network, Some(GDB_STUB_PORT)).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("host forward 'tcp::1234-:80' uses port 1234")
+        );
+        // fine without a debugger, or with the gdbstub moved
+        assert!(network_args(&network, None).is_ok());
+        assert!(network_args(&network, Some(1235)).is_ok());
+
+        let udp = ["udp::1234-:1234".to_string(), "tcp::-:22".to_string()];
+        assert!(network_args(&user_network(&udp), Some(GDB_STUB_PORT)).is_ok());
+    }
+
+    #[test]
+    fn builds_user_netdevs() {
+        let hostfwd = ["tcp::2222-:22".to_string(), "udp::5353-:53".to_string()];
+        let network = NetworkOptions {
+            nic: NicModel::VirtioNet,
+            mac: Some("52:54:00:12:34:57"),
+            ..user_network(&hostfwd)
+        };
+        assert_eq!(
+            network_args(&network, None).unwrap(),
+            [
+                "-netdev",
+                "user,id=net0,hostfwd=tcp::2222-:22,hostfwd=udp::5353-:53",
+                "-device",
+                "virtio-net-pci,netdev=net0,mac=52:54:00:12:34:57",
+            ]
+        );
+
+        let network = NetworkOptions {
+            mode: NetworkMode::None,
+            ..user_network(&hostfwd)
+        };
+        assert_eq!(network_args(&network, None).unwrap(), ["-nic", "none"]);
+    }
+
+    #[test]
+    fn builds_socket_netdevs() {
+        let cases = [
+            (
+                SocketBackend::Multicast("230.0.0.1:5100".to_string()),
+                "socket,id=net0,mcast=230.0.0.1:5100",
+            ),
+            (
+                SocketBackend::Listen(":5101".to_string()),
+                "socket,id=net0,listen=:5101",
+            ),
+            (
+                SocketBackend::Connect("127.0.0.1:5101".to_string()),
+                "socket,id=net0,connect=127.0.0.1:5101",
+            ),
+        ];
+        let capture = Utf8Path::new("/out/net.pcap");
+        for (socket, netdev) in &cases {
+            let network = NetworkOptions {
+                mode: NetworkMode::Socket,
+                socket: Some(socket),
+                capture: Some(capture),
+                ..user_network(&[])
+            };
+            assert_eq!(
+                network_args(&network, Some(GDB_STUB_PORT)).unwrap(),
+                [
+                    "-netdev",
+                    netdev,
+                    "-device",
+                    "e1000,netdev=net0",
+                    "-object",
+                    "filter-dump,id=dump0,netdev=net0,file=/out/net.pcap",
+                ]
+            );
+        }
+
+        let network = NetworkOptions {
+            mode: NetworkMode::Socket,
+            ..user_network(&[])
+        };
+        assert!(network_args(&network, None).is_err());
+    }
 }
@@ -0,0 +1,217 @@
+use crate::steps::process::ChildGuard;
+use anyhow::{Context, Result, bail};
+use camino::Utf8Path;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+// how long one command may take before the connection is considered dead
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// time the guest gets to act on a power-down request before QEMU is told to quit
+const POWERDOWN_GRACE: Duration = Duration::from_secs(2);
+
+// Client for QEMU's machine protocol (QMP) on the socket given by
+// `RunQemuArgs::qmp_socket`.
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+// An error reply; `class` is e.g. "GenericError" or "CommandNotFound".
+#[derive(Debug, Deserialize)]
+pub struct QmpError {
+    pub class: String,
+    pub desc: String,
+}
+
+impl std::fmt::Display for QmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.desc, self.class)
+    }
+}
+
+impl std::error::Error for QmpError {}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct VmStatus {
+    pub running: bool,
+    // "running", "paused", "shutdown", "guest-panicked", ...
+    pub status: String,
+}
+
+impl Qmp {
+    // Connect and leave capabilities negotiation, retrying until QEMU has
+    // created the socket.
+    pub fn connect(socket_path: &Utf8Path, connect_timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + connect_timeout;
+        let stream = loop {
+            match UnixStream::connect(socket_path) {
+                Ok(stream) => break stream,
+                Err(error) if Instant::now() >= deadline => {
+                    return Err(error)
+                        .with_context(|| format!("connect to QMP socket: {}", socket_path));
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        stream
+            .set_read_timeout(Some(REPLY_TIMEOUT))
+            .context("set QMP read timeout")?;
+
+        let writer = stream.try_clone().context("clone QMP socket for writing")?;
+        let mut qmp = Self {
+            reader: BufReader::new(stream),
+            writer,
+        };
+
+        let greeting = qmp.read_message().context("read QMP greeting")?;
+        if greeting.get("QMP").is_none() {
+            bail!("not a QMP server: {}", greeting);
+        }
+        qmp.execute("qmp_capabilities", None)?;
+
+        Ok(qmp)
+    }
+
+    // Run a command and return its "return" value.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut line = request.to_string();
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .with_context(|| format!("send QMP command: {}", command))?;
+
+        loop {
+            let mut message = self
+                .read_message()
+                .with_context(|| format!("wait for QMP reply: {}", command))?;
+            // asynchronous events (STOP, RESUME, SHUTDOWN, ...) are not replies
+            if message.get("event").is_some() {
+                continue;
+            }
+            if let Some(error) = message.get_mut("error") {
+                let error: QmpError = serde_json::from_value(error.take())?;
+                return Err(anyhow::Error::new(error).context(format!("QMP {}", command)));
+            }
+            return Ok(message
+                .get_mut("return")
+                .map(Value::take)
+                .unwrap_or(Value::Null));
+        }
+    }
+
+    // Run a monitor (HMP) command, for what QMP has no direct equivalent of.
+    pub fn human_command(&mut self, command_line: &str, cpu_index: Option<u32>) -> Result<String> {
+        let mut arguments = json!({ "command-line": command_line });
+        if let Some(cpu_index) = cpu_index {
+            arguments["cpu-index"] = json!(cpu_index);
+        }
+        let output = self.execute("human-monitor-command", Some(arguments))?;
+        let output = output.as_str().unwrap_or_default().to_string();
+
+        // HMP reports failures as text
+        if let Some(message) = output.lines().find_map(|line| line.strip_prefix("Error: ")) {
+            bail!("{}: {}", command_line, message);
+        }
+        Ok(output)
+    }
+
+    pub fn status(&mut self) -> Result<VmStatus> {
+        let status = self.execute("query-status", None)?;
+        serde_json::from_value(status).context("parse query-status reply")
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        self.execute("stop", None).map(drop)
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        self.execute("cont", None).map(drop)
+    }
+
+    // Written by QEMU, so the path is resolved relative to QEMU's directory.
+    pub fn screendump(&mut self, path: &Utf8Path) -> Result<()> {
+        self.execute("screendump", Some(json!({ "filename": path.as_str() })))
+            .map(drop)
+    }
+
+    // Snapshots live inside the disk images, which must all support them (qcow2).
+    pub fn save_snapshot(&mut self, name: &str) -> Result<()> {
+        self.human_command(&format!("savevm {}", name), None)
+            .map(drop)
+    }
+
+    pub fn load_snapshot(&mut self, name: &str) -> Result<()> {
+        self.human_command(&format!("loadvm {}", name), None)
+            .map(drop)
+    }
+
+    pub fn registers(&mut self, cpu_index: Option<u32>) -> Result<String> {
+        self.human_command("info registers", cpu_index)
+    }
+
+    pub fn inject_nmi(&mut self) -> Result<()> {
+        self.execute("inject-nmi", None).map(drop)
+    }
+
+    // ACPI power button (PSCI / SBI on the virt machines); the guest decides.
+    pub fn powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", None).map(drop)
+    }
+
+    // QEMU exits at once; the disk images are flushed.
+    pub fn quit(&mut self) -> Result<()> {
+        match self.execute("quit", None) {
+            Ok(_) => Ok(()),
+            Err(error) if error.downcast_ref::<QmpError>().is_some() => Err(error),
+            // QEMU may close the socket before the reply gets out
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("QMP connection closed");
+        }
+        serde_json::from_str(&line).with_context(|| format!("parse QMP message: {}", line.trim()))
+    }
+}
+
+// Stop a QEMU that is still running: ask the guest to power down, then tell
+// QEMU to quit, then leave the rest to the guard, which kills it.
+pub fn shut_down(qemu: &mut ChildGuard, qmp_socket: &Utf8Path) {
+    if !matches!(qemu.try_wait(), Ok(None)) {
+        return;
+    }
+    let Ok(mut qmp) = Qmp::connect(qmp_socket, Duration::ZERO) else {
+        return;
+    };
+
+    // a paused guest (e.g. stopped by the debugger) cannot react to a power-down
+    let running = qmp.status().is_ok_and(|status| status.running);
+    if running && qmp.powerdown().is_ok() && wait_for_exit(qemu, POWERDOWN_GRACE) {
+        return;
+    }
+    if qmp.quit().is_ok() {
+        wait_for_exit(qemu, Duration::from_secs(2));
+    }
+}
+
+fn wait_for_exit(qemu: &mut ChildGuard, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match qemu.try_wait() {
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+            Ok(None) | Err(_) => return false,
+            Ok(Some(_)) => return true,
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use crate::steps::process::{output_prefix, with_output_prefix};
+use anyhow::{Result, anyhow, bail};
+use std::collections::BTreeSet;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Mutex, mpsc};
+use std::time::{Duration, Instant};
+
+// A unit of the build pipeline; starts once every step in `deps` has finished.
+pub struct Step<'a> {
+    name: &'static str,
+    deps: Vec<&'static str>,
+    run: Box<dyn FnOnce() -> Result<()> + Send + 'a>,
+}
+
+impl<'a> Step<'a> {
+    pub fn new(
+        name: &'static str,
+        deps: &[&'static str],
+        run: impl FnOnce() -> Result<()> + Send + 'a,
+    ) -> Self {
+        Self {
+            name,
+            deps: deps.to_vec(),
+            run: Box::new(run),
+        }
+    }
+}
+
+// Result of a step, handed to the steps (and the caller) that depend on it.
+pub struct Output<T> {
+    value: Mutex<Option<T>>,
+}
+
+impl<T: Clone> Output<T> {
+    pub fn new() -> Self {
+        Self {
+            value: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, value: T) {
+        *self.value.lock().unwrap() = Some(value);
+    }
+
+    pub fn get(&self) -> Result<T> {
+        self.value
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("step output read before the step finished"))
+    }
+
+    pub fn into_inner(self) -> Result<T> {
+        self.value
+            .into_inner()
+            .unwrap()
+            .ok_or_else(|| anyhow!("step output read before the step finished"))
+    }
+}
+
+// Run `steps` with at most `jobs` of them at a time, each on its own thread.
+// After the first failure no new step is started; the running ones are waited
+// for and every failure is reported.
+pub fn run_steps(steps: Vec<Step>, jobs: usize, verbose: bool) -> Result<()> {
+    let names: BTreeSet<_> = steps.iter().map(|step| step.name).collect();
+    if names.len() != steps.len() {
+        bail!("duplicate step names in the pipeline");
+    }
+    for step in &steps {
+        if let Some(dep) = step.deps.iter().find(|dep| !names.contains(*dep)) {
+            bail!("step '{}' depends on unknown step '{}'", step.name, dep);
+        }
+    }
+
+    let jobs = jobs.max(1);
+    let started = Instant::now();
+
+    let mut pending = steps;
+    let mut finished = BTreeSet::new();
+    let mut errors = Vec::new();
+
+    // a pipeline run inside another prefixed task (e.g. one matrix
+    // combination) keeps the outer prefix: [x86_64-qemu-debug/kernel]
+    let outer_prefix = output_prefix();
+
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<(&'static str, Duration, Result<()>)>();
+        let mut running = 0;
+
+        loop {
+            while errors.is_empty() && running < jobs {
+                let Some(index) = pending
+                    .iter()
+                    .position(|step| step.deps.iter().all(|dep| finished.contains(dep)))
+                else {
+                    break;
+                };
+
+                let step = pending.remove(index);
+                let sender = sender.clone();
+                let prefix = match &outer_prefix {
+                    Some(outer_prefix) => format!("{}/{}", outer_prefix, step.name),
+                    None => step.name.to_string(),
+                };
+                running += 1;
+
+                scope.spawn(move || {
+                    let step_started = Instant::now();
+                    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        with_output_prefix(&prefix, step.run)
+                    }))
+                    .unwrap_or_else(|_| Err(anyhow!("step panicked")));
+                    let _ = sender.send((step.name, step_started.elapsed(), result));
+                });
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            let (name, elapsed, result) = receiver.recv().expect("a step is still running");
+            running -= 1;
+
+            match result {
+                Ok(()) => {
+                    if verbose {
+                        eprintln!("[{}] done in {:.1}s", name, elapsed.as_secs_f64());
+                    }
+                    finished.insert(name);
+                }
+                Err(error) => errors.push(error.context(format!("step '{}' failed", name))),
+            }
+        }
+    });
+
+    match errors.len() {
+        0 => {}
+        1 => return Err(errors.remove(0)),
+        _ => bail!(
+            "{} steps failed:\n  {}",
+            errors.len(),
+            errors
+                .iter()
+                .map(|error| format!("{:#}", error))
+                .collect::<Vec<_>>()
+                .join("\n  ")
+        ),
+    }
+
+    if !pending.is_empty() {
+        let names: Vec<_> = pending.iter().map(|step| step.name).collect();
+        bail!(
+            "steps never became ready (dependency cycle): {}",
+            names.join(", ")
+        );
+    }
+
+    if verbose {
+        eprintln!(
+            "[pipeline] finished in {:.1}s",
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A step that appends its name to `log` once it runs.
+    fn logged<'a>(
+        name: &'static str,
+        deps: &[&'static str],
+        log: &'a Mutex<Vec<&'static str>>,
+    ) -> Step<'a> {
+        Step::new(name, deps, move || {
+            log.lock().unwrap().push(name);
+            Ok(())
+        })
+    }
+
+    fn error_text(result: Result<()>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    #[test]
+    fn runs_steps_after_their_deps() {
+        let log = Mutex::new(Vec::new());
+        let steps = vec![
+            logged("image", &["kernel", "loader"], &log),
+            logged("loader", &["kernel"], &log),
+            logged("kernel", &[], &log),
+        ];
+        run_steps(steps, 4, false).unwrap();
+        assert_eq!(*log.lock().unwrap(), ["kernel", "loader", "image"]);
+    }
+
+    #[test]
+    fn keeps_to_the_jobs_limit() {
+        for jobs in [1, 2] {
+            let running = AtomicUsize::new(0);
+            let most = AtomicUsize::new(0);
+            let steps = ["a", "b", "c", "d", "e", "f"]
+                .into_iter()
+                .map(|name| {
+                    Step::new(name, &[], || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(20));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                })
+                .collect();
+            run_steps(steps, jobs, false).unwrap();
+            assert!(most.load(Ordering::SeqCst) <= jobs, "jobs {}", jobs);
+        }
+    }
+
+    #[test]
+    fn starts_nothing_after_a_failure() {
+        let log = Mutex::new(Vec::new());
+        let steps = vec![
+            Step::new("kernel", &[], || bail!("linker error")),
+            logged("loader", &[], &log),
+            logged("image", &["kernel"], &log),
+        ];
+        let error = error_text(run_steps(steps, 1, false));
+        assert_eq!(error, "step 'kernel' failed: linker error");
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_every_running_failure() {
+        let steps = vec![
+            Step::new("kernel", &[], || bail!("linker error")),
+            Step::new("loader", &[], || panic!("boom")),
+        ];
+        let error = error_text(run_steps(steps, 2, false));
+        assert!(error.starts_with("2 steps failed:"), "{}", error);
+        assert!(error.contains("step 'kernel' failed: linker error"));
+        assert!(error.contains("step 'loader' failed: step panicked"));
+    }
+
+    #[test]
+    fn rejects_bad_dependencies() {
+        let log = Mutex::new(Vec::new());
+
+        let steps = vec![logged("image", &["kernel"], &log)];
+        let error = error_text(run_steps(steps, 1, false));
+        assert_eq!(error, "step 'image' depends on unknown step 'kernel'");
+
+        let steps = vec![logged("kernel", &[], &log), logged("kernel", &[], &log)];
+        let error = error_text(run_steps(steps, 1, false));
+        assert_eq!(error, "duplicate step names in the pipeline");
+
+        let steps = vec![
+            logged("kernel", &[], &log),
+            logged("loader", &["image"], &log),
+            logged("image", &["loader"], &log),
+        ];
+        let error = error_text(run_steps(steps, 1, false));
+        assert_eq!(
+            error,
+            "steps never became ready (dependency cycle): loader, image"
+        );
+        assert_eq!(*log.lock().unwrap(), ["kernel"]);
+    }
+
+    #[test]
+    fn outputs_are_read_after_their_step() {
+        let output = Output::new();
+        assert!(output.get().is_err());
+
+        let steps = vec![
+            Step::new("kernel", &[], || {
+                output.set(7);
+                Ok(())
+            }),
+            Step::new("image", &["kernel"], || {
+                assert_eq!(output.get()?, 7);
+                Ok(())
+            }),
+        ];
+        run_steps(steps, 2, false).unwrap();
+        assert_eq!(output.into_inner().unwrap(), 7);
+    }
+}
@@ -0,0 +1,46 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A directory of its own under the system temp dir for one test, removed on
+// drop. Tests run in parallel, and so may whole test runs.
+pub struct ScratchDir(Utf8PathBuf);
+
+impl ScratchDir {
+    // `name` only makes the directory easier to recognize
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let temp_dir = Utf8PathBuf::from_path_buf(std::env::temp_dir()).unwrap();
+        let path = temp_dir.join(format!(
+            "xtask-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.0
+    }
+
+    pub fn join(&self, relative: impl AsRef<Utf8Path>) -> Utf8PathBuf {
+        self.0.join(relative)
+    }
+
+    // Write `contents` to `relative`, creating the directories on the way.
+    pub fn write(&self, relative: impl AsRef<Utf8Path>, contents: impl AsRef<[u8]>) -> Utf8PathBuf {
+        let path = self.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
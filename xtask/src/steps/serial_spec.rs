@@ -0,0 +1,497 @@
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::sync::LazyLock;
+
+// expect = ["A9N", "Hello, world!"]   # in order, each on a later line than the previous
+// golden = "boot.golden"               # whole output, rewritten by --bless
+//
+// [[normalize]]                        # applied before comparing with the golden file
+// pattern = "0x[0-9a-f]+"
+// replace = "0xADDR"
+//
+// Paths are relative to the spec file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SerialSpecFile {
+    #[serde(default)]
+    expect: Vec<String>,
+    golden: Option<Utf8PathBuf>,
+    #[serde(default)]
+    normalize: Vec<NormalizeRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NormalizeRule {
+    pattern: String,
+    replace: String,
+}
+
+pub struct CheckSerialArgs<'a> {
+    pub spec_path: &'a Utf8Path,
+    pub serial_log_path: &'a Utf8Path,
+    // rewrite the golden file from this run instead of comparing
+    pub bless: bool,
+}
+
+pub struct SerialCheck {
+    pub passed: bool,
+    pub report: String,
+}
+
+// lines to show around each golden difference, and before a missed expectation
+const DIFF_CONTEXT: usize = 3;
+const TAIL_LINES: usize = 10;
+
+pub fn check_serial(args: &CheckSerialArgs) -> Result<SerialCheck> {
+    let text = std::fs::read_to_string(args.spec_path)
+        .with_context(|| format!("read serial spec: {}", args.spec_path))?;
+    let spec: SerialSpecFile =
+        toml::from_str(&text).with_context(|| format!("parse serial spec: {}", args.spec_path))?;
+
+    let spec_dir = args.spec_path.parent().unwrap_or(Utf8Path::new("."));
+
+    let mut errors = Vec::new();
+    let expectations: Vec<Regex> = spec
+        .expect
+        .iter()
+        .filter_map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|error| errors.push(format!("expect \"{}\": {}", pattern, error)))
+                .ok()
+        })
+        .collect();
+    let normalize: Vec<(Regex, &str)> = spec
+        .normalize
+        .iter()
+        .filter_map(|rule| {
+            Regex::new(&rule.pattern)
+                .map(|pattern| (pattern, rule.replace.as_str()))
+                .map_err(|error| errors.push(format!("normalize \"{}\": {}", rule.pattern, error)))
+                .ok()
+        })
+        .collect();
+    if !errors.is_empty() {
+        bail!(
+            "invalid serial spec {}:\n  {}",
+            args.spec_path,
+            errors.join("\n  ")
+        );
+    }
+
+    let log = std::fs::read(args.serial_log_path)
+        .with_context(|| format!("read serial log: {}", args.serial_log_path))?;
+    let lines = clean_lines(&String::from_utf8_lossy(&log));
+
+    let mut passed = true;
+    let mut report = String::new();
+
+    // ordered expectations
+    let mut next_line = 0;
+    let mut missed = false;
+    for (index, expectation) in expectations.iter().enumerate() {
+        if missed {
+            writeln!(report, "  skip  {:>2}  /{}/", index + 1, expectation)?;
+            continue;
+        }
+
+        match lines[next_line..]
+            .iter()
+            .position(|line| expectation.is_match(line))
+        {
+            Some(offset) => {
+                let line_number = next_line + offset;
+                writeln!(
+                    report,
+                    "  ok    {:>2}  /{}/  line {}",
+                    index + 1,
+                    expectation,
+                    line_number + 1
+                )?;
+                next_line = line_number + 1;
+            }
+            None => {
+                writeln!(
+                    report,
+                    "  MISS  {:>2}  /{}/  not found after line {}",
+                    index + 1,
+                    expectation,
+                    next_line
+                )?;
+                missed = true;
+                passed = false;
+            }
+        }
+    }
+
+    if missed {
+        writeln!(report, "  last lines of output:")?;
+        for line in &lines[lines.len().saturating_sub(TAIL_LINES)..] {
+            writeln!(report, "  | {}", line)?;
+        }
+    }
+
+    // golden file
+    if let Some(golden) = &spec.golden {
+        let golden_path = if golden.is_absolute() {
+            golden.clone()
+        } else {
+            spec_dir.join(golden)
+        };
+
+        let actual: Vec<String> = lines
+            .iter()
+            .map(|line| {
+                normalize
+                    .iter()
+                    .fold(line.clone(), |line, (pattern, replace)| {
+                        pattern.replace_all(&line, *replace).into_owned()
+                    })
+            })
+            .collect();
+
+        if args.bless {
+            let mut contents = actual.join("\n");
+            contents.push('\n');
+            std::fs::write(&golden_path, contents)
+                .with_context(|| format!("write golden file: {}", golden_path))?;
+            writeln!(report, "  blessed {} ({} lines)", golden_path, actual.len())?;
+        } else if !golden_path.exists() {
+            writeln!(
+                report,
+                "  golden file {} does not exist; run with --bless to create it",
+                golden_path
+            )?;
+            passed = false;
+        } else {
+            let expected = std::fs::read_to_string(&golden_path)
+                .with_context(|| format!("read golden file: {}", golden_path))?;
+            let expected: Vec<&str> = expected.lines().collect();
+            let actual: Vec<&str> = actual.iter().map(String::as_str).collect();
+
+            let diff = unified_diff(&expected, &actual, DIFF_CONTEXT);
+            if diff.is_empty() {
+                writeln!(report, "  golden {} matches", golden_path)?;
+            } else {
+                writeln!(
+                    report,
+                    "  golden {} differs (- expected, + actual):",
+                    golden_path
+                )?;
+                for line in diff.lines() {
+                    writeln!(report, "  {}", line)?;
+                }
+                passed = false;
+            }
+        }
+    } else if args.bless {
+        writeln!(report, "  --bless: the spec has no golden file")?;
+    }
+
+    Ok(SerialCheck { passed, report })
+}
+
+// Serial output without carriage returns and terminal escape sequences
+// (firmware clears the screen and positions the cursor).
+fn clean_lines(text: &str) -> Vec<String> {
+    static ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]|\x1b[()][0-9A-Za-z]|\r").expect("valid regex")
+    });
+    ESCAPE
+        .replace_all(text, "")
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+enum DiffOp<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+// Longest-common-subsequence diff; beyond this many cells the changed middle
+// is reported as one replacement.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+fn diff_ops<'a>(expected: &[&'a str], actual: &[&'a str]) -> Vec<DiffOp<'a>> {
+    let prefix = expected
+        .iter()
+        .zip(actual)
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+
+    let old = &expected[prefix..expected.len() - suffix];
+    let new = &actual[prefix..actual.len() - suffix];
+
+    let mut ops: Vec<DiffOp> = expected[..prefix]
+        .iter()
+        .map(|line| DiffOp::Equal(line))
+        .collect();
+
+    if (old.len() + 1) * (new.len() + 1) > MAX_DIFF_CELLS {
+        ops.extend(old.iter().map(|line| DiffOp::Delete(line)));
+        ops.extend(new.iter().map(|line| DiffOp::Insert(line)));
+    } else {
+        // lcs[i][j]: length of the LCS of old[i..] and new[j..]
+        let width = new.len() + 1;
+        let mut lcs = vec![0u32; (old.len() + 1) * width];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                lcs[i * width + j] = if old[i] == new[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() || j < new.len() {
+            if i < old.len() && j < new.len() && old[i] == new[j] {
+                ops.push(DiffOp::Equal(old[i]));
+                i += 1;
+                j += 1;
+            } else if i < old.len()
+                && (j == new.len() || lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+            {
+                ops.push(DiffOp::Delete(old[i]));
+                i += 1;
+            } else {
+                ops.push(DiffOp::Insert(new[j]));
+                j += 1;
+            }
+        }
+    }
+
+    ops.extend(
+        expected[expected.len() - suffix..]
+            .iter()
+            .map(|line| DiffOp::Equal(line)),
+    );
+    ops
+}
+
+// Hunks in unified format; empty when the inputs are equal.
+fn unified_diff(expected: &[&str], actual: &[&str], context: usize) -> String {
+    let ops = diff_ops(expected, actual);
+
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, op) in ops.iter().enumerate() {
+        if matches!(op, DiffOp::Equal(_)) {
+            continue;
+        }
+        let start = index.saturating_sub(context);
+        let end = (index + context + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    // line numbers before each op
+    let mut old_line = Vec::with_capacity(ops.len());
+    let mut new_line = Vec::with_capacity(ops.len());
+    let (mut old_count, mut new_count) = (0, 0);
+    for op in &ops {
+        old_line.push(old_count);
+        new_line.push(new_count);
+        match op {
+            DiffOp::Equal(_) => {
+                old_count += 1;
+                new_count += 1;
+            }
+            DiffOp::Delete(_) => old_count += 1,
+            DiffOp::Insert(_) => new_count += 1,
+        }
+    }
+
+    let mut out = String::new();
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Insert(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Delete(_)))
+            .count();
+
+        // an empty range is numbered by the line before it
+        let first_line = |line: usize, len: usize| if len == 0 { line } else { line + 1 };
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            first_line(old_line[start], old_len),
+            old_len,
+            first_line(new_line[start], new_len),
+            new_len
+        );
+        for op in hunk {
+            let _ = match op {
+                DiffOp::Equal(line) => writeln!(out, " {}", line),
+                DiffOp::Delete(line) => writeln!(out, "-{}", line),
+                DiffOp::Insert(line) => writeln!(out, "+{}", line),
+            };
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::scratch::ScratchDir;
+
+    fn lines(count: usize) -> Vec<String> {
+        (1..=count).map(|line| format!("line {}", line)).collect()
+    }
+
+    fn diff(expected: &[String], actual: &[String]) -> String {
+        let expected: Vec<&str> = expected.iter().map(String::as_str).collect();
+        let actual: Vec<&str> = actual.iter().map(String::as_str).collect();
+        unified_diff(&expected, &actual, DIFF_CONTEXT)
+    }
+
+    fn hunk_headers(diff: &str) -> Vec<&str> {
+        diff.lines().filter(|line| line.starts_with("@@")).collect()
+    }
+
+    #[test]
+    fn equal_inputs_have_no_diff() {
+        assert_eq!(diff(&lines(5), &lines(5)), "");
+    }
+
+    #[test]
+    fn hunk_numbers_count_from_one() {
+        let expected = lines(20);
+        let mut actual = expected.clone();
+        actual[9] = "changed".to_string();
+
+        assert_eq!(
+            diff(&expected, &actual),
+            "@@ -7,7 +7,7 @@\n line 7\n line 8\n line 9\n-line 10\n+changed\n line 11\n line 12\n line 13\n"
+        );
+    }
+
+    #[test]
+    fn insertions_shift_the_new_numbers() {
+        let expected = lines(20);
+        let mut actual = expected.clone();
+        actual.insert(2, "new a".to_string());
+        actual.insert(3, "new b".to_string());
+        actual.remove(16);
+
+        assert_eq!(
+            hunk_headers(&diff(&expected, &actual)),
+            ["@@ -1,5 +1,7 @@", "@@ -12,7 +14,6 @@"]
+        );
+    }
+
+    #[test]
+    fn hunks_merge_when_their_context_overlaps() {
+        let expected = lines(30);
+
+        // six unchanged lines between the changes: contexts touch, one hunk
+        let mut actual = expected.clone();
+        actual[9] = "changed".to_string();
+        actual[16] = "changed".to_string();
+        assert_eq!(
+            hunk_headers(&diff(&expected, &actual)),
+            ["@@ -7,14 +7,14 @@"]
+        );
+
+        // seven unchanged lines: two hunks
+        let mut actual = expected.clone();
+        actual[9] = "changed".to_string();
+        actual[17] = "changed".to_string();
+        assert_eq!(
+            hunk_headers(&diff(&expected, &actual)),
+            ["@@ -7,7 +7,7 @@", "@@ -15,7 +15,7 @@"]
+        );
+    }
+
+    #[test]
+    fn empty_sides_use_the_preceding_line_number() {
+        assert_eq!(hunk_headers(&diff(&[], &lines(2))), ["@@ -0,0 +1,2 @@"]);
+        assert_eq!(hunk_headers(&diff(&lines(2), &[])), ["@@ -1,2 +0,0 @@"]);
+    }
+
+    #[test]
+    fn clean_lines_strips_escapes_and_carriage_returns() {
+        let text = "\x1b[2J\x1b[01;01HBdsDxe: loading\r\n\x1b[?25l\x1b(BA9N \x1b[1;32mok\x1b[0m\r\r\n\nlast";
+        assert_eq!(clean_lines(text), ["BdsDxe: loading", "A9N ok", "", "last"]);
+    }
+
+    // Writes the spec and log into a scratch directory and checks them.
+    fn run_check(name: &str, spec: &str, log: &str) -> SerialCheck {
+        let scratch = ScratchDir::new(&format!("serial-{}", name));
+        let spec_path = scratch.write("spec.toml", spec);
+        let serial_log_path = scratch.write("serial.log", log);
+
+        check_serial(&CheckSerialArgs {
+            spec_path: &spec_path,
+            serial_log_path: &serial_log_path,
+            bless: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn expectations_match_in_order() {
+        let check = run_check(
+            "order",
+            r#"expect = ["A9N", "init", "Hello"]"#,
+            "UEFI\nA9N booting\ninit started\nHello, world!\n",
+        );
+        assert!(check.passed);
+        assert_eq!(
+            check.report,
+            "  ok     1  /A9N/  line 2\n  ok     2  /init/  line 3\n  ok     3  /Hello/  line 4\n"
+        );
+    }
+
+    #[test]
+    fn a_miss_skips_the_rest_and_shows_the_tail() {
+        // "init" appears only before "Hello", so searching after it misses
+        let check = run_check(
+            "miss",
+            r#"expect = ["Hello", "init", "done"]"#,
+            "init started\nHello, world!\n",
+        );
+        assert!(!check.passed);
+        assert_eq!(
+            check.report,
+            "  ok     1  /Hello/  line 2\n  MISS   2  /init/  not found after line 2\n  skip   3  /done/\n  last lines of output:\n  | init started\n  | Hello, world!\n"
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_are_reported_together() {
+        let scratch = ScratchDir::new("serial-invalid");
+        let spec_path = scratch.write(
+            "spec.toml",
+            "expect = [\"(\"]\n[[normalize]]\npattern = \"[\"\nreplace = \"\"\n",
+        );
+
+        let error = check_serial(&CheckSerialArgs {
+            spec_path: &spec_path,
+            serial_log_path: Utf8Path::new("/nonexistent"),
+            bless: false,
+        });
+
+        let error = format!("{:#}", error.err().unwrap());
+        assert!(error.contains("expect \"(\""));
+        assert!(error.contains("normalize \"[\""));
+    }
+}
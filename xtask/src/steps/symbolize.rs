@@ -0,0 +1,377 @@
+use crate::cli::SymbolizeMode;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+// lines after a panic or exception header that are still searched for addresses
+const CRASH_WINDOW_LINES: usize = 32;
+
+// addresses below this are register values or error codes, never code
+const MIN_CODE_ADDRESS: u64 = 0x1000;
+
+// a line that starts a crash window
+static TRIGGER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)panic|exception|fault|abort|backtrace|stack ?trace|\btrap\b|\b(?:rip|eip|pc|elr(?:_el[123])?|sepc|mepc|ra|lr)\s*[:=]",
+    )
+    .expect("valid regex")
+});
+
+// 0x-prefixed hex, or bare hex of a full 64-bit width
+static ADDRESS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b0[xX]([0-9a-fA-F]{1,16})\b|\b([0-9a-fA-F]{16})\b").expect("valid regex")
+});
+
+#[derive(Clone, Debug)]
+pub struct SymbolizeArgs {
+    pub mode: SymbolizeMode,
+    // the ELFs the guest runs (kernel, init); each address is looked up in turn
+    pub elf_paths: Vec<Utf8PathBuf>,
+}
+
+impl SymbolizeArgs {
+    // the ELFs to annotate streamed output with; empty unless inline
+    pub fn inline_elfs(&self) -> &[Utf8PathBuf] {
+        match self.mode {
+            SymbolizeMode::Inline => &self.elf_paths,
+            _ => &[],
+        }
+    }
+}
+
+struct Module {
+    name: String,
+    loader: addr2line::Loader,
+    // (start, end) of .text, for the symbol table fallback
+    text: Option<(u64, u64)>,
+}
+
+// One address found in the serial output, resolved innermost frame first
+// (inlined functions, then the function they were inlined into).
+struct Resolved {
+    address: u64,
+    module: String,
+    frames: Vec<ResolvedFrame>,
+}
+
+struct ResolvedFrame {
+    function: Option<String>,
+    location: Option<String>,
+}
+
+// Picks the code addresses out of serial lines that follow a panic or
+// exception header (instruction pointers, backtrace frames).
+#[derive(Default)]
+struct AddressScanner {
+    // lines left in the current crash window
+    window: usize,
+    // received but not yet terminated by a newline
+    pending: Vec<u8>,
+}
+
+impl AddressScanner {
+    // The addresses on the lines `chunk` completed.
+    fn feed(&mut self, chunk: &[u8]) -> Vec<u64> {
+        self.pending.extend_from_slice(chunk);
+
+        let mut addresses = Vec::new();
+        while let Some(newline) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            addresses.extend(self.scan_line(line.trim_end()));
+        }
+        addresses
+    }
+
+    fn scan_line(&mut self, line: &str) -> Vec<u64> {
+        if TRIGGER.is_match(line) {
+            self.window = CRASH_WINDOW_LINES;
+        }
+        if self.window == 0 {
+            return Vec::new();
+        }
+        self.window -= 1;
+
+        ADDRESS
+            .captures_iter(line)
+            .filter_map(|captures| {
+                let digits = captures.get(1).or(captures.get(2))?.as_str();
+                u64::from_str_radix(digits, 16).ok()
+            })
+            .filter(|&address| address >= MIN_CODE_ADDRESS)
+            .collect()
+    }
+}
+
+// Watches serial output for crashes and resolves the addresses in them
+// against the DWARF of the guest's ELFs.
+pub struct CrashScanner {
+    modules: Vec<Module>,
+    addresses: AddressScanner,
+}
+
+impl CrashScanner {
+    // None when none of the ELFs can be read; unreadable ones are reported
+    // and skipped.
+    pub fn load(elf_paths: &[Utf8PathBuf]) -> Option<Self> {
+        let modules: Vec<Module> = elf_paths
+            .iter()
+            .filter_map(|elf_path| match load_module(elf_path) {
+                Ok(module) => Some(module),
+                Err(error) => {
+                    eprintln!("[symbolize] skipping {}: {:#}", elf_path, error);
+                    None
+                }
+            })
+            .collect();
+        if modules.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            modules,
+            addresses: AddressScanner::default(),
+        })
+    }
+
+    // Feed raw serial output; returns the annotations for the lines `chunk`
+    // completed, one per resolved address, ready to print.
+    pub fn feed(&mut self, chunk: &[u8]) -> String {
+        let mut annotations = String::new();
+        for address in self.addresses.feed(chunk) {
+            let Some(resolved) = self.resolve(address) else {
+                continue;
+            };
+            for text in describe(&resolved) {
+                annotations.push_str("    ~> ");
+                annotations.push_str(&text);
+                annotations.push('\n');
+            }
+        }
+        annotations
+    }
+
+    fn scan_line(&mut self, line: &str) -> Vec<Resolved> {
+        self.addresses
+            .scan_line(line)
+            .into_iter()
+            .filter_map(|address| self.resolve(address))
+            .collect()
+    }
+
+    fn resolve(&self, address: u64) -> Option<Resolved> {
+        self.modules.iter().find_map(|module| {
+            let frames = dwarf_frames(module, address)
+                .filter(|frames| !frames.is_empty())
+                .or_else(|| symbol_frame(module, address).map(|frame| vec![frame]))?;
+            Some(Resolved {
+                address,
+                module: module.name.clone(),
+                frames,
+            })
+        })
+    }
+}
+
+// Symbolize the crashes in a finished serial log and print them as a report.
+pub fn report_crashes(args: &SymbolizeArgs, serial_log_path: &Utf8Path) -> Result<()> {
+    if args.mode != SymbolizeMode::Report {
+        return Ok(());
+    }
+    // e.g. QEMU failed before the guest wrote anything
+    let Ok(log) = std::fs::read(serial_log_path) else {
+        return Ok(());
+    };
+    let Some(mut scanner) = CrashScanner::load(&args.elf_paths) else {
+        return Ok(());
+    };
+
+    let mut report = String::new();
+    let mut resolved_count = 0;
+    let text = String::from_utf8_lossy(&log);
+    for line in text.lines() {
+        let resolved = scanner.scan_line(line.trim_end());
+        if resolved.is_empty() {
+            continue;
+        }
+        resolved_count += resolved.len();
+
+        report.push_str(&format!("  | {}\n", line.trim_end()));
+        for resolved in &resolved {
+            for text in describe(resolved) {
+                report.push_str(&format!("      {}\n", text));
+            }
+        }
+    }
+
+    if resolved_count > 0 {
+        eprintln!(
+            "[crash] {} address(es) resolved in {}:",
+            resolved_count, serial_log_path
+        );
+        eprint!("{}", report);
+    }
+
+    Ok(())
+}
+
+fn load_module(elf_path: &Utf8Path) -> Result<Module> {
+    let loader = addr2line::Loader::new(elf_path)
+        .map_err(|error| anyhow::anyhow!("{}", error))
+        .with_context(|| format!("read DWARF: {}", elf_path))?;
+    let text = loader
+        .get_section_range(b".text")
+        .map(|range| (range.begin, range.end));
+
+    Ok(Module {
+        name: elf_path
+            .file_name()
+            .unwrap_or(elf_path.as_str())
+            .to_string(),
+        loader,
+        text,
+    })
+}
+
+fn dwarf_frames(module: &Module, address: u64) -> Option<Vec<ResolvedFrame>> {
+    let mut iter = module.loader.find_frames(address).ok()?;
+
+    let mut frames = Vec::new();
+    while let Ok(Some(frame)) = iter.next() {
+        let function = frame
+            .function
+            .as_ref()
+            .and_then(|function| function.demangle().ok())
+            .map(Cow::into_owned);
+        let location = frame.location.and_then(|location| {
+            let file = location.file?;
+            Some(match location.line {
+                Some(line) => format!("{}:{}", file, line),
+                None => file.to_string(),
+            })
+        });
+        if function.is_some() || location.is_some() {
+            frames.push(ResolvedFrame { function, location });
+        }
+    }
+    Some(frames)
+}
+
+// Without debug info only the symbol table is left; it matches any address
+// after a symbol, so the address must also lie in .text.
+fn symbol_frame(module: &Module, address: u64) -> Option<ResolvedFrame> {
+    let (start, end) = module.text?;
+    if !(start..end).contains(&address) {
+        return None;
+    }
+    let name = module.loader.find_symbol(address)?;
+    Some(ResolvedFrame {
+        function: Some(addr2line::demangle_auto(Cow::Borrowed(name), None).into_owned()),
+        location: None,
+    })
+}
+
+// "0xffff800000102a4c in a9n::kernel::fault at src/kernel/fault.cpp:57 (kernel.elf)",
+// then one "inlined into ..." line per enclosing frame
+fn describe(resolved: &Resolved) -> Vec<String> {
+    resolved
+        .frames
+        .iter()
+        .enumerate()
+        .map(|(index, frame)| {
+            let mut text = if index == 0 {
+                format!("{:#x} in ", resolved.address)
+            } else {
+                "  inlined into ".to_string()
+            };
+            text.push_str(frame.function.as_deref().unwrap_or("??"));
+            if let Some(location) = &frame.location {
+                text.push_str(" at ");
+                text.push_str(location);
+            }
+            if index == 0 {
+                text.push_str(&format!(" ({})", resolved.module));
+            }
+            text
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_crash_windows_are_searched() {
+        let mut scanner = AddressScanner::default();
+        assert!(
+            scanner
+                .scan_line("loaded init at 0xffff800000200000")
+                .is_empty()
+        );
+
+        // the header itself is in the window
+        assert_eq!(
+            scanner.scan_line("KERNEL PANIC at 0xffff800000102a4c"),
+            [0xffff800000102a4c]
+        );
+        assert_eq!(scanner.scan_line("  #1 0x401000"), [0x401000]);
+
+        for header in ["Page Fault", "RIP: 0x1", "elr_el1=0x0", "sepc = 0", "TRAP"] {
+            let mut scanner = AddressScanner::default();
+            scanner.scan_line(header);
+            assert_eq!(scanner.scan_line("0x2000"), [0x2000], "{}", header);
+        }
+        for not_a_header in ["trapped", "pcie ready", "array: 0"] {
+            let mut scanner = AddressScanner::default();
+            scanner.scan_line(not_a_header);
+            assert!(scanner.scan_line("0x2000").is_empty(), "{}", not_a_header);
+        }
+    }
+
+    #[test]
+    fn crash_window_spans_the_header_and_the_lines_after_it() {
+        let mut scanner = AddressScanner::default();
+        scanner.scan_line("backtrace:");
+        for frame in 1..CRASH_WINDOW_LINES {
+            assert_eq!(scanner.scan_line("0x2000"), [0x2000], "line {}", frame);
+        }
+        assert!(scanner.scan_line("0x2000").is_empty());
+
+        // another header opens a new window
+        scanner.scan_line("exception");
+        assert_eq!(scanner.scan_line("0x2000"), [0x2000]);
+    }
+
+    #[test]
+    fn picks_code_addresses() {
+        let mut scanner = AddressScanner::default();
+        scanner.scan_line("panic");
+        let cases: [(&str, &[u64]); 6] = [
+            // register values and error codes are not code
+            ("err=0x0 cr2=0xfff rip=0x1000", &[0x1000]),
+            ("frame ffff800000102a4c", &[0xffff800000102a4c]),
+            // bare hex only at full width, which plain numbers rarely have
+            ("count 123456 id deadbeef 0ffff800000102a4c", &[]),
+            ("0X00000000DEADBEEF", &[0xdeadbeef]),
+            // too wide for an address
+            ("0x1ffff800000102a4c", &[]),
+            ("ra=0x80200010, sp=0x80300000", &[0x80200010, 0x80300000]),
+        ];
+        for (line, addresses) in cases {
+            assert_eq!(scanner.scan_line(line), addresses, "{}", line);
+        }
+    }
+
+    #[test]
+    fn feed_scans_completed_lines() {
+        let mut scanner = AddressScanner::default();
+        assert!(scanner.feed(b"pani").is_empty());
+        assert!(scanner.feed(b"c!\r\nrip: 0xffff8000").is_empty());
+        assert_eq!(scanner.feed(b"00102a4c\r\n  #1 0x40"), [0xffff800000102a4c]);
+        assert_eq!(scanner.feed(b"1000\n\n0x2000\n"), [0x401000, 0x2000]);
+        assert_eq!(scanner.pending, b"");
+    }
+}
@@ -0,0 +1,239 @@
+use crate::cli::{Arch, Platform};
+use crate::config::target_key;
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use std::io::Read;
+
+// Everything the pipeline needs to know about an arch, in one place.
+#[derive(Clone, Debug)]
+pub struct TargetDesc {
+    // used for out/<arch>-..., A9N's -DARCH and the Nun target json name
+    pub arch_name: &'static str,
+
+    // UEFI removable-media default loader under /EFI/BOOT
+    pub uefi_boot_file_name: &'static str,
+
+    // a9nloader-rs cargo target; None when there is no UEFI target for the arch
+    pub loader_cargo_target: Option<&'static str>,
+    // loader artifact relative to out/<...>/
+    pub loader_output_path: &'static str,
+
+    // target dir name produced by the Nun custom target json
+    pub nun_target_triple: &'static str,
+    pub init_artifact_name: &'static str,
+
+    // kernel artifact relative to out/<...>/
+    pub kernel_output_path: &'static str,
+
+    // ELF e_machine of binaries built for the arch
+    pub elf_machine: u16,
+}
+
+const X86_64: TargetDesc = TargetDesc {
+    arch_name: "x86_64",
+    uefi_boot_file_name: "BOOTX64.EFI",
+    loader_cargo_target: Some("x86_64-unknown-uefi"),
+    loader_output_path: "a9nloader/a9nloader-rs.efi",
+    nun_target_triple: "x86_64-unknown-a9n",
+    init_artifact_name: "core",
+    kernel_output_path: "a9n/kernel.elf",
+    elf_machine: 0x3e,
+};
+
+const AARCH64: TargetDesc = TargetDesc {
+    arch_name: "aarch64",
+    uefi_boot_file_name: "BOOTAA64.EFI",
+    loader_cargo_target: Some("aarch64-unknown-uefi"),
+    loader_output_path: "a9nloader/a9nloader-rs.efi",
+    nun_target_triple: "aarch64-unknown-a9n",
+    init_artifact_name: "core",
+    kernel_output_path: "a9n/kernel.elf",
+    elf_machine: 0xb7,
+};
+
+const RISCV64: TargetDesc = TargetDesc {
+    arch_name: "riscv64",
+    uefi_boot_file_name: "BOOTRISCV64.EFI",
+    loader_cargo_target: None,
+    loader_output_path: "a9nloader/a9nloader-rs.efi",
+    nun_target_triple: "riscv64-unknown-a9n",
+    init_artifact_name: "core",
+    kernel_output_path: "a9n/kernel.elf",
+    elf_machine: 0xf3,
+};
+
+// x86_64-qemu-debug: one build configuration, named like its out directory
+pub fn build_key(arch: &Arch, platform: &Platform, release: bool) -> String {
+    format!(
+        "{}-{}",
+        target_key(arch, platform),
+        if release { "release" } else { "debug" }
+    )
+}
+
+// out/<build key>; every step of the configuration writes below it, and
+// `clean` deletes from it
+pub fn out_base(
+    repo_root: &Utf8Path,
+    arch: &Arch,
+    platform: &Platform,
+    release: bool,
+) -> Utf8PathBuf {
+    repo_root
+        .join("out")
+        .join(build_key(arch, platform, release))
+}
+
+pub fn target_desc(arch: &Arch) -> &'static TargetDesc {
+    match arch {
+        Arch::X86_64 => &X86_64,
+        Arch::Aarch64 => &AARCH64,
+        Arch::Riscv64 => &RISCV64,
+    }
+}
+
+impl TargetDesc {
+    pub fn boot_efi_image_path(&self) -> String {
+        format!("/EFI/BOOT/{}", self.uefi_boot_file_name)
+    }
+
+    pub fn loader_path(&self, out_base: &Utf8Path) -> Utf8PathBuf {
+        out_base.join(self.loader_output_path)
+    }
+
+    pub fn kernel_path(&self, out_base: &Utf8Path) -> Utf8PathBuf {
+        out_base.join(self.kernel_output_path)
+    }
+
+    pub fn init_path(&self, nun_target_dir: &Utf8Path, release: bool) -> Utf8PathBuf {
+        nun_target_dir
+            .join(self.nun_target_triple)
+            .join(if release { "release" } else { "debug" })
+            .join(self.init_artifact_name)
+    }
+}
+
+// The arch a 64-bit little-endian ELF was built for.
+pub fn arch_from_elf(elf_path: &Utf8Path) -> Result<Arch> {
+    let mut header = [0u8; 20];
+    std::fs::File::open(elf_path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .with_context(|| format!("read ELF header: {}", elf_path))?;
+
+    // EI_CLASS = ELFCLASS64, EI_DATA = ELFDATA2LSB
+    if &header[..4] != b"\x7fELF" || header[4] != 2 || header[5] != 1 {
+        bail!("not a 64-bit little-endian ELF: {}", elf_path);
+    }
+
+    let machine = u16::from_le_bytes([header[18], header[19]]);
+    Arch::value_variants()
+        .iter()
+        .find(|arch| target_desc(arch).elf_machine == machine)
+        .cloned()
+        .with_context(|| format!("unsupported ELF machine {:#x}: {}", machine, elf_path))
+}
+
+// What a step builds, for support checks and messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    Kernel,
+    Loader,
+    Init,
+}
+
+impl std::fmt::Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Component::Kernel => write!(f, "the A9N kernel"),
+            Component::Loader => write!(f, "a9nloader"),
+            Component::Init => write!(f, "Nun OS"),
+        }
+    }
+}
+
+struct SupportEntry {
+    arch: Arch,
+    platform: Platform,
+    // components that cannot be built for the combination, and why
+    unsupported: &'static [(Component, &'static str)],
+}
+
+// Every arch x platform combination the pipeline knows; anything missing
+// here is rejected before a step starts.
+const SUPPORT_MATRIX: &[SupportEntry] = &[
+    SupportEntry {
+        arch: Arch::X86_64,
+        platform: Platform::Qemu,
+        unsupported: &[],
+    },
+    SupportEntry {
+        arch: Arch::Aarch64,
+        platform: Platform::Qemu,
+        unsupported: &[],
+    },
+    SupportEntry {
+        arch: Arch::Riscv64,
+        platform: Platform::Qemu,
+        unsupported: &[(
+            Component::Loader,
+            "a9nloader-rs has no riscv64 UEFI cargo target",
+        )],
+    },
+];
+
+// Called by each step before it does any work, dry-run included.
+pub fn validate_supported(component: Component, arch: &Arch, platform: &Platform) -> Result<()> {
+    let name = target_key(arch, platform);
+
+    let Some(entry) = SUPPORT_MATRIX
+        .iter()
+        .find(|entry| entry.arch == *arch && entry.platform == *platform)
+    else {
+        bail!(
+            "{} is not a supported target (supported: {})",
+            name,
+            supported_for(None).join(", ")
+        );
+    };
+
+    if let Some((_, reason)) = entry
+        .unsupported
+        .iter()
+        .find(|(unsupported, _)| *unsupported == component)
+    {
+        bail!(
+            "{} cannot be built for {}: {} (supported for {}: {})",
+            component,
+            name,
+            reason,
+            component,
+            supported_for(Some(component)).join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+// The whole pipeline (kernel, loader, init) for one combination.
+pub fn validate_pipeline_supported(arch: &Arch, platform: &Platform) -> Result<()> {
+    [Component::Kernel, Component::Loader, Component::Init]
+        .into_iter()
+        .try_for_each(|component| validate_supported(component, arch, platform))
+}
+
+// Combinations that can build `component`, or that are known at all.
+fn supported_for(component: Option<Component>) -> Vec<String> {
+    SUPPORT_MATRIX
+        .iter()
+        .filter(|entry| {
+            component.is_none_or(|component| {
+                entry
+                    .unsupported
+                    .iter()
+                    .all(|(unsupported, _)| *unsupported != component)
+            })
+        })
+        .map(|entry| target_key(&entry.arch, &entry.platform))
+        .collect()
+}
@@ -0,0 +1,671 @@
+use crate::cli::{NetworkMode, SymbolizeMode};
+use crate::steps::guest_test::{
+    TestOutcome, decode_exit_status, forward_stderr, stop_qemu, tee_serial,
+};
+use crate::steps::process::{ChildGuard, catch_interrupts, interrupted};
+use crate::steps::qemu::{
+    NetworkOptions, RunQemuArgs, SerialTarget, SocketBackend, VarsStore, spawn_qemu_captured,
+    test_exit_device,
+};
+use crate::steps::qmp::Qmp;
+use crate::steps::serial_spec::{CheckSerialArgs, check_serial};
+use crate::steps::symbolize::{SymbolizeArgs, report_crashes};
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+// segment the instances join unless the topology names another
+const DEFAULT_MULTICAST: &str = "230.0.0.1:5100";
+
+// how long a listening instance gets to open its socket before the others start
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// multicast = "230.0.0.1:5100"         # segment of the instances without listen/connect
+//
+// [[instance]]
+// name = "server"                      # prefix of its output, and its out directory
+// init = "target/x86_64-nun/debug/echo-server"   # packed as /kernel/init.elf
+// listen = ":5101"                     # point-to-point link instead of the segment
+// daemon = true                        # not expected to exit; stopped with the rest
+//
+// [[instance]]
+// name = "client"
+// connect = "127.0.0.1:5101"
+// expect = "tests/client.expect"       # serial expectations, checked by `test`
+//
+// [[instance]]
+// name = "observer"
+// image = "out/observer.img"           # a ready disk image instead of init
+// mac = "52:54:00:12:35:80"
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopologyFile {
+    multicast: Option<String>,
+    #[serde(default)]
+    instance: Vec<InstanceEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstanceEntry {
+    name: String,
+    init: Option<Utf8PathBuf>,
+    image: Option<Utf8PathBuf>,
+    expect: Option<Utf8PathBuf>,
+    #[serde(default)]
+    daemon: bool,
+    mac: Option<String>,
+    multicast: Option<String>,
+    listen: Option<String>,
+    connect: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InstanceSpec {
+    pub name: String,
+    pub boot: InstanceBoot,
+    pub expect: Option<Utf8PathBuf>,
+    pub daemon: bool,
+    pub mac: String,
+    pub socket: SocketBackend,
+}
+
+#[derive(Clone, Debug)]
+pub enum InstanceBoot {
+    // the image built by the pipeline
+    Pipeline,
+    // the pipeline's kernel and loader with this init ELF
+    Init(Utf8PathBuf),
+    Image(Utf8PathBuf),
+}
+
+// Paths in the file are relative to the file.
+pub fn load_topology(topology_path: &Utf8Path) -> Result<Vec<InstanceSpec>> {
+    let text = std::fs::read_to_string(topology_path)
+        .with_context(|| format!("read topology: {}", topology_path))?;
+    parse_topology(&text, topology_path)
+}
+
+fn parse_topology(text: &str, topology_path: &Utf8Path) -> Result<Vec<InstanceSpec>> {
+    let file: TopologyFile =
+        toml::from_str(text).with_context(|| format!("parse topology: {}", topology_path))?;
+
+    let base_dir = topology_path.parent().unwrap_or(Utf8Path::new("."));
+    let resolve = |path: Utf8PathBuf| base_dir.join(path);
+    let multicast = file
+        .multicast
+        .unwrap_or_else(|| DEFAULT_MULTICAST.to_string());
+
+    let mut instances: Vec<InstanceSpec> = Vec::new();
+    let mut errors = Vec::new();
+    for (index, entry) in file.instance.into_iter().enumerate() {
+        let name = entry.name;
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            errors.push(format!(
+                "instance {}: name '{}' must be letters, digits, '-' and '_'",
+                index + 1,
+                name
+            ));
+        }
+        if instances.iter().any(|instance| instance.name == name) {
+            errors.push(format!("instance {}: name '{}' is taken", index + 1, name));
+        }
+
+        let boot = match (entry.init, entry.image) {
+            (None, None) => InstanceBoot::Pipeline,
+            (Some(init), None) => InstanceBoot::Init(resolve(init)),
+            (None, Some(image)) => InstanceBoot::Image(resolve(image)),
+            (Some(_), Some(_)) => {
+                errors.push(format!("{}: set init or image, not both", name));
+                InstanceBoot::Pipeline
+            }
+        };
+
+        let socket = match (entry.multicast, entry.listen, entry.connect) {
+            (None, None, None) => SocketBackend::Multicast(multicast.clone()),
+            (Some(group), None, None) => SocketBackend::Multicast(group),
+            (None, Some(address), None) => SocketBackend::Listen(address),
+            (None, None, Some(address)) => SocketBackend::Connect(address),
+            _ => {
+                errors.push(format!(
+                    "{}: set only one of multicast, listen and connect",
+                    name
+                ));
+                SocketBackend::Multicast(multicast.clone())
+            }
+        };
+
+        instances.push(InstanceSpec {
+            name,
+            boot,
+            expect: entry.expect.map(resolve),
+            daemon: entry.daemon,
+            // instances on one segment must not share QEMU's default MAC
+            mac: entry
+                .mac
+                .unwrap_or_else(|| format!("52:54:00:12:35:{:02x}", (index + 1) % 256)),
+            socket,
+        });
+    }
+
+    if instances.is_empty() {
+        errors.push("no [[instance]]".to_string());
+    }
+    if !errors.is_empty() {
+        bail!(
+            "invalid topology {}:\n  {}",
+            topology_path,
+            errors.join("\n  ")
+        );
+    }
+
+    Ok(instances)
+}
+
+pub struct TopologyInstance<'a> {
+    pub name: &'a str,
+    // serial must be Stdio; QEMU's output is captured and prefixed with the name
+    pub qemu: RunQemuArgs<'a>,
+    pub serial_log_path: &'a Utf8Path,
+    pub daemon: bool,
+    pub symbolize_elfs: &'a [Utf8PathBuf],
+}
+
+pub struct RunTopologyArgs<'a> {
+    pub instances: &'a [TopologyInstance<'a>],
+    // With a timeout the guests' verdicts are waited for, as in a test: until
+    // every instance but the daemons has ended, one of them failed, or the
+    // timeout passed. Without one the run ends when the first instance exits.
+    pub timeout: Option<Duration>,
+    pub dry_run: bool,
+}
+
+// Boot every instance, then shut all of them down together. Listening
+// instances start first. Returns each instance's outcome, or None in dry-run.
+pub fn run_topology(args: &RunTopologyArgs) -> Result<Option<Vec<TestOutcome>>> {
+    for instance in args.instances {
+        if !matches!(instance.qemu.serial, SerialTarget::Stdio) {
+            bail!("{}: serial must be stdio in a topology", instance.name);
+        }
+    }
+    if args.timeout.is_some() && args.instances.iter().all(|instance| instance.daemon) {
+        bail!("every instance is a daemon, so nothing would end the test");
+    }
+
+    let mut start_order: Vec<usize> = (0..args.instances.len()).collect();
+    start_order.sort_by_key(|&index| !is_listener(&args.instances[index]));
+
+    if args.dry_run {
+        for &index in &start_order {
+            let instance = &args.instances[index];
+            eprintln!("[dry-run] instance {}:", instance.name);
+            eprintln!("[dry-run]   serial log: {}", instance.serial_log_path);
+            spawn_qemu_captured(&instance.qemu)?;
+        }
+        if let Some(timeout) = args.timeout {
+            eprintln!("[dry-run] timeout: {}s", timeout.as_secs());
+        }
+        return Ok(None);
+    }
+
+    catch_interrupts()?;
+
+    let mut running: Vec<Option<ChildGuard>> = args.instances.iter().map(|_| None).collect();
+    let mut serial_threads = Vec::new();
+    for &index in &start_order {
+        let instance = &args.instances[index];
+        let mut qemu = spawn_qemu_captured(&instance.qemu)?.context("QEMU was not started")?;
+
+        let prefix = instance.name.to_string();
+        let stderr = qemu.take_stderr().context("QEMU stderr is not captured")?;
+        forward_stderr(stderr, Some(prefix.clone()));
+        let stdout = qemu.take_stdout().context("QEMU stdout is not captured")?;
+        serial_threads.push((
+            index,
+            tee_serial(
+                stdout,
+                instance.serial_log_path,
+                instance.symbolize_elfs,
+                Some(prefix),
+            )?,
+        ));
+
+        // QMP answers once QEMU has set up its netdevs, so the listening
+        // socket exists before anyone tries to connect to it
+        if is_listener(instance)
+            && let Some(qmp_socket) = instance.qemu.qmp_socket
+        {
+            Qmp::connect(qmp_socket, LISTEN_TIMEOUT)
+                .with_context(|| format!("{}: wait for QEMU to listen", instance.name))?;
+        }
+        running[index] = Some(qemu);
+    }
+
+    let deadline = args.timeout.map(|timeout| Instant::now() + timeout);
+    let mut statuses: Vec<Option<ExitStatus>> = args.instances.iter().map(|_| None).collect();
+    loop {
+        for (status, qemu) in statuses.iter_mut().zip(running.iter_mut()) {
+            if status.is_none()
+                && let Some(qemu) = qemu
+            {
+                *status = qemu.try_wait()?;
+            }
+        }
+        if interrupted() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+
+        let finished = match deadline {
+            None => statuses.iter().any(Option::is_some),
+            Some(_) => {
+                let outcomes: Vec<Option<TestOutcome>> = args
+                    .instances
+                    .iter()
+                    .zip(&statuses)
+                    .map(|(instance, status)| {
+                        status.map(|status| {
+                            decode_exit_status(test_exit_device(&instance.qemu.arch), status)
+                        })
+                    })
+                    .collect();
+                let failed = outcomes
+                    .iter()
+                    .flatten()
+                    .any(|outcome| *outcome != TestOutcome::Passed);
+                let waiting = args
+                    .instances
+                    .iter()
+                    .zip(&outcomes)
+                    .any(|(instance, outcome)| !instance.daemon && outcome.is_none());
+                failed || !waiting
+            }
+        };
+        if finished {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+    // each gets its own power-down grace period, at the same time
+    std::thread::scope(|scope| {
+        for (instance, qemu) in args.instances.iter().zip(running) {
+            if let Some(qemu) = qemu {
+                scope.spawn(move || stop_qemu(qemu, instance.qemu.qmp_socket));
+            }
+        }
+    });
+
+    // as in a single test, a reader whose QEMU had to be stopped is not waited for
+    for (index, serial_thread) in serial_threads {
+        if statuses[index].is_some() {
+            serial_thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("serial reader panicked"))?
+                .with_context(|| format!("{}: copy serial output", args.instances[index].name))?;
+        }
+    }
+
+    let outcomes = args
+        .instances
+        .iter()
+        .zip(statuses)
+        .map(|(instance, status)| match status {
+            Some(status) => decode_exit_status(test_exit_device(&instance.qemu.arch), status),
+            None if interrupted() => TestOutcome::Interrupted,
+            None if timed_out && !instance.daemon => TestOutcome::TimedOut,
+            None => TestOutcome::Stopped,
+        })
+        .collect();
+
+    Ok(Some(outcomes))
+}
+
+pub struct BootTopologyArgs<'a> {
+    pub topology_path: &'a Utf8Path,
+    // out/<key>/topology; each instance gets the directory of its name below it
+    pub topology_dir: &'a Utf8Path,
+
+    // what the pipeline built
+    pub img_path: &'a Utf8Path,
+    pub kernel_elf_path: &'a Utf8Path,
+    pub init_elf_path: &'a Utf8Path,
+
+    // shared by every instance; the disk, network, serial and QMP socket are
+    // replaced by the instance's own (only the NIC model and capture are kept)
+    pub qemu: RunQemuArgs<'a>,
+    pub symbolize: SymbolizeMode,
+
+    // as `test`: wait for the verdicts, then check each instance's expectations
+    pub test: bool,
+    pub timeout: Duration,
+    pub bless: bool,
+}
+
+// One instance of a topology, with the files it gets in its own directory.
+struct InstanceFiles {
+    spec: InstanceSpec,
+    instance_dir: Utf8PathBuf,
+    img_path: Utf8PathBuf,
+    serial_log_path: Utf8PathBuf,
+    qmp_socket_path: Utf8PathBuf,
+    capture_path: Utf8PathBuf,
+    symbolize: SymbolizeArgs,
+}
+
+// Boot the instances described at `topology_path`. `build_image` packs the
+// pipeline's kernel and loader with another init ELF (image path, init path).
+// As a test, returns the exit code of the first instance that failed (by its
+// verdict or its serial expectations).
+pub fn boot_topology(
+    args: &BootTopologyArgs,
+    build_image: impl Fn(&Utf8Path, &Utf8Path) -> Result<Utf8PathBuf>,
+) -> Result<i32> {
+    let specs = load_topology(args.topology_path)?;
+    if specs.len() > 1 && matches!(args.qemu.vars, VarsStore::File(_)) {
+        bail!("the instances of a topology cannot share one --vars-file");
+    }
+    let dry_run = args.qemu.dry_run;
+
+    let mut files = Vec::new();
+    for spec in specs {
+        let instance_dir = args.topology_dir.join(&spec.name);
+        if !dry_run {
+            std::fs::create_dir_all(&instance_dir)
+                .with_context(|| format!("create instance dir: {}", instance_dir))?;
+        }
+
+        let (img_path, init_elf_path) = match &spec.boot {
+            InstanceBoot::Pipeline => {
+                // QEMU locks a writable disk, so every instance boots its own copy
+                let img_path = instance_dir.join("spencer.img");
+                let source = args.img_path;
+                if dry_run {
+                    eprintln!("[dry-run] copy {} -> {}", source, img_path);
+                } else {
+                    std::fs::copy(source, &img_path)
+                        .with_context(|| format!("copy image: {} -> {}", source, img_path))?;
+                }
+                (img_path, args.init_elf_path.to_path_buf())
+            }
+            InstanceBoot::Init(elf_path) => (
+                build_image(&instance_dir.join("spencer.img"), elf_path)?,
+                elf_path.clone(),
+            ),
+            // whatever init it holds, the pipeline's is the best guess for symbols
+            InstanceBoot::Image(img_path) => (img_path.clone(), args.init_elf_path.to_path_buf()),
+        };
+
+        files.push(InstanceFiles {
+            img_path,
+            serial_log_path: instance_dir.join("serial.log"),
+            qmp_socket_path: instance_dir.join("qmp.sock"),
+            capture_path: instance_dir.join("net.pcap"),
+            symbolize: SymbolizeArgs {
+                mode: args.symbolize,
+                elf_paths: vec![args.kernel_elf_path.to_path_buf(), init_elf_path],
+            },
+            instance_dir,
+            spec,
+        });
+    }
+
+    let instances: Vec<_> = files
+        .iter()
+        .map(|instance| TopologyInstance {
+            name: &instance.spec.name,
+            qemu: RunQemuArgs {
+                out_base: &instance.instance_dir,
+                img_path: &instance.img_path,
+                // the topology decides how the instances are linked
+                network: NetworkOptions {
+                    mode: NetworkMode::Socket,
+                    nic: args.qemu.network.nic,
+                    mac: Some(&instance.spec.mac),
+                    hostfwd: &[],
+                    socket: Some(&instance.spec.socket),
+                    capture: args
+                        .qemu
+                        .network
+                        .capture
+                        .map(|_| instance.capture_path.as_path()),
+                },
+                serial: SerialTarget::Stdio,
+                qmp_socket: Some(&instance.qmp_socket_path),
+                gdb_port: None,
+                stop_at_start: false,
+                headless: args.test,
+                test_exit: args.test,
+                ..args.qemu.clone()
+            },
+            serial_log_path: &instance.serial_log_path,
+            daemon: instance.spec.daemon,
+            symbolize_elfs: instance.symbolize.inline_elfs(),
+        })
+        .collect();
+
+    let topology_args = RunTopologyArgs {
+        instances: &instances,
+        timeout: args.test.then_some(args.timeout),
+        dry_run,
+    };
+
+    let Some(outcomes) = run_topology(&topology_args)? else {
+        for instance in &files {
+            if let Some(spec_path) = instance.spec.expect.as_ref().filter(|_| args.test) {
+                eprintln!(
+                    "[dry-run] check {} serial against: {}",
+                    instance.spec.name, spec_path
+                );
+            }
+        }
+        return Ok(0);
+    };
+
+    let mut exit_code = 0;
+    for (instance, outcome) in files.iter().zip(outcomes) {
+        report_crashes(&instance.symbolize, &instance.serial_log_path)?;
+        if !args.test {
+            continue;
+        }
+
+        eprintln!(
+            "[test] {}: {} (serial: {})",
+            instance.spec.name, outcome, instance.serial_log_path
+        );
+        let mut instance_exit_code = outcome.exit_code();
+
+        if let Some(spec_path) = &instance.spec.expect {
+            let check = check_serial(&CheckSerialArgs {
+                spec_path,
+                serial_log_path: &instance.serial_log_path,
+                bless: args.bless,
+            })?;
+
+            eprintln!(
+                "[test] {}: serial expectations {} ({}):",
+                instance.spec.name,
+                if check.passed { "met" } else { "NOT met" },
+                spec_path
+            );
+            eprint!("{}", check.report);
+
+            if !check.passed && instance_exit_code == 0 {
+                instance_exit_code = 1;
+            }
+        }
+
+        if exit_code == 0 {
+            exit_code = instance_exit_code;
+        }
+    }
+
+    Ok(exit_code)
+}
+
+fn is_listener(instance: &TopologyInstance) -> bool {
+    matches!(instance.qemu.network.socket, Some(SocketBackend::Listen(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<InstanceSpec>> {
+        parse_topology(text, Utf8Path::new("/project/net/topology.toml"))
+    }
+
+    fn parse_error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn parses_instances_in_order() {
+        let instances = parse(
+            r#"
+            multicast = "230.0.0.2:6000"
+
+            [[instance]]
+            name = "server"
+            init = "bin/echo-server"
+            listen = ":5101"
+            daemon = true
+
+            [[instance]]
+            name = "client"
+            connect = "127.0.0.1:5101"
+            expect = "../tests/client.expect"
+
+            [[instance]]
+            name = "observer"
+            image = "/images/observer.img"
+            mac = "52:54:00:12:35:80"
+
+            [[instance]]
+            name = "peer_2"
+            "#,
+        )
+        .unwrap();
+
+        let names: Vec<&str> = instances
+            .iter()
+            .map(|instance| instance.name.as_str())
+            .collect();
+        assert_eq!(names, ["server", "client", "observer", "peer_2"]);
+
+        // relative paths are taken from the file's directory
+        let [server, client, observer, peer] = instances.as_slice() else {
+            panic!("four instances");
+        };
+        assert!(
+            matches!(&server.boot, InstanceBoot::Init(path) if path == "/project/net/bin/echo-server")
+        );
+        assert!(server.daemon);
+        assert!(matches!(&server.socket, SocketBackend::Listen(address) if address == ":5101"));
+
+        assert!(matches!(client.boot, InstanceBoot::Pipeline));
+        assert_eq!(
+            client.expect.as_deref(),
+            Some(Utf8Path::new("/project/net/../tests/client.expect"))
+        );
+        assert!(!client.daemon);
+        assert!(
+            matches!(&client.socket, SocketBackend::Connect(address) if address == "127.0.0.1:5101")
+        );
+
+        assert!(
+            matches!(&observer.boot, InstanceBoot::Image(path) if path == "/images/observer.img")
+        );
+        assert_eq!(observer.mac, "52:54:00:12:35:80");
+
+        // the file's segment, unless the instance names its own
+        assert!(
+            matches!(&peer.socket, SocketBackend::Multicast(group) if group == "230.0.0.2:6000")
+        );
+        assert_eq!(peer.expect, None);
+    }
+
+    #[test]
+    fn numbers_default_macs_by_position() {
+        let instances = parse(
+            r#"
+            [[instance]]
+            name = "a"
+            [[instance]]
+            name = "b"
+            mac = "52:54:00:00:00:01"
+            [[instance]]
+            name = "c"
+            multicast = "230.0.0.3:7000"
+            "#,
+        )
+        .unwrap();
+        let macs: Vec<&str> = instances
+            .iter()
+            .map(|instance| instance.mac.as_str())
+            .collect();
+        assert_eq!(
+            macs,
+            [
+                "52:54:00:12:35:01",
+                "52:54:00:00:00:01",
+                "52:54:00:12:35:03"
+            ]
+        );
+        assert!(
+            matches!(&instances[0].socket, SocketBackend::Multicast(group) if group == DEFAULT_MULTICAST)
+        );
+        assert!(
+            matches!(&instances[2].socket, SocketBackend::Multicast(group) if group == "230.0.0.3:7000")
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_instance() {
+        let error = parse_error(
+            r#"
+            [[instance]]
+            name = "web server"
+
+            [[instance]]
+            name = ""
+
+            [[instance]]
+            name = "client"
+            init = "bin/client"
+            image = "client.img"
+
+            [[instance]]
+            name = "client"
+            listen = ":5101"
+            connect = "127.0.0.1:5101"
+            "#,
+        );
+        assert!(error.starts_with("invalid topology /project/net/topology.toml:"));
+        for message in [
+            "instance 1: name 'web server' must be letters, digits, '-' and '_'",
+            "instance 2: name '' must be letters, digits, '-' and '_'",
+            "client: set init or image, not both",
+            "instance 4: name 'client' is taken",
+            "client: set only one of multicast, listen and connect",
+        ] {
+            assert!(error.contains(message), "{}: {}", message, error);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_topologies_and_unknown_keys() {
+        assert!(parse_error("").contains("no [[instance]]"));
+        assert!(parse_error("multicast = \"230.0.0.1:5100\"\n").contains("no [[instance]]"));
+
+        let error = parse_error("[[instance]]\nname = \"a\"\nnic = \"e1000\"\n");
+        assert!(error.starts_with("parse topology: /project/net/topology.toml"));
+        assert!(error.contains("unknown field `nic`"), "{}", error);
+    }
+}
@@ -0,0 +1,811 @@
+use anyhow::{Context, Result, bail};
+use camino::Utf8Path;
+
+// EFI_SYSTEM_NV_DATA_FV_GUID, the file system of the firmware volume
+const NV_DATA_FV_GUID: Guid = Guid::new(
+    0xfff12b8d,
+    0x7696,
+    0x4c8b,
+    [0xa9, 0x85, 0x27, 0x47, 0x07, 0x5b, 0x4f, 0x50],
+);
+// signatures of the variable store inside it
+const AUTHENTICATED_VARIABLE_GUID: Guid = Guid::new(
+    0xaaf32c78,
+    0x947b,
+    0x439a,
+    [0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92],
+);
+const VARIABLE_GUID: Guid = Guid::new(
+    0xddcf3616,
+    0x3275,
+    0x4164,
+    [0x98, 0xb6, 0xfe, 0x85, 0x70, 0x7f, 0xfe, 0x7d],
+);
+// EFI_GLOBAL_VARIABLE: Boot####, BootOrder, Timeout, ...
+pub const GLOBAL_VARIABLE_GUID: Guid = Guid::new(
+    0x8be4df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const STORE_HEADER_SIZE: usize = 28;
+const STORE_FORMATTED: u8 = 0x5a;
+const STORE_HEALTHY: u8 = 0xfe;
+
+const VARIABLE_START_ID: u16 = 0x55aa;
+// variable states; bits are cleared as a variable goes through its life
+const VAR_ADDED: u8 = 0x3f;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+const AUTHENTICATED_HEADER_SIZE: usize = 60;
+const HEADER_SIZE: usize = 32;
+
+pub const ATTRIBUTE_NON_VOLATILE: u32 = 0x01;
+pub const ATTRIBUTE_BOOTSERVICE_ACCESS: u32 = 0x02;
+pub const ATTRIBUTE_RUNTIME_ACCESS: u32 = 0x04;
+const ATTRIBUTE_NAMES: [(u32, &str); 7] = [
+    (ATTRIBUTE_NON_VOLATILE, "NV"),
+    (ATTRIBUTE_BOOTSERVICE_ACCESS, "BS"),
+    (ATTRIBUTE_RUNTIME_ACCESS, "RT"),
+    (0x08, "HR"),
+    (0x10, "AW"),
+    (0x20, "AT"),
+    (0x40, "AP"),
+];
+
+const LOAD_OPTION_ACTIVE: u32 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+            data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut guid = [0; 16];
+        guid.copy_from_slice(&bytes[..16]);
+        Guid(guid)
+    }
+}
+
+impl std::str::FromStr for Guid {
+    type Err = anyhow::Error;
+
+    // 8be4df61-93ca-11d2-aa0d-00e098032b8c
+    fn from_str(text: &str) -> Result<Self> {
+        let fields: Vec<&str> = text.split('-').collect();
+        let lengths: Vec<usize> = fields.iter().map(|field| field.len()).collect();
+        if lengths != [8, 4, 4, 4, 12] {
+            bail!("invalid GUID '{}'", text);
+        }
+        let hex = |field: &str| u64::from_str_radix(field, 16);
+        let parse = || -> std::result::Result<Guid, std::num::ParseIntError> {
+            let tail = hex(fields[3])? << 48 | hex(fields[4])?;
+            Ok(Guid::new(
+                hex(fields[0])? as u32,
+                hex(fields[1])? as u16,
+                hex(fields[2])? as u16,
+                tail.to_be_bytes(),
+            ))
+        };
+        parse().map_err(|_| anyhow::anyhow!("invalid GUID '{}'", text))
+    }
+}
+
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub guid: Guid,
+    pub attributes: u32,
+    pub data: Vec<u8>,
+    // monotonic count, timestamp and public key index of the authenticated
+    // format, kept as they were
+    auth_fields: [u8; 28],
+}
+
+impl Variable {
+    pub fn new(name: &str, guid: Guid, attributes: u32, data: Vec<u8>) -> Self {
+        Variable {
+            name: name.to_string(),
+            guid,
+            attributes,
+            data,
+            auth_fields: [0; 28],
+        }
+    }
+}
+
+// The variable store of an edk2 firmware volume (OVMF_VARS.fd, AAVMF_VARS.fd)
+// as seen by the firmware: the live variables, without deleted ones.
+pub struct VarStore {
+    image: Vec<u8>,
+    // the variables, from the store header to the end of the store
+    area: std::ops::Range<usize>,
+    authenticated: bool,
+    pub variables: Vec<Variable>,
+}
+
+impl VarStore {
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let image =
+            std::fs::read(path).with_context(|| format!("read variable store: {}", path))?;
+        Self::parse(image).with_context(|| format!("parse variable store: {}", path))
+    }
+
+    fn parse(image: Vec<u8>) -> Result<Self> {
+        if image.len() < 0x48 || &image[40..44] != FV_SIGNATURE {
+            bail!("not a firmware volume");
+        }
+        if Guid::from_bytes(&image[16..]) != NV_DATA_FV_GUID {
+            bail!("not a firmware volume of variables (no NV data GUID)");
+        }
+
+        let store_offset = read_u16(&image, 48) as usize;
+        let header = image
+            .get(store_offset..store_offset + STORE_HEADER_SIZE)
+            .context("variable store header past the end")?;
+        let authenticated = match Guid::from_bytes(header) {
+            AUTHENTICATED_VARIABLE_GUID => true,
+            VARIABLE_GUID => false,
+            other => bail!("unknown variable store signature {}", other),
+        };
+        let store_size = read_u32(header, 16) as usize;
+        if header[20] != STORE_FORMATTED || header[21] != STORE_HEALTHY {
+            bail!("variable store is not formatted and healthy");
+        }
+        if store_size < align4(STORE_HEADER_SIZE) {
+            bail!(
+                "variable store of {} bytes is smaller than its header",
+                store_size
+            );
+        }
+        let store_end = store_offset + store_size;
+        if store_end > image.len() {
+            bail!(
+                "variable store of {} bytes does not fit the volume",
+                store_size
+            );
+        }
+
+        let mut store = VarStore {
+            area: align4(store_offset + STORE_HEADER_SIZE)..store_end,
+            authenticated,
+            variables: Vec::new(),
+            image,
+        };
+
+        // a variable being replaced stays valid until the new copy is complete
+        let mut in_transition = Vec::new();
+        let header_size = store.header_size();
+        let mut offset = store.area.start;
+        while offset + header_size <= store_end
+            && read_u16(&store.image, offset) == VARIABLE_START_ID
+        {
+            let header = &store.image[offset..offset + header_size];
+            let state = header[2];
+            let (attributes, name_size, data_size, guid_offset) = if authenticated {
+                (
+                    read_u32(header, 4),
+                    read_u32(header, 36) as usize,
+                    read_u32(header, 40) as usize,
+                    44,
+                )
+            } else {
+                (
+                    read_u32(header, 4),
+                    read_u32(header, 8) as usize,
+                    read_u32(header, 12) as usize,
+                    16,
+                )
+            };
+            let name_start = offset + header_size;
+            let data_start = name_start + name_size;
+            let data_end = data_start + data_size;
+            if data_end > store_end {
+                bail!("variable at {:#x} runs past the end of the store", offset);
+            }
+
+            let variable = Variable {
+                name: decode_utf16(&store.image[name_start..data_start]),
+                guid: Guid::from_bytes(&header[guid_offset..]),
+                attributes,
+                data: store.image[data_start..data_end].to_vec(),
+                auth_fields: match authenticated {
+                    true => header[8..36].try_into().expect("28 bytes"),
+                    false => [0; 28],
+                },
+            };
+            match state {
+                VAR_ADDED => store.variables.push(variable),
+                _ if state == VAR_ADDED & VAR_IN_DELETED_TRANSITION => in_transition.push(variable),
+                // deleted, or only the header was written
+                _ => {}
+            }
+            offset = align4(data_end);
+        }
+        for variable in in_transition {
+            if store.find(&variable.name, variable.guid).is_none() {
+                store.variables.push(variable);
+            }
+        }
+
+        Ok(store)
+    }
+
+    pub fn find(&self, name: &str, guid: Guid) -> Option<&Variable> {
+        self.variables
+            .iter()
+            .find(|variable| variable.name == name && variable.guid == guid)
+    }
+
+    // Add `variable`, replacing one of the same name and GUID.
+    pub fn set(&mut self, variable: Variable) {
+        match self
+            .variables
+            .iter_mut()
+            .find(|existing| existing.name == variable.name && existing.guid == variable.guid)
+        {
+            Some(existing) => *existing = variable,
+            None => self.variables.push(variable),
+        }
+    }
+
+    // Whether there was such a variable.
+    pub fn remove(&mut self, name: &str, guid: Guid) -> bool {
+        let count = self.variables.len();
+        self.variables
+            .retain(|variable| !(variable.name == name && variable.guid == guid));
+        self.variables.len() != count
+    }
+
+    // Write the live variables back compacted, as the firmware's own reclaim
+    // would; the rest of the volume is left as it was.
+    pub fn save(&mut self, path: &Utf8Path) -> Result<()> {
+        let header_size = self.header_size();
+        let mut area = Vec::new();
+        for variable in &self.variables {
+            let mut name = encode_utf16(&variable.name);
+            name.extend_from_slice(&[0, 0]);
+
+            let start = area.len();
+            area.resize(start + header_size, 0);
+            let header = &mut area[start..];
+            header[0..2].copy_from_slice(&VARIABLE_START_ID.to_le_bytes());
+            header[2] = VAR_ADDED;
+            header[4..8].copy_from_slice(&variable.attributes.to_le_bytes());
+            let sizes_offset = if self.authenticated {
+                header[8..36].copy_from_slice(&variable.auth_fields);
+                36
+            } else {
+                8
+            };
+            header[sizes_offset..sizes_offset + 4]
+                .copy_from_slice(&(name.len() as u32).to_le_bytes());
+            header[sizes_offset + 4..sizes_offset + 8]
+                .copy_from_slice(&(variable.data.len() as u32).to_le_bytes());
+            header[sizes_offset + 8..sizes_offset + 24].copy_from_slice(&variable.guid.0);
+
+            area.extend_from_slice(&name);
+            area.extend_from_slice(&variable.data);
+            area.resize(align4(area.len()), 0xff);
+        }
+
+        let capacity = self.area.len();
+        if area.len() > capacity {
+            bail!(
+                "variable store full: {} bytes of variables, room for {}",
+                area.len(),
+                capacity
+            );
+        }
+        area.resize(capacity, 0xff);
+        self.image[self.area.clone()].copy_from_slice(&area);
+
+        // never leave a half-written store behind
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, &self.image)
+            .with_context(|| format!("write variable store: {}", temp_path))?;
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("replace variable store: {}", path))
+    }
+
+    fn header_size(&self) -> usize {
+        match self.authenticated {
+            true => AUTHENTICATED_HEADER_SIZE,
+            false => HEADER_SIZE,
+        }
+    }
+}
+
+// "NV,BS,RT"
+pub fn format_attributes(attributes: u32) -> String {
+    let names: Vec<&str> = ATTRIBUTE_NAMES
+        .iter()
+        .filter(|(bit, _)| attributes & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    names.join(",")
+}
+
+// The reverse of `format_attributes`, case-insensitive.
+pub fn parse_attributes(text: &str) -> Result<u32> {
+    text.split(',')
+        .map(|name| {
+            ATTRIBUTE_NAMES
+                .iter()
+                .find(|(_, known)| known.eq_ignore_ascii_case(name.trim()))
+                .map(|(bit, _)| *bit)
+                .with_context(|| {
+                    format!(
+                        "unknown attribute '{}' (known: NV, BS, RT, HR, AW, AT, AP)",
+                        name
+                    )
+                })
+        })
+        .try_fold(0, |attributes, bit| Ok(attributes | bit?))
+}
+
+// An EFI_LOAD_OPTION, the value of a Boot#### variable.
+#[derive(Clone, Debug)]
+pub struct LoadOption {
+    pub active: bool,
+    pub description: String,
+    pub device_path: Vec<u8>,
+    pub optional_data: Vec<u8>,
+}
+
+impl LoadOption {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 6 {
+            bail!("load option of {} bytes is too short", data.len());
+        }
+        let attributes = read_u32(data, 0);
+        let path_length = read_u16(data, 4) as usize;
+
+        let description_end = (6..data.len().saturating_sub(1))
+            .step_by(2)
+            .find(|&offset| data[offset] == 0 && data[offset + 1] == 0)
+            .context("load option description is not terminated")?;
+        let path_start = description_end + 2;
+        let path_end = path_start + path_length;
+        if path_end > data.len() {
+            bail!("load option device path runs past its end");
+        }
+
+        Ok(LoadOption {
+            active: attributes & LOAD_OPTION_ACTIVE != 0,
+            description: decode_utf16(&data[6..description_end]),
+            device_path: data[path_start..path_end].to_vec(),
+            optional_data: data[path_end..].to_vec(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let attributes = if self.active { LOAD_OPTION_ACTIVE } else { 0 };
+        let mut data = attributes.to_le_bytes().to_vec();
+        data.extend_from_slice(&(self.device_path.len() as u16).to_le_bytes());
+        data.extend_from_slice(&encode_utf16(&self.description));
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&self.device_path);
+        data.extend_from_slice(&self.optional_data);
+        data
+    }
+}
+
+// A short-form device path of just a file, e.g. \EFI\BOOT\BOOTX64.EFI; the
+// firmware looks for it on every file system it knows.
+pub fn file_device_path(path: &str) -> Vec<u8> {
+    let mut name = encode_utf16(&path.replace('/', "\\"));
+    name.extend_from_slice(&[0, 0]);
+
+    let mut device_path = vec![0x04, 0x04];
+    device_path.extend_from_slice(&((4 + name.len()) as u16).to_le_bytes());
+    device_path.extend_from_slice(&name);
+    // end of the entire device path
+    device_path.extend_from_slice(&[0x7f, 0xff, 0x04, 0x00]);
+    device_path
+}
+
+// A device path in the firmware's text notation, for the node types boot
+// entries are usually made of; others print as their type and subtype.
+pub fn format_device_path(device_path: &[u8]) -> String {
+    let mut nodes = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= device_path.len() {
+        let (node_type, subtype) = (device_path[offset], device_path[offset + 1]);
+        let length = read_u16(device_path, offset + 2) as usize;
+        if length < 4 || offset + length > device_path.len() {
+            nodes.push("<malformed>".to_string());
+            break;
+        }
+        let data = &device_path[offset + 4..offset + length];
+        offset += length;
+
+        nodes.push(match (node_type, subtype) {
+            (0x7f, 0xff) => break,
+            (0x7f, 0x01) => ",".to_string(),
+            (0x01, 0x01) if data.len() >= 2 => format!("Pci({:#x},{:#x})", data[1], data[0]),
+            (0x02, 0x01) if data.len() >= 8 => match read_u32(data, 0) {
+                0x0a0341d0 => format!("PciRoot({:#x})", read_u32(data, 4)),
+                0x0a0841d0 => format!("PcieRoot({:#x})", read_u32(data, 4)),
+                hid => format!("Acpi({:#x},{:#x})", hid, read_u32(data, 4)),
+            },
+            (0x03, 0x0b) if data.len() >= 6 => {
+                let mac: Vec<String> = data[..6].iter().map(|b| format!("{:02x}", b)).collect();
+                format!("MAC({})", mac.join(""))
+            }
+            (0x03, 0x0c) => "IPv4(...)".to_string(),
+            (0x03, 0x0d) => "IPv6(...)".to_string(),
+            (0x03, 0x18) => format!("Uri({})", String::from_utf8_lossy(data)),
+            (0x04, 0x01) if data.len() >= 38 => {
+                let number = read_u32(data, 0);
+                let start = read_u64(data, 4);
+                let size = read_u64(data, 12);
+                match data[37] {
+                    0x02 => format!(
+                        "HD({},GPT,{},{:#x},{:#x})",
+                        number,
+                        Guid::from_bytes(&data[20..]),
+                        start,
+                        size
+                    ),
+                    _ => format!(
+                        "HD({},MBR,{:#010x},{:#x},{:#x})",
+                        number,
+                        read_u32(data, 20),
+                        start,
+                        size
+                    ),
+                }
+            }
+            (0x04, 0x04) => decode_utf16(data),
+            (0x04, 0x06) if data.len() >= 16 => format!("FvFile({})", Guid::from_bytes(data)),
+            (0x04, 0x07) if data.len() >= 16 => format!("Fv({})", Guid::from_bytes(data)),
+            _ => format!("Path({},{})", node_type, subtype),
+        });
+    }
+    nodes.join("/").replace("/,/", ",")
+}
+
+// "Boot0003" -> 3
+pub fn boot_option_number(name: &str) -> Option<u16> {
+    let digits = name.strip_prefix("Boot")?;
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u16::from_str_radix(digits, 16).ok()
+}
+
+pub fn decode_u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+pub fn encode_u16_list(values: &[u16]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+// UTF-16LE without the terminating NUL
+pub fn encode_utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+// UTF-16LE up to the first NUL
+pub fn decode_utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = decode_u16_list(bytes)
+        .into_iter()
+        .take_while(|&unit| unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().expect("2 bytes"))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::scratch::ScratchDir;
+
+    const VOLUME_HEADER_SIZE: usize = 0x48;
+    const STORE_SIZE: usize = 0x200;
+
+    // An empty, formatted firmware volume of variables, as in a fresh OVMF_VARS.fd.
+    fn empty_volume(authenticated: bool) -> Vec<u8> {
+        let mut image = vec![0xff; VOLUME_HEADER_SIZE + STORE_SIZE];
+        image[..VOLUME_HEADER_SIZE].fill(0);
+        image[16..32].copy_from_slice(&NV_DATA_FV_GUID.0);
+        image[40..44].copy_from_slice(FV_SIGNATURE);
+        image[48..50].copy_from_slice(&(VOLUME_HEADER_SIZE as u16).to_le_bytes());
+
+        let signature = match authenticated {
+            true => AUTHENTICATED_VARIABLE_GUID,
+            false => VARIABLE_GUID,
+        };
+        let header = &mut image[VOLUME_HEADER_SIZE..VOLUME_HEADER_SIZE + STORE_HEADER_SIZE];
+        header.fill(0);
+        header[..16].copy_from_slice(&signature.0);
+        header[16..20].copy_from_slice(&(STORE_SIZE as u32).to_le_bytes());
+        header[20] = STORE_FORMATTED;
+        header[21] = STORE_HEALTHY;
+        image
+    }
+
+    // what `save` writes for `store`
+    fn saved_image(store: &mut VarStore, name: &str) -> Vec<u8> {
+        let scratch = ScratchDir::new(&format!("vars-{}", name));
+        let path = scratch.join("store.fd");
+        store.save(&path).unwrap();
+        std::fs::read(&path).unwrap()
+    }
+
+    fn names(store: &VarStore) -> Vec<&str> {
+        store
+            .variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .collect()
+    }
+
+    // offset of the header of the store's first variable
+    const FIRST_VARIABLE: usize = VOLUME_HEADER_SIZE + STORE_HEADER_SIZE;
+
+    #[test]
+    fn round_trips_variables() {
+        for authenticated in [true, false] {
+            let mut store = VarStore::parse(empty_volume(authenticated)).unwrap();
+            assert!(store.variables.is_empty());
+
+            let mut timeout = Variable::new("Timeout", GLOBAL_VARIABLE_GUID, 0x07, vec![5, 0]);
+            timeout.auth_fields = [0x11; 28];
+            let vendor = "01234567-89ab-cdef-0123-456789abcdef".parse().unwrap();
+            store.set(timeout);
+            store.set(Variable::new("Odd", vendor, 0x03, vec![1, 2, 3]));
+
+            let image = saved_image(&mut store, "round-trip");
+            assert_eq!(image.len(), VOLUME_HEADER_SIZE + STORE_SIZE);
+            let loaded = VarStore::parse(image).unwrap();
+            assert_eq!(names(&loaded), ["Timeout", "Odd"]);
+
+            let timeout = loaded.find("Timeout", GLOBAL_VARIABLE_GUID).unwrap();
+            assert_eq!(
+                (timeout.attributes, timeout.data.as_slice()),
+                (0x07, &[5, 0][..])
+            );
+            let expected_auth = if authenticated { [0x11; 28] } else { [0; 28] };
+            assert_eq!(timeout.auth_fields, expected_auth);
+
+            let odd = loaded.find("Odd", vendor).unwrap();
+            assert_eq!(
+                (odd.attributes, odd.data.as_slice()),
+                (0x03, &[1, 2, 3][..])
+            );
+            assert!(loaded.find("Odd", GLOBAL_VARIABLE_GUID).is_none());
+        }
+    }
+
+    #[test]
+    fn set_replaces_and_remove_reports() {
+        let mut store = VarStore::parse(empty_volume(true)).unwrap();
+        store.set(Variable::new("A", GLOBAL_VARIABLE_GUID, 0x07, vec![1]));
+        store.set(Variable::new("A", GLOBAL_VARIABLE_GUID, 0x07, vec![2]));
+        assert_eq!(store.variables.len(), 1);
+        assert_eq!(store.find("A", GLOBAL_VARIABLE_GUID).unwrap().data, [2]);
+
+        assert!(store.remove("A", GLOBAL_VARIABLE_GUID));
+        assert!(!store.remove("A", GLOBAL_VARIABLE_GUID));
+    }
+
+    #[test]
+    fn skips_deleted_and_keeps_unreplaced_transitions() {
+        let mut store = VarStore::parse(empty_volume(false)).unwrap();
+        for name in ["A", "B", "C"] {
+            store.set(Variable::new(name, GLOBAL_VARIABLE_GUID, 0x07, vec![0; 4]));
+        }
+        let mut image = saved_image(&mut store, "states");
+        // each record: header, "X\0" in UTF-16, 4 bytes of data
+        let record_size = HEADER_SIZE + 4 + 4;
+        let state = |index: usize| FIRST_VARIABLE + index * record_size + 2;
+
+        // A is deleted (0xfd cleared too), B is mid-replacement with no new copy yet
+        image[state(0)] = VAR_ADDED & VAR_IN_DELETED_TRANSITION & 0xfd;
+        image[state(1)] = VAR_ADDED & VAR_IN_DELETED_TRANSITION;
+        let store = VarStore::parse(image).unwrap();
+        assert_eq!(names(&store), ["C", "B"]);
+    }
+
+    #[test]
+    fn reports_a_full_store() {
+        let mut store = VarStore::parse(empty_volume(true)).unwrap();
+        store.set(Variable::new(
+            "Big",
+            GLOBAL_VARIABLE_GUID,
+            0x07,
+            vec![0; STORE_SIZE],
+        ));
+        let scratch = ScratchDir::new("vars-full");
+        let path = scratch.join("store.fd");
+        let error = store.save(&path).unwrap_err();
+        assert!(error.to_string().starts_with("variable store full"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_malformed_volumes() {
+        let mut store = VarStore::parse(empty_volume(true)).unwrap();
+        store.set(Variable::new(
+            "Timeout",
+            GLOBAL_VARIABLE_GUID,
+            0x07,
+            vec![5, 0],
+        ));
+        let image = saved_image(&mut store, "malformed");
+
+        // cut anywhere, it must fail without panicking
+        for length in 0..image.len() {
+            assert!(
+                VarStore::parse(image[..length].to_vec()).is_err(),
+                "{}",
+                length
+            );
+        }
+
+        let corrupt = |offset: usize, bytes: &[u8]| {
+            let mut image = image.clone();
+            image[offset..offset + bytes.len()].copy_from_slice(bytes);
+            VarStore::parse(image).err().map(|error| error.to_string())
+        };
+        let store_header = VOLUME_HEADER_SIZE;
+        let cases: [(usize, &[u8], &str); 7] = [
+            (40, b"_FVX", "not a firmware volume"),
+            (16, &[0; 16], "no NV data GUID"),
+            (48, &0xfff0u16.to_le_bytes(), "header past the end"),
+            (store_header, &[0; 16], "unknown variable store signature"),
+            (store_header + 21, &[0xff], "not formatted and healthy"),
+            (
+                store_header + 16,
+                &0x10000u32.to_le_bytes(),
+                "does not fit the volume",
+            ),
+            (
+                store_header + 16,
+                &8u32.to_le_bytes(),
+                "smaller than its header",
+            ),
+        ];
+        for (offset, bytes, message) in cases {
+            let error = corrupt(offset, bytes).unwrap_or_default();
+            assert!(error.contains(message), "{:?}: {}", message, error);
+        }
+
+        // a data size running past the store
+        let data_size = FIRST_VARIABLE + 40;
+        let error = corrupt(data_size, &u32::MAX.to_le_bytes()).unwrap();
+        assert!(
+            error.contains("runs past the end of the store"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn round_trips_load_options() {
+        let option = LoadOption {
+            active: true,
+            description: "UEFI Shell".to_string(),
+            device_path: file_device_path("/EFI/BOOT/SHELL.EFI"),
+            optional_data: vec![0xde, 0xad],
+        };
+        let data = option.encode();
+        let parsed = LoadOption::parse(&data).unwrap();
+        assert!(parsed.active);
+        assert_eq!(parsed.description, "UEFI Shell");
+        assert_eq!(parsed.device_path, option.device_path);
+        assert_eq!(parsed.optional_data, [0xde, 0xad]);
+        assert_eq!(
+            format_device_path(&parsed.device_path),
+            r"\EFI\BOOT\SHELL.EFI"
+        );
+
+        let inactive = LoadOption {
+            active: false,
+            ..option.clone()
+        };
+        assert!(!LoadOption::parse(&inactive.encode()).unwrap().active);
+
+        // everything before the optional data is required
+        let optional_start = data.len() - option.optional_data.len();
+        for length in 0..optional_start {
+            assert!(LoadOption::parse(&data[..length]).is_err(), "{}", length);
+        }
+        let mut long_path = data.clone();
+        long_path[4..6].copy_from_slice(&0x1000u16.to_le_bytes());
+        assert!(LoadOption::parse(&long_path).is_err());
+    }
+
+    #[test]
+    fn formats_truncated_device_paths() {
+        let device_path = file_device_path("/EFI/BOOT/BOOTX64.EFI");
+        for length in 0..device_path.len() {
+            // partial nodes show as malformed, never panic
+            format_device_path(&device_path[..length]);
+        }
+        assert_eq!(format_device_path(&device_path[..10]), "<malformed>");
+        assert_eq!(format_device_path(&[0x04, 0x04, 0x02, 0x00]), "<malformed>");
+    }
+
+    #[test]
+    fn parses_guids_and_attributes() {
+        let text = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+        let guid: Guid = text.parse().unwrap();
+        assert_eq!(guid, GLOBAL_VARIABLE_GUID);
+        assert_eq!(guid.to_string(), text);
+        for invalid in [
+            "",
+            "8be4df61-93ca-11d2-aa0d",
+            "8be4df6g-93ca-11d2-aa0d-00e098032b8c",
+        ] {
+            assert!(invalid.parse::<Guid>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!(parse_attributes("nv, BS,rt").unwrap(), 0x07);
+        assert_eq!(format_attributes(0x27), "NV,BS,RT,AT");
+        assert!(parse_attributes("NV,XX").is_err());
+    }
+
+    #[test]
+    fn numbers_boot_options() {
+        assert_eq!(boot_option_number("Boot000A"), Some(10));
+        assert_eq!(boot_option_number("Boot0001x"), None);
+        assert_eq!(boot_option_number("BootOrder"), None);
+        assert_eq!(decode_u16_list(&[1, 0, 2, 0, 3]), [1, 2]);
+        assert_eq!(encode_u16_list(&[1, 0x0203]), [1, 0, 3, 2]);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::cli::{BootAction, VarsAction};
+use crate::steps::qemu::{VarsStore, ensure_vars_store, kept_vars_path};
+use crate::steps::uefi_vars::{
+    ATTRIBUTE_BOOTSERVICE_ACCESS, ATTRIBUTE_NON_VOLATILE, ATTRIBUTE_RUNTIME_ACCESS,
+    GLOBAL_VARIABLE_GUID, LoadOption, VarStore, Variable, boot_option_number, decode_u16_list,
+    encode_u16_list, encode_utf16, file_device_path, format_attributes, format_device_path,
+    parse_attributes,
+};
+use anyhow::{Context, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+
+// what the firmware itself gives Boot#### and BootOrder
+const BOOT_ATTRIBUTES: u32 =
+    ATTRIBUTE_NON_VOLATILE | ATTRIBUTE_BOOTSERVICE_ACCESS | ATTRIBUTE_RUNTIME_ACCESS;
+
+pub struct RunVarsArgs<'a> {
+    pub current_dir: &'a Utf8Path,
+    pub out_base: &'a Utf8Path,
+    // how runs keep their store (qemu.vars)
+    pub vars: VarsStore<'a>,
+    // --file, relative to the current dir
+    pub file: Option<&'a str>,
+    pub action: &'a VarsAction,
+}
+
+// `template_path` finds the store every other one starts from; it is only
+// asked for when the action needs it.
+pub fn run_vars(args: &RunVarsArgs, template_path: impl Fn() -> Result<Utf8PathBuf>) -> Result<()> {
+    let store_path = match args.file {
+        Some(file) => args.current_dir.join(file),
+        None => kept_vars_path(args.out_base, args.vars, &template_path()?).context(
+            "every run gets a fresh variable store (qemu.vars); \
+             use --vars persistent, --vars-file PATH or --file PATH",
+        )?,
+    };
+
+    if let VarsAction::Reset = args.action {
+        let template_path = template_path()?;
+        std::fs::copy(&template_path, &store_path)
+            .with_context(|| format!("copy firmware vars: {} -> {}", template_path, store_path))?;
+        eprintln!("[vars] reset {} from {}", store_path, template_path);
+        return Ok(());
+    }
+
+    if !store_path.exists() {
+        if let VarsAction::List | VarsAction::Boot(BootAction::List) = args.action {
+            eprintln!(
+                "[vars] {} does not exist yet; showing the template it will start from",
+                store_path
+            );
+            let store = VarStore::load(&template_path()?)?;
+            return print_vars(&store, args.action);
+        }
+        ensure_vars_store(&store_path, &template_path()?)?;
+    }
+
+    let mut store = VarStore::load(&store_path)?;
+    let global = GLOBAL_VARIABLE_GUID;
+
+    match args.action {
+        VarsAction::List | VarsAction::Boot(BootAction::List) => {
+            return print_vars(&store, args.action);
+        }
+        VarsAction::Reset => unreachable!("handled above"),
+        VarsAction::Add {
+            name,
+            guid,
+            attributes,
+            data,
+        } => {
+            let guid = guid
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or(global);
+            let attributes = parse_attributes(attributes)?;
+            if attributes & ATTRIBUTE_NON_VOLATILE == 0 {
+                bail!("the store only holds non-volatile variables; include NV");
+            }
+            let data = match (&data.hex, &data.string, &data.data_file) {
+                (Some(hex), _, _) => parse_hex(hex)?,
+                (_, Some(text), _) => {
+                    let mut data = encode_utf16(text);
+                    data.extend_from_slice(&[0, 0]);
+                    data
+                }
+                (_, _, Some(data_file)) => {
+                    let data_path = args.current_dir.join(data_file);
+                    std::fs::read(&data_path)
+                        .with_context(|| format!("read variable data: {}", data_path))?
+                }
+                (None, None, None) => unreachable!("clap requires one"),
+            };
+            store.set(Variable::new(name, guid, attributes, data));
+        }
+        VarsAction::Delete { name, guid } => {
+            let guid = guid
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or(global);
+            if !store.remove(name, guid) {
+                bail!("no variable {} {}", name, guid);
+            }
+        }
+        VarsAction::Boot(BootAction::Add {
+            description,
+            path,
+            first,
+            inactive,
+        }) => {
+            let used: Vec<u16> = store
+                .variables
+                .iter()
+                .filter(|variable| variable.guid == global)
+                .filter_map(|variable| boot_option_number(&variable.name))
+                .collect();
+            let number = (0..=u16::MAX)
+                .find(|number| !used.contains(number))
+                .context("every Boot#### is taken")?;
+
+            let option = LoadOption {
+                active: !inactive,
+                description: description.clone(),
+                device_path: file_device_path(path),
+                optional_data: Vec::new(),
+            };
+            let name = format!("Boot{:04X}", number);
+            store.set(Variable::new(
+                &name,
+                global,
+                BOOT_ATTRIBUTES,
+                option.encode(),
+            ));
+
+            let mut order = boot_order(&store);
+            if *first {
+                order.insert(0, number);
+            } else {
+                order.push(number);
+            }
+            store.set(Variable::new(
+                "BootOrder",
+                global,
+                BOOT_ATTRIBUTES,
+                encode_u16_list(&order),
+            ));
+            eprintln!("[vars] added {}", name);
+        }
+        VarsAction::Boot(BootAction::Delete { number }) => {
+            let digits = number.strip_prefix("Boot").unwrap_or(number);
+            let number = u16::from_str_radix(digits, 16)
+                .with_context(|| format!("invalid boot entry number '{}'", number))?;
+            let name = format!("Boot{:04X}", number);
+            if !store.remove(&name, global) {
+                bail!("no {}", name);
+            }
+            let order: Vec<u16> = boot_order(&store)
+                .into_iter()
+                .filter(|&entry| entry != number)
+                .collect();
+            store.set(Variable::new(
+                "BootOrder",
+                global,
+                BOOT_ATTRIBUTES,
+                encode_u16_list(&order),
+            ));
+        }
+    }
+
+    store.save(&store_path)?;
+    eprintln!("[vars] wrote {}", store_path);
+
+    Ok(())
+}
+
+fn boot_order(store: &VarStore) -> Vec<u16> {
+    store
+        .find("BootOrder", GLOBAL_VARIABLE_GUID)
+        .map(|variable| decode_u16_list(&variable.data))
+        .unwrap_or_default()
+}
+
+fn print_vars(store: &VarStore, action: &VarsAction) -> Result<()> {
+    if let VarsAction::Boot(_) = action {
+        let order = boot_order(store);
+        let order_text: Vec<String> = order
+            .iter()
+            .map(|number| format!("{:04X}", number))
+            .collect();
+        println!("BootOrder: {}", order_text.join(","));
+
+        let mut entries: Vec<(u16, &Variable)> = store
+            .variables
+            .iter()
+            .filter(|variable| variable.guid == GLOBAL_VARIABLE_GUID)
+            .filter_map(|variable| Some((boot_option_number(&variable.name)?, variable)))
+            .collect();
+        entries.sort_by_key(|(number, _)| {
+            (
+                order
+                    .iter()
+                    .position(|entry| entry == number)
+                    .unwrap_or(usize::MAX),
+                *number,
+            )
+        });
+        for (number, variable) in entries {
+            match LoadOption::parse(&variable.data) {
+                Ok(option) => println!(
+                    "Boot{:04X}{} {}\t{}",
+                    number,
+                    if option.active { "*" } else { " " },
+                    option.description,
+                    format_device_path(&option.device_path)
+                ),
+                Err(error) => println!("Boot{:04X}  <{:#}>", number, error),
+            }
+        }
+        return Ok(());
+    }
+
+    let name_width = store
+        .variables
+        .iter()
+        .map(|variable| variable.name.len())
+        .max()
+        .unwrap_or(0);
+    for variable in &store.variables {
+        println!(
+            "{:<name_width$}  {}  {:<8}  {:>6} B  {}",
+            variable.name,
+            variable.guid,
+            format_attributes(variable.attributes),
+            variable.data.len(),
+            preview_var(variable)
+        );
+    }
+    Ok(())
+}
+
+// a short rendering of a variable's value for the listing
+fn preview_var(variable: &Variable) -> String {
+    if variable.guid == GLOBAL_VARIABLE_GUID {
+        if boot_option_number(&variable.name).is_some()
+            && let Ok(option) = LoadOption::parse(&variable.data)
+        {
+            return format!("\"{}\"", option.description);
+        }
+        if variable.name == "BootOrder" {
+            let order: Vec<String> = decode_u16_list(&variable.data)
+                .iter()
+                .map(|number| format!("{:04X}", number))
+                .collect();
+            return order.join(",");
+        }
+    }
+
+    const PREVIEW_BYTES: usize = 16;
+    let hex: Vec<String> = variable
+        .data
+        .iter()
+        .take(PREVIEW_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let ellipsis = if variable.data.len() > PREVIEW_BYTES {
+        " ..."
+    } else {
+        ""
+    };
+    format!("{}{}", hex.join(" "), ellipsis)
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let digits: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    if !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid hex bytes '{}'", text);
+    }
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|offset| u8::from_str_radix(&digits[offset..offset + 2], 16).unwrap_or(0))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_bytes() {
+        assert_eq!(parse_hex("0x01ff").unwrap(), [0x01, 0xff]);
+        assert_eq!(parse_hex("de ad\tbe ef").unwrap(), [0xde, 0xad, 0xbe, 0xef]);
+        assert!(parse_hex("").unwrap().is_empty());
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("0xzz").is_err());
+        assert!(parse_hex("éé").is_err());
+    }
+
+    #[test]
+    fn previews_boot_variables() {
+        let option = LoadOption {
+            active: true,
+            description: "shell".to_string(),
+            device_path: file_device_path("/EFI/BOOT/SHELL.EFI"),
+            optional_data: Vec::new(),
+        };
+        let entry = Variable::new(
+            "Boot0001",
+            GLOBAL_VARIABLE_GUID,
+            BOOT_ATTRIBUTES,
+            option.encode(),
+        );
+        assert_eq!(preview_var(&entry), "\"shell\"");
+
+        let order = Variable::new(
+            "BootOrder",
+            GLOBAL_VARIABLE_GUID,
+            BOOT_ATTRIBUTES,
+            encode_u16_list(&[1, 0x1a]),
+        );
+        assert_eq!(preview_var(&order), "0001,001A");
+
+        // not a load option after all: shown as bytes
+        let broken = Variable::new(
+            "Boot0002",
+            GLOBAL_VARIABLE_GUID,
+            BOOT_ATTRIBUTES,
+            vec![1, 2],
+        );
+        assert_eq!(preview_var(&broken), "01 02");
+
+        let long = Variable::new(
+            "Blob",
+            GLOBAL_VARIABLE_GUID,
+            BOOT_ATTRIBUTES,
+            vec![0xab; 17],
+        );
+        assert_eq!(preview_var(&long), format!("{} ...", ["ab"; 16].join(" ")));
+    }
+}